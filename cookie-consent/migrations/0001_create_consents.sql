@@ -0,0 +1,44 @@
+-- Copyright (c) 2024 Tobias Briones. All rights reserved.
+-- This file is part of https://github.com/mathswe/legal
+
+-- Each consent keeps its whole CookieConsentValue as JSON in `value`, and the fields
+-- compliance queries filter by in their own columns.
+CREATE TABLE consents (
+    id TEXT PRIMARY KEY NOT NULL,
+    domain TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    country TEXT,
+    essential INTEGER NOT NULL,
+    functional INTEGER NOT NULL,
+    analytical INTEGER NOT NULL,
+    targeting INTEGER NOT NULL,
+    policy_version TEXT,
+    previous_id TEXT,
+    withdrawn_id TEXT,
+    retained_until TEXT,
+    value TEXT NOT NULL
+);
+
+CREATE INDEX consents_domain_created_at ON consents (domain, created_at, id);
+
+CREATE TABLE rate_counters (
+    key TEXT PRIMARY KEY NOT NULL,
+    count INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL
+);
+
+CREATE INDEX rate_counters_expires_at ON rate_counters (expires_at);
+
+-- Each domain, day, and country has its counts in their own columns, so a consent is counted
+-- with a single atomic upsert.
+CREATE TABLE consent_counts (
+    domain TEXT NOT NULL,
+    day TEXT NOT NULL,
+    country TEXT NOT NULL,
+    consents INTEGER NOT NULL,
+    essential INTEGER NOT NULL,
+    functional INTEGER NOT NULL,
+    analytical INTEGER NOT NULL,
+    targeting INTEGER NOT NULL,
+    PRIMARY KEY (domain, day, country)
+);
@@ -0,0 +1,579 @@
+// Copyright (c) 2024 Tobias Briones. All rights reserved.
+// This file is part of https://github.com/mathswe/legal
+
+use std::fmt::{Display, Formatter};
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+use worker::{Error, Request, Response, RouteContext, Url};
+
+use crate::api_error::ApiError;
+use crate::config::DomainsConfig;
+use crate::consent::{CookieConsent, Domain};
+use crate::log::RequestLog;
+use crate::retention::{apply_retention, RETENTION_BATCH_SIZE};
+use crate::server::ServiceData;
+use crate::stats::{StatsCounter, StatsReport};
+use crate::store::{ConsentStore, StoreError};
+
+const ADMIN_TOKEN_SECRET: &str = "ADMIN_TOKEN";
+
+const DEFAULT_PAGE_SIZE: u64 = 50;
+
+const MAX_PAGE_SIZE: u64 = 100;
+
+/// Maximum number of days scanned for a page, so a long range with few consents doesn't
+/// exceed the subrequests of a Worker request.
+const MAX_DAYS_PER_PAGE: usize = 31;
+
+/// Maximum number of days of a statistics query, since each day is read from the store.
+const MAX_STATS_DAYS: i64 = 31;
+
+/// Defines a query of the consents of a `Domain` created in the range from `from`
+/// (inclusive) to `to` (exclusive), read from the query string of an admin request.
+#[derive(PartialEq, Debug)]
+pub struct ConsentQuery {
+    domain: Domain,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    cursor: Option<ExportCursor>,
+    limit: u64,
+}
+
+impl ConsentQuery {
+    /// Reads the query from the URL parameters `domain`, `from` and `to` as RFC 3339 dates,
+    /// and the optional `cursor` of a previous page and `limit` of consents, which is at
+    /// most 100. The `domain` has to be an allowed domain.
+    pub fn from_url(url: &Url, domains: &DomainsConfig) -> Result<Self, QueryError> {
+        let param = |name| query_param(url, name);
+        let required = |name| param(name).ok_or(QueryError(format!("Missing {}", name)));
+        let date = |name| {
+            required(name).and_then(|value| {
+                DateTime::parse_from_rfc3339(&value)
+                    .map(|date| date.with_timezone(&Utc))
+                    .map_err(|_| QueryError(format!("Invalid {}: {}", name, value)))
+            })
+        };
+        let domain = Domain::new(required("domain")?);
+        let from = date("from")?;
+        let to = date("to")?;
+        let cursor = param("cursor")
+            .map(|cursor| {
+                ExportCursor::decode(&cursor).ok_or(QueryError("Invalid cursor".to_string()))
+            })
+            .transpose()?;
+        let limit = param("limit")
+            .map(|limit| {
+                limit
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|limit| *limit > 0)
+                    .ok_or(QueryError(format!("Invalid limit: {}", limit)))
+            })
+            .transpose()?
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .min(MAX_PAGE_SIZE);
+
+        if domains.get(&domain).is_none() {
+            return Err(QueryError(format!("Unknown domain: {}", domain)));
+        }
+
+        if from >= to {
+            return Err(QueryError("The range has to end after it starts".to_string()));
+        }
+
+        Ok(ConsentQuery { domain, from, to, cursor, limit })
+    }
+}
+
+/// Defines a query of the consent statistics of a `Domain` in the range of days from `from`
+/// to `to`, both inclusive, read from the query string of an admin request.
+#[derive(PartialEq, Debug)]
+pub struct StatsQuery {
+    domain: Domain,
+    from: NaiveDate,
+    to: NaiveDate,
+    by_country: bool,
+}
+
+impl StatsQuery {
+    /// Reads the query from the URL parameters `domain`, `from` and `to` as `YYYY-MM-DD`
+    /// dates in UTC, and the optional `by_country` flag to group the statistics by country.
+    /// The `domain` has to be an allowed domain, and the range can't exceed 31 days.
+    pub fn from_url(url: &Url, domains: &DomainsConfig) -> Result<Self, QueryError> {
+        let param = |name| query_param(url, name);
+        let required = |name| param(name).ok_or(QueryError(format!("Missing {}", name)));
+        let day = |name| {
+            required(name).and_then(|value| {
+                value
+                    .parse::<NaiveDate>()
+                    .map_err(|_| QueryError(format!("Invalid {}: {}", name, value)))
+            })
+        };
+        let domain = Domain::new(required("domain")?);
+        let from = day("from")?;
+        let to = day("to")?;
+        let by_country = match param("by_country").as_deref() {
+            None | Some("false") => false,
+            Some("true") => true,
+            Some(value) => return Err(QueryError(format!("Invalid by_country: {}", value))),
+        };
+
+        if domains.get(&domain).is_none() {
+            return Err(QueryError(format!("Unknown domain: {}", domain)));
+        }
+
+        if from > to {
+            return Err(QueryError("The range has to end after it starts".to_string()));
+        }
+
+        if (to - from).num_days() >= MAX_STATS_DAYS {
+            return Err(QueryError(format!("The range exceeds {} days", MAX_STATS_DAYS)));
+        }
+
+        Ok(StatsQuery { domain, from, to, by_country })
+    }
+}
+
+/// Defines where the next page of an export starts, that is, the day it scans and the store
+/// cursor within that day. It's sent to the client as an opaque base64url string.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+struct ExportCursor {
+    day: NaiveDate,
+    cursor: Option<String>,
+}
+
+impl ExportCursor {
+    fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap())
+    }
+
+    fn decode(encoded: &str) -> Option<Self> {
+        URL_SAFE_NO_PAD
+            .decode(encoded)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+    }
+}
+
+/// Defines a page of exported consents. Its `cursor` is `None` once the queried range was
+/// fully scanned, so the last page might be empty.
+#[derive(PartialEq, Debug, Serialize)]
+pub struct ConsentExport {
+    consents: Vec<CookieConsent>,
+    cursor: Option<String>,
+}
+
+#[derive(PartialEq, Debug)]
+pub struct QueryError(String);
+
+impl Display for QueryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Lists the consents of a `Domain` created in a date range, authenticated with the
+/// `ADMIN_TOKEN` secret as a bearer token. It's meant for compliance exports, so it's not
+/// available to the allowed origins.
+pub async fn get_consents<S: ConsentStore + 'static>(
+    req: Request,
+    ctx: RouteContext<ServiceData<S>>,
+) -> Result<Response, Error> {
+    let log = RequestLog::from_req(&req);
+
+    if let Err(e) = authorize(&req, &ctx) {
+        return e.to_response(&log);
+    }
+
+    match ConsentQuery::from_url(&req.url()?, ctx.data.domains()) {
+        Ok(query) => match list_consents(ctx.data.store(), &query).await {
+            Ok(export) => Response::from_json(&export),
+            Err(e) => ApiError::storage("consent_export_failed", e).to_response(&log),
+        },
+        Err(e) => ApiError::BadQuery(e.to_string()).to_response(&log),
+    }
+}
+
+/// Applies the retention rules to a batch of stored consents starting after the `cursor` URL
+/// parameter, so the rules can be applied by hand to the existing consents batch by batch. It
+/// responds with the `RetentionReport` of the batch, whose `cursor` continues the next batch.
+pub async fn post_retention<S: ConsentStore + 'static>(
+    req: Request,
+    ctx: RouteContext<ServiceData<S>>,
+) -> Result<Response, Error> {
+    let log = RequestLog::from_req(&req);
+
+    if let Err(e) = authorize(&req, &ctx) {
+        return e.to_response(&log);
+    }
+
+    let store = ctx.data.store();
+    let cursor = query_param(&req.url()?, "cursor");
+
+    match apply_retention(store, ctx.data.domains(), Utc::now(), cursor, RETENTION_BATCH_SIZE)
+        .await
+    {
+        Ok(report) => Response::from_json(&report),
+        Err(e) => ApiError::storage("retention_failed", e).to_response(&log),
+    }
+}
+
+/// Responds with the consent statistics of a `Domain` in a range of days, authenticated with
+/// the `ADMIN_TOKEN` secret as a bearer token, so the cookie banner can be tuned by the
+/// acceptance rate of each cookie category.
+pub async fn get_stats<S: StatsCounter + 'static>(
+    req: Request,
+    ctx: RouteContext<ServiceData<S>>,
+) -> Result<Response, Error> {
+    let log = RequestLog::from_req(&req);
+
+    if let Err(e) = authorize(&req, &ctx) {
+        return e.to_response(&log);
+    }
+
+    match StatsQuery::from_url(&req.url()?, ctx.data.domains()) {
+        Ok(query) => match read_stats(ctx.data.store(), &query).await {
+            Ok(report) => Response::from_json(&report),
+            Err(e) => ApiError::storage("stats_read_failed", e).to_response(&log),
+        },
+        Err(e) => ApiError::BadQuery(e.to_string()).to_response(&log),
+    }
+}
+
+/// Authorizes an admin request whose `Authorization` header has the `ADMIN_TOKEN` secret as a
+/// bearer token.
+fn authorize<D>(req: &Request, ctx: &RouteContext<D>) -> Result<(), ApiError> {
+    let token = ctx.env.secret(ADMIN_TOKEN_SECRET).ok().map(|token| token.to_string());
+    let authorization = req.headers().get("Authorization").ok().flatten();
+
+    if is_authorized(authorization.as_deref(), token.as_deref()) {
+        Ok(())
+    } else {
+        Err(ApiError::Unauthorized)
+    }
+}
+
+/// Returns the value of the URL query parameter with the given name.
+pub fn query_param(url: &Url, name: &str) -> Option<String> {
+    url
+        .query_pairs()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.to_string())
+}
+
+/// Returns whether the `Authorization` header has the admin token as a bearer token. It's
+/// never authorized if there's no admin token, and the token is compared in constant time.
+pub fn is_authorized(authorization: Option<&str>, token: Option<&str>) -> bool {
+    match (authorization.and_then(|value| value.strip_prefix("Bearer ")), token) {
+        (Some(given), Some(token)) if !token.is_empty() => {
+            given.len() == token.len() && given
+                .bytes()
+                .zip(token.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+        }
+        _ => false,
+    }
+}
+
+/// Lists a page of the queried consents by their creation time, scanning the index of the
+/// `Domain` one day at a time.
+pub async fn list_consents(
+    store: &impl ConsentStore,
+    query: &ConsentQuery,
+) -> Result<ConsentExport, StoreError> {
+    let start = query
+        .cursor
+        .clone()
+        .unwrap_or(ExportCursor { day: query.from.date_naive(), cursor: None });
+    let mut day = start.day;
+    let mut cursor = start.cursor;
+    let mut consents = Vec::new();
+    let mut scanned_days = 0;
+    let day_start = |day: NaiveDate| day.and_time(NaiveTime::MIN).and_utc();
+
+    while day_start(day) < query.to {
+        if consents.len() as u64 == query.limit || scanned_days == MAX_DAYS_PER_PAGE {
+            let next = ExportCursor { day, cursor };
+
+            return Ok(ConsentExport { consents, cursor: Some(next.encode()) });
+        }
+
+        let limit = query.limit - consents.len() as u64;
+        let page = store.list_day(&query.domain, day, cursor.take(), limit).await?;
+
+        for id in page.ids {
+            if let Some(consent) = store.get(&id).await? {
+                let (_, value) = consent.to_kv();
+
+                if query.from <= value.created_at() && value.created_at() < query.to {
+                    consents.push(consent);
+                }
+            }
+        }
+
+        cursor = page.cursor;
+
+        if cursor.is_none() {
+            day = day.succ_opt().unwrap_or(NaiveDate::MAX);
+            scanned_days += 1;
+        }
+    }
+
+    Ok(ConsentExport { consents, cursor: None })
+}
+
+/// Reads the daily counts of the queried days, which are kept as consents are registered, so
+/// the statistics don't scan the consents.
+pub async fn read_stats(
+    counter: &impl StatsCounter,
+    query: &StatsQuery,
+) -> Result<StatsReport, StoreError> {
+    let mut days = Vec::new();
+
+    for day in query.from.iter_days().take_while(|day| day <= &query.to) {
+        days.push((day, counter.get_counts(&query.domain, day).await?));
+    }
+
+    Ok(StatsReport::from_days(query.domain.clone(), days, query.by_country))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::SecondsFormat;
+    use futures::executor::block_on;
+    use worker::Url;
+
+    use crate::admin::{
+        ConsentQuery,
+        is_authorized,
+        list_consents,
+        QueryError,
+        read_stats,
+        StatsQuery,
+    };
+    use crate::config::DomainsConfig;
+    use crate::consent::Domain;
+    use crate::stats::{count_consent, MemoryStatsCounter};
+    use crate::store::{ConsentBuilder, ConsentStore, MemoryConsentStore};
+
+    const CONFIG_JSON: &str = r#"[
+        {
+            "domain": "mathswe.com",
+            "policies": [{ "version": "2024-03-10", "status": "Active" }]
+        },
+        {
+            "domain": "math.software",
+            "policies": [{ "version": "2024-03-10", "status": "Active" }]
+        }
+    ]"#;
+
+    #[test]
+    fn reads_query_from_url() {
+        let domains = DomainsConfig::from_json(CONFIG_JSON).unwrap();
+        let query = ConsentQuery::from_url(
+            &url("domain=mathswe.com&from=2024-03-01T00:00:00Z&to=2024-04-01T00:00:00%2B02:00"),
+            &domains,
+        ).unwrap();
+
+        assert_eq!(Domain::new("mathswe.com"), query.domain);
+        assert_eq!("2024-03-31T22:00:00Z", query.to.to_rfc3339_opts(SecondsFormat::Secs, true));
+        assert_eq!(50, query.limit);
+        assert_eq!(None, query.cursor);
+    }
+
+    #[test]
+    fn rejects_invalid_query() {
+        let domains = DomainsConfig::from_json(CONFIG_JSON).unwrap();
+        let range = "from=2024-03-01T00:00:00Z&to=2024-04-01T00:00:00Z";
+        let invalid_queries = [
+            range.to_string(),
+            format!("domain=mathsoftware.engineer&{}", range),
+            "domain=mathswe.com&from=2024-03-01T00:00:00Z".to_string(),
+            "domain=mathswe.com&from=2024-03-01&to=2024-04-01T00:00:00Z".to_string(),
+            "domain=mathswe.com&from=2024-04-01T00:00:00Z&to=2024-03-01T00:00:00Z".to_string(),
+            format!("domain=mathswe.com&{}&limit=0", range),
+            format!("domain=mathswe.com&{}&limit=many", range),
+            format!("domain=mathswe.com&{}&cursor=abc", range),
+        ];
+
+        invalid_queries
+            .iter()
+            .for_each(|query| assert!(
+                matches!(ConsentQuery::from_url(&url(query), &domains), Err(QueryError(_))),
+                "{} is not a valid query",
+                query
+            ))
+    }
+
+    #[test]
+    fn authorizes_bearer_token() {
+        let token_cases = [
+            (Some("Bearer secret-token"), Some("secret-token"), true),
+            (Some("Bearer secret-tokem"), Some("secret-token"), false),
+            (Some("Bearer secret"), Some("secret-token"), false),
+            (Some("secret-token"), Some("secret-token"), false),
+            (None, Some("secret-token"), false),
+            (Some("Bearer "), Some(""), false),
+            (Some("Bearer secret-token"), None, false),
+        ];
+
+        token_cases
+            .iter()
+            .for_each(|(authorization, token, expected)| assert_eq!(
+                *expected,
+                is_authorized(*authorization, *token),
+                "Authorization: {:?}, token: {:?}",
+                authorization,
+                token
+            ))
+    }
+
+    #[test]
+    fn lists_consents_in_range_by_pages() {
+        let store = MemoryConsentStore::default();
+        let domains = DomainsConfig::from_json(CONFIG_JSON).unwrap();
+        let consents = [
+            ConsentBuilder::at("2024-02-29T23:59:59Z").build(),
+            ConsentBuilder::at("2024-03-01T10:00:00Z").build(),
+            ConsentBuilder::at("2024-03-01T08:00:00Z").build(),
+            ConsentBuilder::at("2024-03-05T12:00:00Z").build(),
+            ConsentBuilder::at("2024-03-05T12:00:00Z").domain("math.software").build(),
+            ConsentBuilder::at("2024-03-20T12:00:00Z").build(),
+            ConsentBuilder::at("2024-04-01T00:00:00Z").build(),
+        ];
+        let id = |i: usize| consents[i].to_kv().0;
+        let range = "domain=mathswe.com&from=2024-03-01T00:00:00Z&to=2024-04-01T00:00:00Z";
+        let export = |query: String| {
+            let query = ConsentQuery::from_url(&url(&query), &domains).unwrap();
+            let export = serde_json::to_value(block_on(list_consents(&store, &query)).unwrap())
+                .unwrap();
+            let ids = export["consents"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|consent| consent["id"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>();
+
+            (ids, export["cursor"].as_str().map(str::to_string))
+        };
+
+        consents
+            .iter()
+            .for_each(|consent| block_on(store.put(consent)).unwrap());
+
+        let (first_ids, first_cursor) = export(format!("{}&limit=2", range));
+        let (second_ids, second_cursor) = export(
+            format!("{}&limit=2&cursor={}", range, first_cursor.clone().unwrap())
+        );
+        let (last_ids, last_cursor) = export(
+            format!("{}&limit=2&cursor={}", range, second_cursor.clone().unwrap())
+        );
+        let (all_ids, all_cursor) = export(range.to_string());
+
+        assert_eq!(vec![id(2), id(1)], first_ids, "consents are listed by creation time");
+        assert_eq!(vec![id(3), id(5)], second_ids, "the cursor spans days");
+        assert_eq!((vec![], None), (last_ids, last_cursor), "there are no more consents");
+        assert_eq!(vec![id(2), id(1), id(3), id(5)], all_ids);
+        assert_eq!(None, all_cursor);
+    }
+
+    #[test]
+    fn limits_days_scanned_per_page() {
+        let store = MemoryConsentStore::default();
+        let domains = DomainsConfig::from_json(CONFIG_JSON).unwrap();
+        let consent = ConsentBuilder::at("2024-06-01T00:00:00Z").build();
+        let query = ConsentQuery::from_url(
+            &url("domain=mathswe.com&from=2024-01-01T00:00:00Z&to=2025-01-01T00:00:00Z"),
+            &domains,
+        ).unwrap();
+
+        block_on(store.put(&consent)).unwrap();
+
+        let first_page = serde_json::to_value(block_on(list_consents(&store, &query)).unwrap())
+            .unwrap();
+
+        assert_eq!(0, first_page["consents"].as_array().unwrap().len());
+        assert!(first_page["cursor"].is_string(), "an empty page still has a cursor");
+    }
+
+    #[test]
+    fn reads_stats_query_from_url() {
+        let domains = DomainsConfig::from_json(CONFIG_JSON).unwrap();
+        let query = StatsQuery::from_url(
+            &url("domain=math.software&from=2024-03-01&to=2024-03-31&by_country=true"),
+            &domains,
+        ).unwrap();
+        let single_day_query = StatsQuery::from_url(
+            &url("domain=mathswe.com&from=2024-03-01&to=2024-03-01"),
+            &domains,
+        ).unwrap();
+
+        assert_eq!(Domain::new("math.software"), query.domain);
+        assert_eq!("2024-03-31", query.to.to_string());
+        assert!(query.by_country);
+        assert!(!single_day_query.by_country, "statistics aren't grouped by country by default");
+    }
+
+    #[test]
+    fn rejects_invalid_stats_query() {
+        let domains = DomainsConfig::from_json(CONFIG_JSON).unwrap();
+        let invalid_queries = [
+            "from=2024-03-01&to=2024-03-31",
+            "domain=mathsoftware.engineer&from=2024-03-01&to=2024-03-31",
+            "domain=mathswe.com&from=2024-03-01",
+            "domain=mathswe.com&from=2024-03-01T00:00:00Z&to=2024-03-31",
+            "domain=mathswe.com&from=2024-03-31&to=2024-03-01",
+            "domain=mathswe.com&from=2024-03-01&to=2024-04-01",
+            "domain=mathswe.com&from=2024-03-01&to=2024-03-31&by_country=yes",
+        ];
+
+        invalid_queries
+            .iter()
+            .for_each(|query| assert!(
+                matches!(StatsQuery::from_url(&url(query), &domains), Err(QueryError(_))),
+                "{} is not a valid query",
+                query
+            ))
+    }
+
+    #[test]
+    fn reads_stats_of_domain_by_day() {
+        let counter = MemoryStatsCounter::default();
+        let domains = DomainsConfig::from_json(CONFIG_JSON).unwrap();
+        let consents = [
+            ConsentBuilder::at("2024-02-29T23:59:59Z").build(),
+            ConsentBuilder::at("2024-03-01T10:00:00Z").build(),
+            ConsentBuilder::at("2024-03-03T08:00:00Z").build(),
+            ConsentBuilder::at("2024-03-03T12:00:00Z").build(),
+            ConsentBuilder::at("2024-03-03T12:00:00Z").domain("math.software").build(),
+        ];
+        let query = StatsQuery::from_url(
+            &url("domain=mathswe.com&from=2024-03-01&to=2024-03-03"),
+            &domains,
+        ).unwrap();
+
+        consents
+            .iter()
+            .for_each(|consent| block_on(count_consent(&counter, consent)).unwrap());
+
+        let report = serde_json::to_value(block_on(read_stats(&counter, &query)).unwrap())
+            .unwrap();
+        let day_consents = report["days"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|day| day["consents"].as_u64().unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(3, report["total"]["consents"]);
+        assert_eq!(vec![1, 0, 2], day_consents, "days without consents are reported empty");
+        assert_eq!(0.0, report["total"]["targeting"]["rate"]);
+    }
+
+    fn url(query: &str) -> Url {
+        Url::parse(&format!("https://consent.mathswe.com/admin/consents?{}", query)).unwrap()
+    }
+}
@@ -1,41 +1,125 @@
 // Copyright (c) 2024 Tobias Briones. All rights reserved.
 // This file is part of https://github.com/mathswe/legal
 
-use std::net::Ipv4Addr;
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
 use serde::{Deserialize, Serialize};
 
+/// Default length of the network prefix kept from an IPv6 address when anonymizing it.
+pub const DEFAULT_IPV6_PREFIX_LENGTH: u8 = 48;
+
+/// Defines an IPv4 or IPv6 address with its host part set to zero. IPv4 addresses keep their
+/// first three octets, while IPv6 addresses keep a configurable network prefix, such as /48.
+/// It's serialized as the string of the anonymized address, so records that only stored
+/// anonymous IPv4 addresses are still valid.
 #[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
-pub struct AnonymousIpv4(String);
+pub struct AnonymousIp(String);
+
+impl AnonymousIp {
+    pub fn from_ip(ip: IpAddr, ipv6_prefix_length: u8) -> AnonymousIp {
+        match ip {
+            IpAddr::V4(ipv4addr) => Self::from_ipv4(ipv4addr),
+            IpAddr::V6(ipv6addr) => Self::from_ipv6(ipv6addr, ipv6_prefix_length),
+        }
+    }
 
-impl AnonymousIpv4 {
-    pub fn from_ipv4(ipv4addr: Ipv4Addr) -> AnonymousIpv4 {
+    pub fn from_ipv4(ipv4addr: Ipv4Addr) -> AnonymousIp {
         let [octet1, octet2, octet3, _] = ipv4addr.octets();
         let anonymous_ip = format!("{}.{}.{}.0", octet1, octet2, octet3);
 
-        AnonymousIpv4(anonymous_ip)
+        AnonymousIp(anonymous_ip)
+    }
+
+    /// Keeps the first `prefix_length` bits of the address, which is at most `128`.
+    pub fn from_ipv6(ipv6addr: Ipv6Addr, prefix_length: u8) -> AnonymousIp {
+        let host_length = 128 - u32::from(prefix_length.min(128));
+        let mask = u128::MAX.checked_shl(host_length).unwrap_or(0);
+        let anonymous_ip = Ipv6Addr::from(u128::from(ipv6addr) & mask);
+
+        AnonymousIp(anonymous_ip.to_string())
+    }
+}
+
+impl Display for AnonymousIp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::net::Ipv4Addr;
-    use crate::anonymous_ip::AnonymousIpv4;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use std::str::FromStr;
+
+    use crate::anonymous_ip::{AnonymousIp, DEFAULT_IPV6_PREFIX_LENGTH};
 
     #[test]
     fn creates_anonymous_ipv4_from_original_ip() {
         assert_eq!(
-            AnonymousIpv4("1.1.1.0".to_string()),
-            AnonymousIpv4::from_ipv4(Ipv4Addr::new(1, 1, 1, 1))
+            AnonymousIp("1.1.1.0".to_string()),
+            AnonymousIp::from_ipv4(Ipv4Addr::new(1, 1, 1, 1))
         );
 
         assert_eq!(
-            AnonymousIpv4("123.213.231.0".to_string()),
-            AnonymousIpv4::from_ipv4(Ipv4Addr::new(123, 213, 231, 85))
+            AnonymousIp("123.213.231.0".to_string()),
+            AnonymousIp::from_ipv4(Ipv4Addr::new(123, 213, 231, 85))
         );
 
         assert_eq!(
-            AnonymousIpv4("240.80.150.0".to_string()),
-            AnonymousIpv4::from_ipv4(Ipv4Addr::new(240, 80, 150, 210))
+            AnonymousIp("240.80.150.0".to_string()),
+            AnonymousIp::from_ipv4(Ipv4Addr::new(240, 80, 150, 210))
         );
     }
+
+    #[test]
+    fn creates_anonymous_ipv6_from_original_ip() {
+        let ip = Ipv6Addr::from_str("2001:db8:abcd:12:3456:789a:bcde:f012").unwrap();
+
+        assert_eq!(
+            AnonymousIp("2001:db8:abcd::".to_string()),
+            AnonymousIp::from_ipv6(ip, DEFAULT_IPV6_PREFIX_LENGTH)
+        );
+
+        assert_eq!(
+            AnonymousIp("2001:db8:abcd:12::".to_string()),
+            AnonymousIp::from_ipv6(ip, 64)
+        );
+
+        assert_eq!(
+            AnonymousIp("2001:db8:ab00::".to_string()),
+            AnonymousIp::from_ipv6(ip, 40)
+        );
+
+        assert_eq!(
+            AnonymousIp("::".to_string()),
+            AnonymousIp::from_ipv6(ip, 0)
+        );
+
+        assert_eq!(
+            AnonymousIp(ip.to_string()),
+            AnonymousIp::from_ipv6(ip, 255),
+            "prefix length is at most 128"
+        );
+    }
+
+    #[test]
+    fn creates_anonymous_ip_from_any_family() {
+        assert_eq!(
+            AnonymousIp("1.1.1.0".to_string()),
+            AnonymousIp::from_ip(IpAddr::from_str("1.1.1.1").unwrap(), 48)
+        );
+
+        assert_eq!(
+            AnonymousIp("2606:4700:4700::".to_string()),
+            AnonymousIp::from_ip(IpAddr::from_str("2606:4700:4700::1111").unwrap(), 48)
+        );
+    }
+
+    #[test]
+    fn deserializes_stored_anonymous_ipv4() {
+        let ip = serde_json::from_str::<AnonymousIp>(r#""123.213.231.0""#).unwrap();
+
+        assert_eq!(AnonymousIp("123.213.231.0".to_string()), ip);
+    }
 }
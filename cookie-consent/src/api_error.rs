@@ -0,0 +1,309 @@
+// Copyright (c) 2024 Tobias Briones. All rights reserved.
+// This file is part of https://github.com/mathswe/legal
+
+use std::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+use worker::{Error, Response};
+
+use crate::log::RequestLog;
+
+/// Content type of the error responses, defined by RFC 7807.
+pub const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
+
+/// Prefix of the problem `type` URIs. They're tag URIs (RFC 4151), so they identify each
+/// problem permanently without being links to a documentation page.
+const PROBLEM_TYPE_PREFIX: &str = "tag:mathswe.com,2024:cookie-consent:";
+
+/// Defines the reasons a request fails, so every handler responds to them with the same
+/// status code and problem details.
+#[derive(PartialEq, Clone, Debug)]
+pub enum ApiError {
+    /// The request comes from an origin that's not allowed.
+    ForbiddenOrigin,
+    /// The admin request doesn't have the admin token as a bearer token.
+    Unauthorized,
+    /// The body of the request is not valid JSON.
+    BadBody(String),
+    /// The query string of the request has an invalid parameter.
+    BadQuery(String),
+    /// The body of the request is well-formed JSON but its fields are not acceptable, like a
+    /// missing field or an unknown cookie policy version.
+    Validation(Vec<FieldError>),
+    NotFound,
+    /// The path exists but doesn't accept the method of the request, so the `Allow` header
+    /// has the methods it accepts.
+    MethodNotAllowed { allow: Vec<String> },
+    RateLimited { retry_after: u64 },
+    /// The consent store failed, which is logged as the given event. The error details are
+    /// only logged, so the client never sees them.
+    Storage { event: &'static str, error: String },
+    /// The service is misconfigured or failed otherwise, which is logged as the given event.
+    Internal { event: &'static str, error: String },
+}
+
+impl ApiError {
+    pub fn storage(event: &'static str, error: impl Display) -> Self {
+        ApiError::Storage { event, error: error.to_string() }
+    }
+
+    pub fn internal(event: &'static str, error: impl Display) -> Self {
+        ApiError::Internal { event, error: error.to_string() }
+    }
+
+    pub fn status(&self) -> u16 {
+        match self {
+            ApiError::ForbiddenOrigin => 403,
+            ApiError::Unauthorized => 401,
+            ApiError::BadBody(_) | ApiError::BadQuery(_) => 400,
+            ApiError::Validation(_) => 422,
+            ApiError::NotFound => 404,
+            ApiError::MethodNotAllowed { .. } => 405,
+            ApiError::RateLimited { .. } => 429,
+            ApiError::Storage { .. } | ApiError::Internal { .. } => 500,
+        }
+    }
+
+    /// Returns the stable `type` URI of the problem, e.g.,
+    /// `tag:mathswe.com,2024:cookie-consent:rate-limited`.
+    pub fn type_uri(&self) -> String {
+        let name = match self {
+            ApiError::ForbiddenOrigin => "forbidden-origin",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::BadBody(_) => "bad-body",
+            ApiError::BadQuery(_) => "bad-query",
+            ApiError::Validation(_) => "validation-failed",
+            ApiError::NotFound => "not-found",
+            ApiError::MethodNotAllowed { .. } => "method-not-allowed",
+            ApiError::RateLimited { .. } => "rate-limited",
+            ApiError::Storage { .. } => "storage-failed",
+            ApiError::Internal { .. } => "internal-error",
+        };
+
+        format!("{}{}", PROBLEM_TYPE_PREFIX, name)
+    }
+
+    pub fn title(&self) -> &'static str {
+        match self {
+            ApiError::ForbiddenOrigin => "Origin not allowed",
+            ApiError::Unauthorized => "Unauthorized",
+            ApiError::BadBody(_) => "Invalid request body",
+            ApiError::BadQuery(_) => "Invalid query parameter",
+            ApiError::Validation(_) => "Validation failed",
+            ApiError::NotFound => "Not found",
+            ApiError::MethodNotAllowed { .. } => "Method not allowed",
+            ApiError::RateLimited { .. } => "Too many requests",
+            ApiError::Storage { .. } => "Cookie consent store failed",
+            ApiError::Internal { .. } => "Internal error",
+        }
+    }
+
+    /// Returns the problem details of this error for the request with the given ID, so the
+    /// client can report the ID to correlate the failure with the logs.
+    pub fn problem(&self, request_id: &str) -> Problem {
+        let detail = match self {
+            ApiError::BadBody(detail) | ApiError::BadQuery(detail) => Some(detail.clone()),
+            ApiError::Validation(errors) => Some(
+                errors
+                    .iter()
+                    .map(FieldError::to_string)
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            ),
+            ApiError::RateLimited { retry_after } => {
+                Some(format!("Retry after {} seconds", retry_after))
+            }
+            _ => None,
+        };
+
+        Problem {
+            problem_type: self.type_uri(),
+            title: self.title().to_string(),
+            status: self.status(),
+            detail,
+            errors: match self {
+                ApiError::Validation(errors) => Some(errors.clone()),
+                _ => None,
+            },
+            request_id: request_id.to_string(),
+        }
+    }
+
+    /// Returns the headers the response of this error has besides its content type.
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        match self {
+            ApiError::Unauthorized => vec![("WWW-Authenticate", "Bearer".to_string())],
+            ApiError::MethodNotAllowed { allow } => vec![("Allow", allow.join(", "))],
+            ApiError::RateLimited { retry_after } => {
+                vec![("Retry-After", retry_after.to_string())]
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Logs this error for the given request, and returns its problem details response.
+    pub fn to_response(&self, log: &RequestLog) -> Result<Response, Error> {
+        self.log(log);
+
+        let mut res = Response::ok(self.problem(log.request_id()).to_json())?
+            .with_status(self.status());
+        let headers = res.headers_mut();
+
+        headers.set("Content-Type", PROBLEM_CONTENT_TYPE)?;
+
+        for (name, value) in self.headers() {
+            headers.set(name, &value)?;
+        }
+        Ok(res)
+    }
+
+    fn log(&self, log: &RequestLog) {
+        match self {
+            ApiError::ForbiddenOrigin => log.info("origin_forbidden"),
+            ApiError::RateLimited { .. } => log.info("rate_limited"),
+            ApiError::Storage { event, error } | ApiError::Internal { event, error } => {
+                log.error(event, error)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Defines why a field of a request body is not valid. The `field` is a JSON Pointer (RFC
+/// 6901) to it, like `/pref/essential`, so clients can show the error next to its input.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct FieldError {
+    field: String,
+    message: String,
+}
+
+impl FieldError {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        FieldError { field: field.into(), message: message.into() }
+    }
+}
+
+impl Display for FieldError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+/// Defines the problem details (RFC 7807) body of an error response. Besides the standard
+/// members, it has the `request_id` of the failed request, and the field `errors` of a
+/// validation failure.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    problem_type: String,
+    title: String,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errors: Option<Vec<FieldError>>,
+    request_id: String,
+}
+
+impl Problem {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api_error::{ApiError, FieldError};
+
+    #[test]
+    fn maps_errors_to_problems() {
+        let cases = [
+            (ApiError::ForbiddenOrigin, 403, "forbidden-origin"),
+            (ApiError::Unauthorized, 401, "unauthorized"),
+            (ApiError::BadBody("expected value".to_string()), 400, "bad-body"),
+            (ApiError::BadQuery("Invalid from date".to_string()), 400, "bad-query"),
+            (
+                ApiError::Validation(vec![FieldError::new("/policy_version", "Unknown")]),
+                422,
+                "validation-failed",
+            ),
+            (ApiError::NotFound, 404, "not-found"),
+            (
+                ApiError::MethodNotAllowed { allow: vec!["GET".to_string()] },
+                405,
+                "method-not-allowed",
+            ),
+            (ApiError::RateLimited { retry_after: 30 }, 429, "rate-limited"),
+            (ApiError::storage("consent_store_failed", "KV unavailable"), 500, "storage-failed"),
+            (ApiError::internal("signing_keys_missing", "No keys"), 500, "internal-error"),
+        ];
+
+        cases
+            .iter()
+            .for_each(|(error, status, name)| {
+                let problem = serde_json::to_value(error.problem("ray")).unwrap();
+
+                assert_eq!(*status, error.status(), "{:?}", error);
+                assert_eq!(*status, problem["status"]);
+                assert_eq!(
+                    format!("tag:mathswe.com,2024:cookie-consent:{}", name),
+                    problem["type"]
+                );
+                assert_eq!(error.title(), problem["title"]);
+                assert_eq!("ray", problem["request_id"]);
+            });
+    }
+
+    #[test]
+    fn hides_internal_error_details() {
+        let errors = [
+            ApiError::storage("consent_store_failed", "KV unavailable"),
+            ApiError::internal("signing_keys_missing", "Consent signing keys are not configured"),
+        ];
+
+        errors
+            .iter()
+            .for_each(|error| assert!(
+                serde_json::to_value(error.problem("ray")).unwrap().get("detail").is_none(),
+                "{:?} only logs its details",
+                error
+            ));
+    }
+
+    #[test]
+    fn lists_validation_field_errors() {
+        let error = ApiError::Validation(vec![
+            FieldError::new("/pref/essential", "Essential cookies can't be refused"),
+            FieldError::new("/pref/marketing", "Unknown field"),
+        ]);
+        let problem = serde_json::to_value(error.problem("ray")).unwrap();
+
+        assert_eq!(
+            "/pref/essential: Essential cookies can't be refused; /pref/marketing: Unknown field",
+            problem["detail"]
+        );
+        assert_eq!("/pref/essential", problem["errors"][0]["field"]);
+        assert_eq!("Unknown field", problem["errors"][1]["message"]);
+        assert!(
+            serde_json::to_value(ApiError::NotFound.problem("ray")).unwrap().get("errors").is_none()
+        );
+    }
+
+    #[test]
+    fn sets_error_headers() {
+        assert_eq!(
+            vec![("Retry-After", "30".to_string())],
+            ApiError::RateLimited { retry_after: 30 }.headers()
+        );
+        assert_eq!(
+            vec![("WWW-Authenticate", "Bearer".to_string())],
+            ApiError::Unauthorized.headers()
+        );
+        assert_eq!(
+            vec![("Allow", "GET, POST".to_string())],
+            ApiError::MethodNotAllowed { allow: vec!["GET".to_string(), "POST".to_string()] }
+                .headers()
+        );
+        assert!(ApiError::NotFound.headers().is_empty());
+    }
+}
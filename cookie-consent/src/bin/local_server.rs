@@ -0,0 +1,460 @@
+// Copyright (c) 2024 Tobias Briones. All rights reserved.
+// This file is part of https://github.com/mathswe/legal
+
+//! Serves the cookie consent routes natively on a local port, so the service can run for
+//! development with `cargo run --features local` instead of wrangler. The consents are stored
+//! in memory, and the geolocation of every request is taken from the environment defaults.
+
+use std::env;
+use std::io::Cursor;
+use std::str::FromStr;
+
+use chrono::Utc;
+use futures::executor::block_on;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use tiny_http::{Header, Request, Response, Server};
+use url::Url;
+use worker::Method;
+
+use cookie_consent::admin::{
+    ConsentQuery,
+    is_authorized,
+    list_consents,
+    query_param,
+    read_stats,
+    StatsQuery,
+};
+use cookie_consent::anonymous_ip::{AnonymousIp, DEFAULT_IPV6_PREFIX_LENGTH};
+use cookie_consent::api_error::{ApiError, FieldError, PROBLEM_CONTENT_TYPE};
+use cookie_consent::client_req::Origin;
+use cookie_consent::config::{DomainConfig, DomainsConfig};
+use cookie_consent::consent::CookieConsentPref;
+use cookie_consent::cookie_consent::{
+    ConsentError,
+    find_consent,
+    find_consent_receipt,
+    find_consent_status,
+    find_history,
+    read_consent_req,
+    register_consent,
+    update_consent,
+    with_domain_signals,
+    with_receipt,
+    withdraw_consent,
+};
+use cookie_consent::geolocation::Geolocation;
+use cookie_consent::log::RequestLog;
+use cookie_consent::policy::PolicyVersion;
+use cookie_consent::privacy_signal::PrivacySignals;
+use cookie_consent::rate_limit::{check_rate_limit, MemoryRateCounter, RateClient, RateLimit};
+use cookie_consent::receipt::{SigningKeys, VerificationReq};
+use cookie_consent::retention::{apply_retention, RETENTION_BATCH_SIZE};
+use cookie_consent::route::{find_route, Route, route_methods, ROUTES};
+use cookie_consent::stats::MemoryStatsCounter;
+use cookie_consent::store::MemoryConsentStore;
+
+const DEFAULT_PORT: u16 = 8787;
+
+const DEFAULT_ALLOWED_DOMAINS: &str = r#"[
+    { "domain": "mathswe.com", "policies": [{ "version": "2024-03-10", "status": "Active" }] },
+    { "domain": "math.software", "policies": [{ "version": "2024-03-10", "status": "Active" }] },
+    {
+        "domain": "mathsoftware.engineer",
+        "policies": [{ "version": "2024-03-10", "status": "Active" }]
+    }
+]"#;
+
+/// Defines the local server settings, read from the environment variables `PORT`,
+/// `IPV6_PREFIX_LENGTH`, `ALLOWED_DOMAINS`, `CONSENT_SIGNING_KEYS`, `ADMIN_TOKEN`, and
+/// `GEOLOCATION_*` for the geolocation defaults.
+struct LocalConfig {
+    port: u16,
+    ipv6_prefix_length: u8,
+    domains: DomainsConfig,
+    signing_keys: Option<SigningKeys>,
+    admin_token: Option<String>,
+    geolocation: Geolocation,
+}
+
+impl LocalConfig {
+    fn from_env() -> Self {
+        let time_zone = var("GEOLOCATION_TIME_ZONE")
+            .and_then(|time_zone| chrono_tz::Tz::from_str(&time_zone).ok())
+            .unwrap_or(chrono_tz::Tz::UTC);
+
+        LocalConfig {
+            port: var("PORT")
+                .and_then(|port| port.parse().ok())
+                .unwrap_or(DEFAULT_PORT),
+            ipv6_prefix_length: var("IPV6_PREFIX_LENGTH")
+                .and_then(|length| length.parse().ok())
+                .unwrap_or(DEFAULT_IPV6_PREFIX_LENGTH),
+            domains: DomainsConfig::from_json(
+                &var("ALLOWED_DOMAINS").unwrap_or(DEFAULT_ALLOWED_DOMAINS.to_string())
+            ).expect("Fail to read the allowed domains"),
+            signing_keys: var("CONSENT_SIGNING_KEYS").map(|json| {
+                SigningKeys::from_json(&json).expect("Fail to read the consent signing keys")
+            }),
+            admin_token: var("ADMIN_TOKEN"),
+            geolocation: Geolocation::new(
+                time_zone,
+                var("GEOLOCATION_COUNTRY"),
+                var("GEOLOCATION_CITY"),
+                var("GEOLOCATION_REGION"),
+                var("GEOLOCATION_REGION_CODE"),
+            ),
+        }
+    }
+}
+
+type LocalResponse = Response<Cursor<Vec<u8>>>;
+
+fn main() {
+    let config = LocalConfig::from_env();
+    let store = MemoryConsentStore::default();
+    let counter = MemoryRateCounter::default();
+    let stats = MemoryStatsCounter::default();
+    let server = Server::http(("127.0.0.1", config.port))
+        .expect("Fail to start the local server");
+
+    println!("Cookie consent service listening on http://127.0.0.1:{}", config.port);
+
+    for req in server.incoming_requests() {
+        if let Err(e) = handle(&store, &counter, &stats, &config, req) {
+            eprintln!("Fail to respond request: {}", e);
+        }
+    }
+}
+
+/// Serves the request as the Worker does, matching it against the same `ROUTES` with
+/// `find_route`, so unknown paths are `NotFound` and known paths with another method are
+/// `MethodNotAllowed`. The admin routes and the route errors have no CORS headers, like in the
+/// Worker.
+fn handle(
+    store: &MemoryConsentStore,
+    counter: &MemoryRateCounter,
+    stats: &MemoryStatsCounter,
+    config: &LocalConfig,
+    mut req: Request,
+) -> std::io::Result<()> {
+    let origin = header(&req, "Origin")
+        .and_then(|origin| Origin::from_str(&origin, &config.domains));
+    let domain_config = origin
+        .clone()
+        .and_then(|origin| config.domains.get(&origin.domain()).cloned())
+        .unwrap_or(config.domains.default_domain().clone());
+    let domain = domain_config.domain().clone();
+    let geolocation = config.geolocation.clone();
+    let ip = req
+        .remote_addr()
+        .map(|addr| AnonymousIp::from_ip(addr.ip(), config.ipv6_prefix_length));
+    let user_agent = header(&req, "User-Agent").unwrap_or_default();
+    let privacy_signals = PrivacySignals::from_headers(
+        header(&req, "Sec-GPC").as_deref(),
+        header(&req, "DNT").as_deref(),
+    );
+    let log = RequestLog::new(header(&req, "cf-ray"), req.method().to_string(), req.url());
+    let signals = |consent| with_receipt(
+        with_domain_signals(consent, &domain_config),
+        domain_config.domain(),
+        config.signing_keys.as_ref(),
+    );
+    let method = Method::from(req.method().to_string());
+    let path = req.url().split('?').next().unwrap_or_default().to_string();
+
+    if method == Method::Options {
+        return req.respond(preflight(origin, &path, &log));
+    }
+
+    let route = match find_route(&ROUTES, &method, &path) {
+        Ok(route) => route,
+        Err(e) => return req.respond(problem(e, &log)),
+    };
+    let id = route.param(&path, "id").unwrap_or_default();
+    let is_admin_route = matches!(
+        route,
+        Route::GetConsents | Route::PostRetention | Route::GetStats
+    );
+
+    if route == Route::PostConsent {
+        let client = RateClient::new(domain, ip.clone(), user_agent.clone());
+        let rate_limit = block_on(check_rate_limit(
+            counter,
+            &client,
+            domain_config.rate_limit(),
+            Utc::now(),
+        ));
+
+        match rate_limit {
+            Ok(RateLimit::Allowed) => {}
+            Ok(RateLimit::Limited { retry_after }) => {
+                let res = problem(ApiError::RateLimited { retry_after }, &log);
+
+                return respond(req, origin, res);
+            }
+            Err(e) => log.warn("rate_limit_check_failed", e),
+        }
+    }
+
+    let res = match route {
+        Route::PostConsent => match read_body(&mut req, &domain_config) {
+            Ok(Ok((pref, policy_version))) => consent_response(block_on(register_consent(
+                store,
+                stats,
+                &log,
+                &domain_config,
+                pref,
+                policy_version,
+                geolocation,
+                ip,
+                user_agent,
+                privacy_signals,
+            )).map(signals)),
+            Ok(Err(errors)) => Err(ApiError::Validation(errors)),
+            Err(e) => Err(ApiError::BadBody(e.to_string())),
+        },
+        Route::GetConsent => consent_response(
+            block_on(find_consent(store, &id, &domain_config)).map(signals)
+        ),
+        Route::PostWithdrawal => consent_response(block_on(withdraw_consent(
+            store,
+            id,
+            &domain_config,
+            geolocation,
+            ip,
+            user_agent,
+            privacy_signals,
+        )).map(signals)),
+        Route::PostUpdate => match read_body(&mut req, &domain_config) {
+            Ok(Ok((pref, policy_version))) => consent_response(block_on(update_consent(
+                store,
+                id,
+                &domain_config,
+                pref,
+                policy_version,
+                geolocation,
+                ip,
+                user_agent,
+                privacy_signals,
+            )).map(signals)),
+            Ok(Err(errors)) => Err(ApiError::Validation(errors)),
+            Err(e) => Err(ApiError::BadBody(e.to_string())),
+        },
+        Route::GetHistory => consent_response(
+            block_on(find_history(store, id, &domain_config))
+                .map(|history| history.into_iter().map(signals).collect::<Vec<_>>())
+        ),
+        Route::GetReceipt => consent_response(
+            block_on(find_consent_receipt(store, &id, &domain_config))
+        ),
+        Route::GetStatus => consent_response(
+            block_on(find_consent_status(store, &id, &domain_config, Utc::now()))
+        ),
+        Route::GetConsents | Route::PostRetention | Route::GetStats
+            if !is_admin(&req, config) =>
+        {
+            Err(ApiError::Unauthorized)
+        }
+        Route::GetConsents => {
+            let url = Url::parse(&format!("http://127.0.0.1{}", req.url())).unwrap();
+
+            match ConsentQuery::from_url(&url, &config.domains) {
+                Ok(query) => consent_response(
+                    block_on(list_consents(store, &query)).map_err(ConsentError::from)
+                ),
+                Err(e) => Err(ApiError::BadQuery(e.to_string())),
+            }
+        }
+        Route::PostRetention => {
+            let url = Url::parse(&format!("http://127.0.0.1{}", req.url())).unwrap();
+            let cursor = query_param(&url, "cursor");
+
+            consent_response(block_on(apply_retention(
+                store,
+                &config.domains,
+                Utc::now(),
+                cursor,
+                RETENTION_BATCH_SIZE,
+            )).map_err(ConsentError::from))
+        }
+        Route::GetStats => {
+            let url = Url::parse(&format!("http://127.0.0.1{}", req.url())).unwrap();
+
+            match StatsQuery::from_url(&url, &config.domains) {
+                Ok(query) => consent_response(
+                    block_on(read_stats(stats, &query)).map_err(ConsentError::from)
+                ),
+                Err(e) => Err(ApiError::BadQuery(e.to_string())),
+            }
+        }
+        Route::PostVerification => match (&config.signing_keys, read_json(&mut req)) {
+            (Some(keys), Ok(VerificationReq { receipt, domain })) => match keys.verify(
+                &receipt,
+                origin.as_ref().map(|_| domain_config.domain()).or(domain.as_ref()),
+                Utc::now(),
+            ) {
+                Ok(claims) => Ok(json(&claims)),
+                Err(e) => Err(ApiError::Validation(
+                    vec![FieldError::new("/receipt", e.to_string())]
+                )),
+            },
+            (None, _) => Err(ApiError::internal(
+                "signing_keys_missing",
+                "Consent signing keys are not configured",
+            )),
+            (_, Err(e)) => Err(ApiError::BadBody(e.to_string())),
+        },
+    }.unwrap_or_else(|error| problem(error, &log));
+
+    if is_admin_route {
+        req.respond(res)
+    } else {
+        respond(req, origin, res)
+    }
+}
+
+/// Answers the CORS preflight of the path with the methods of its routes, like the Worker. The
+/// preflight of a path no route matches is `NotFound`, and a request without a valid `Origin`
+/// is answered without CORS headers, as in the Worker `local` mode.
+fn preflight(origin: Option<Origin>, path: &str, log: &RequestLog) -> LocalResponse {
+    let methods = route_methods(&ROUTES, path);
+
+    if methods.is_empty() {
+        return problem(ApiError::NotFound, log);
+    }
+
+    let res = Response::from_string("").with_status_code(204);
+
+    match origin {
+        Some(origin) => preflight_cors(res, origin, &methods),
+        None => res,
+    }
+}
+
+fn respond(req: Request, origin: Option<Origin>, res: LocalResponse) -> std::io::Result<()> {
+    match origin {
+        Some(origin) => req.respond(cors(res, origin)),
+        None => req.respond(res),
+    }
+}
+
+fn read_body(
+    req: &mut Request,
+    domain_config: &DomainConfig,
+) -> Result<Result<(CookieConsentPref, PolicyVersion), Vec<FieldError>>, serde_json::Error> {
+    read_json::<Value>(req).map(|body| read_consent_req(body, domain_config))
+}
+
+fn read_json<T: DeserializeOwned>(req: &mut Request) -> Result<T, serde_json::Error> {
+    let mut body = String::new();
+    let _ = req.as_reader().read_to_string(&mut body);
+
+    serde_json::from_str::<T>(&body)
+}
+
+fn consent_response<T: Serialize>(
+    result: Result<T, ConsentError>,
+) -> Result<LocalResponse, ApiError> {
+    result
+        .map(|value| json(&value))
+        .map_err(|e| e.to_api_error("consent_store_failed"))
+}
+
+fn json<T: Serialize>(value: &T) -> LocalResponse {
+    Response::from_string(serde_json::to_string(value).unwrap())
+        .with_header(Header::from_bytes("Content-Type", "application/json").unwrap())
+}
+
+/// Returns the problem details response of the error, and prints the details of the server
+/// errors, since the local server has no Workers logs.
+fn problem(error: ApiError, log: &RequestLog) -> LocalResponse {
+    if let ApiError::Storage { event, error } | ApiError::Internal { event, error } = &error {
+        eprintln!("{}: {}", event, error);
+    }
+
+    error
+        .headers()
+        .into_iter()
+        .fold(
+            Response::from_string(error.problem(log.request_id()).to_json())
+                .with_status_code(error.status())
+                .with_header(Header::from_bytes("Content-Type", PROBLEM_CONTENT_TYPE).unwrap()),
+            |res, (name, value)| res.with_header(Header::from_bytes(name, value).unwrap()),
+        )
+}
+
+fn cors(res: LocalResponse, origin: Origin) -> LocalResponse {
+    let allow_origin = Header::from_bytes("Access-Control-Allow-Origin", origin.to_string());
+
+    res
+        .with_header(allow_origin.unwrap())
+        .with_header(Header::from_bytes("Access-Control-Expose-Headers", "Retry-After").unwrap())
+}
+
+fn preflight_cors(res: LocalResponse, origin: Origin, methods: &[Method]) -> LocalResponse {
+    let allow_origin = Header::from_bytes("Access-Control-Allow-Origin", origin.to_string());
+    let allow_methods = methods
+        .iter()
+        .map(Method::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    res
+        .with_header(allow_origin.unwrap())
+        .with_header(Header::from_bytes("Access-Control-Allow-Methods", allow_methods).unwrap())
+        .with_header(Header::from_bytes("Access-Control-Allow-Headers", "Content-Type").unwrap())
+        .with_header(Header::from_bytes("Access-Control-Max-Age", "86400").unwrap())
+}
+
+fn is_admin(req: &Request, config: &LocalConfig) -> bool {
+    is_authorized(header(req, "Authorization").as_deref(), config.admin_token.as_deref())
+}
+
+fn header(req: &Request, name: &'static str) -> Option<String> {
+    req
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv(name))
+        .map(|header| header.value.to_string())
+}
+
+fn var(key: &str) -> Option<String> {
+    env::var(key).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use cookie_consent::client_req::Origin;
+    use cookie_consent::config::DomainsConfig;
+    use cookie_consent::log::RequestLog;
+
+    use crate::{DEFAULT_ALLOWED_DOMAINS, preflight};
+
+    #[test]
+    fn default_allowed_domains_are_valid() {
+        assert!(DomainsConfig::from_json(DEFAULT_ALLOWED_DOMAINS).is_ok());
+    }
+
+    #[test]
+    fn answers_preflight_of_worker_routes() {
+        let domains = DomainsConfig::from_json(DEFAULT_ALLOWED_DOMAINS).unwrap();
+        let origin = || Origin::from_str("https://mathswe.com", &domains);
+        let log = RequestLog::new(None, "OPTIONS", "/");
+        let allow_methods = |path| {
+            preflight(origin(), path, &log)
+                .headers()
+                .iter()
+                .find(|header| header.field.equiv("Access-Control-Allow-Methods"))
+                .map(|header| header.value.to_string())
+        };
+
+        assert_eq!(Some("POST".to_string()), allow_methods("/"));
+        assert_eq!(Some("GET".to_string()), allow_methods("/abc"));
+        assert_eq!(Some("POST, GET".to_string()), allow_methods("/verify"));
+        assert_eq!(204, preflight(None, "/abc", &log).status_code().0);
+        assert_eq!(404, preflight(origin(), "/abc/unknown", &log).status_code().0);
+        assert_eq!(404, preflight(origin(), "//abc", &log).status_code().0);
+    }
+}
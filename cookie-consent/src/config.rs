@@ -0,0 +1,348 @@
+// Copyright (c) 2024 Tobias Briones. All rights reserved.
+// This file is part of https://github.com/mathswe/legal
+
+use std::fmt::{Display, Formatter};
+
+use serde::Deserialize;
+use worker::{Env, Error};
+
+use crate::consent::Domain;
+use crate::consent_mode::ConsentModeMapping;
+use crate::consent_receipt::ControllerConfig;
+use crate::lifetime::LifetimeConfig;
+use crate::policy::{KnownPolicy, PolicyStatus};
+use crate::rate_limit::RateLimitConfig;
+use crate::retention::RetentionConfig;
+use crate::tcf::TcfConfig;
+
+const ALLOWED_DOMAINS_VAR: &str = "ALLOWED_DOMAINS";
+
+/// Defines a registrable `Domain` allowed to send requests, the subdomains it allows, and its
+/// known cookie policy versions. If `subdomains` is `None`, all its subdomains are allowed.
+/// Otherwise, only the domain itself and the given subdomains are allowed.
+///
+/// Its `consent_mode` maps the consents to Google Consent Mode signals. If `tcf` is present,
+/// its consents also have an IAB TCF TC string. Its `controller` is stated in the consent
+/// receipts. Its `rate_limit` throttles the consents a client can register. Its `retention`
+/// defines how long its consents are kept. Its `lifetime` defines how long they're valid
+/// before asking the user again. Unknown settings are rejected, so a misspelled one doesn't
+/// silently fall back to its default.
+#[derive(PartialEq, Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DomainConfig {
+    domain: Domain,
+    #[serde(default)]
+    subdomains: Option<Vec<String>>,
+    policies: Vec<KnownPolicy>,
+    #[serde(default)]
+    consent_mode: ConsentModeMapping,
+    #[serde(default)]
+    tcf: Option<TcfConfig>,
+    #[serde(default)]
+    controller: Option<ControllerConfig>,
+    #[serde(default)]
+    rate_limit: RateLimitConfig,
+    #[serde(default)]
+    retention: RetentionConfig,
+    #[serde(default)]
+    lifetime: LifetimeConfig,
+}
+
+impl DomainConfig {
+    pub fn new(
+        domain: Domain,
+        subdomains: Option<Vec<String>>,
+        policies: Vec<KnownPolicy>,
+    ) -> Self {
+        DomainConfig {
+            domain,
+            subdomains,
+            policies,
+            consent_mode: ConsentModeMapping::default(),
+            tcf: None,
+            controller: None,
+            rate_limit: RateLimitConfig::default(),
+            retention: RetentionConfig::default(),
+            lifetime: LifetimeConfig::default(),
+        }
+    }
+
+    pub fn domain(&self) -> &Domain {
+        &self.domain
+    }
+
+    pub fn policies(&self) -> &[KnownPolicy] {
+        &self.policies
+    }
+
+    pub fn consent_mode(&self) -> &ConsentModeMapping {
+        &self.consent_mode
+    }
+
+    pub fn tcf(&self) -> Option<&TcfConfig> {
+        self.tcf.as_ref()
+    }
+
+    pub fn controller(&self) -> Option<&ControllerConfig> {
+        self.controller.as_ref()
+    }
+
+    pub fn rate_limit(&self) -> &RateLimitConfig {
+        &self.rate_limit
+    }
+
+    pub fn retention(&self) -> &RetentionConfig {
+        &self.retention
+    }
+
+    pub fn lifetime(&self) -> &LifetimeConfig {
+        &self.lifetime
+    }
+
+    /// Returns whether the given subdomain of this `Domain` is allowed, where `None` means
+    /// the domain itself.
+    pub fn allows_subdomain(&self, subdomain: Option<&str>) -> bool {
+        match (subdomain, &self.subdomains) {
+            (None, _) => true,
+            (Some(_), None) => true,
+            (Some(subdomain), Some(subdomains)) => subdomains
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(subdomain)),
+        }
+    }
+}
+
+/// Defines the `Domain`s allowed to send requests, loaded from the `ALLOWED_DOMAINS` Worker
+/// variable as a JSON array of `DomainConfig`s, so adding a site doesn't require a code
+/// change.
+#[derive(PartialEq, Clone, Debug)]
+pub struct DomainsConfig(Vec<DomainConfig>);
+
+impl DomainsConfig {
+    pub fn from_env(env: &Env) -> Result<Self, Error> {
+        let json = env.var(ALLOWED_DOMAINS_VAR)?.to_string();
+
+        Self::from_json(&json).map_err(|e| Error::RustError(e.to_string()))
+    }
+
+    /// Parses and validates the configuration, so there's at least one domain, no domain is
+    /// configured twice, every domain has an active cookie policy version, and their TCF,
+    /// controller, rate limit, retention, and lifetime settings are valid, and consents aren't
+    /// valid for longer than they're retained.
+    pub fn from_json(json: &str) -> Result<Self, ConfigError> {
+        let domains = serde_json::from_str::<Vec<DomainConfig>>(json)
+            .map_err(|e| ConfigError(format!("Invalid {}: {}", ALLOWED_DOMAINS_VAR, e)))?;
+
+        if domains.is_empty() {
+            return Err(ConfigError(format!("{} has no domains", ALLOWED_DOMAINS_VAR)));
+        }
+
+        for (i, config) in domains.iter().enumerate() {
+            if domains[..i].iter().any(|other| other.domain == config.domain) {
+                return Err(ConfigError(format!(
+                    "Domain {} is configured more than once",
+                    config.domain
+                )));
+            }
+
+            if !config.policies.iter().any(|policy| policy.status() == PolicyStatus::Active) {
+                return Err(ConfigError(format!(
+                    "Domain {} has no active cookie policy version",
+                    config.domain
+                )));
+            }
+
+            if let Some(Err(e)) = config.tcf.as_ref().map(TcfConfig::validate) {
+                return Err(ConfigError(format!("Domain {} has {}", config.domain, e)));
+            }
+
+            if let Some(Err(e)) = config.controller.as_ref().map(ControllerConfig::validate) {
+                return Err(ConfigError(format!("Domain {} has {}", config.domain, e)));
+            }
+
+            if let Err(e) = config.rate_limit.validate() {
+                return Err(ConfigError(format!("Domain {} has {}", config.domain, e)));
+            }
+
+            if let Err(e) = config.retention.validate() {
+                return Err(ConfigError(format!("Domain {} has {}", config.domain, e)));
+            }
+
+            if let Err(e) = config.lifetime.validate() {
+                return Err(ConfigError(format!("Domain {} has {}", config.domain, e)));
+            }
+
+            if config.lifetime.max_days() > config.retention.consent_days() {
+                return Err(ConfigError(format!(
+                    "Domain {} has a consent lifetime longer than its retention",
+                    config.domain
+                )));
+            }
+        }
+
+        Ok(DomainsConfig(domains))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=&DomainConfig> {
+        self.0.iter()
+    }
+
+    pub fn get(&self, domain: &Domain) -> Option<&DomainConfig> {
+        self.0.iter().find(|config| &config.domain == domain)
+    }
+
+    /// Returns the first configured domain, which is the one assumed when there's no origin,
+    /// like in local mode.
+    pub fn default_domain(&self) -> &DomainConfig {
+        &self.0[0]
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct ConfigError(String);
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{ConfigError, DomainsConfig};
+    use crate::consent::Domain;
+
+    const CONFIG_JSON: &str = r#"[
+        {
+            "domain": "mathswe.com",
+            "policies": [{ "version": "2024-03-10", "status": "Active" }]
+        },
+        {
+            "domain": "math.software",
+            "subdomains": ["staging", "www"],
+            "policies": [
+                { "version": "2024-01-01", "status": "Retired" },
+                { "version": "2024-03-10", "status": "Active" }
+            ],
+            "tcf": {
+                "cmp_id": 10,
+                "cmp_version": 1,
+                "publisher_cc": "HN",
+                "mapping": { "targeting": { "purposes": [1, 2, 3, 4] } }
+            }
+        }
+    ]"#;
+
+    #[test]
+    fn loads_allowed_domains() {
+        let config = DomainsConfig::from_json(CONFIG_JSON).unwrap();
+        let domains = config
+            .iter()
+            .map(|domain_config| domain_config.domain().clone())
+            .collect::<Vec<_>>();
+
+        assert_eq!(vec![Domain::new("mathswe.com"), Domain::new("math.software")], domains);
+        assert_eq!(&Domain::new("mathswe.com"), config.default_domain().domain());
+        assert_eq!(2, config.get(&Domain::new("math.software")).unwrap().policies().len());
+        assert_eq!(None, config.get(&Domain::new("mathsoftware.engineer")));
+        assert_eq!(None, config.default_domain().tcf());
+        assert!(config.get(&Domain::new("math.software")).unwrap().tcf().is_some());
+    }
+
+    #[test]
+    fn allows_configured_subdomains() {
+        let config = DomainsConfig::from_json(CONFIG_JSON).unwrap();
+        let mathswe = config.get(&Domain::new("mathswe.com")).unwrap();
+        let math_software = config.get(&Domain::new("math.software")).unwrap();
+
+        assert!(mathswe.allows_subdomain(None));
+        assert!(mathswe.allows_subdomain(Some("any.nested")));
+        assert!(math_software.allows_subdomain(None));
+        assert!(math_software.allows_subdomain(Some("staging")));
+        assert!(math_software.allows_subdomain(Some("WWW")));
+        assert!(!math_software.allows_subdomain(Some("dev")));
+        assert!(!math_software.allows_subdomain(Some("dev.staging")));
+    }
+
+    #[test]
+    fn rejects_invalid_config() {
+        let invalid_configs = [
+            "",
+            "{}",
+            "[]",
+            r#"[{ "domain": "mathswe.com" }]"#,
+            r#"[{ "domain": "mathswe.com", "policies": [] }]"#,
+            r#"[{
+                "domain": "mathswe.com",
+                "policies": [{ "version": "2024-01-01", "status": "Retired" }]
+            }]"#,
+            r#"[
+                {
+                    "domain": "mathswe.com",
+                    "policies": [{ "version": "2024-03-10", "status": "Active" }]
+                },
+                {
+                    "domain": "MathSwe.com",
+                    "policies": [{ "version": "2024-03-10", "status": "Active" }]
+                }
+            ]"#,
+            r#"[{
+                "domain": "mathswe.com",
+                "policies": [{ "version": "2024-03-10", "status": "Active" }],
+                "tcf": { "cmp_id": 0, "cmp_version": 1, "publisher_cc": "HN" }
+            }]"#,
+            r#"[{
+                "domain": "mathswe.com",
+                "policies": [{ "version": "2024-03-10", "status": "Active" }],
+                "controller": {
+                    "name": "MathSwe",
+                    "contact": "",
+                    "address": "",
+                    "email": "",
+                    "phone": "",
+                    "url": "http://mathswe.com",
+                    "policy_url": "https://mathswe.com/legal/cookie-policy"
+                }
+            }]"#,
+            r#"[{
+                "domain": "mathswe.com",
+                "policies": [{ "version": "2024-03-10", "status": "Active" }],
+                "rate_limit": { "max_requests": 0 }
+            }]"#,
+            r#"[{
+                "domain": "mathswe.com",
+                "policies": [{ "version": "2024-03-10", "status": "Active" }],
+                "retention": { "consent_days": 30, "personal_data_days": 60 }
+            }]"#,
+            r#"[{
+                "domain": "mathswe.com",
+                "policies": [{ "version": "2024-03-10", "status": "Active" }],
+                "lifetime": { "countries": { "fr": 180 } }
+            }]"#,
+            r#"[{
+                "domain": "mathswe.com",
+                "policies": [{ "version": "2024-03-10", "status": "Active" }],
+                "retention": { "consent_days": 200, "personal_data_days": 100 },
+                "lifetime": { "default_days": 180, "countries": { "DE": 395 } }
+            }]"#,
+            r#"[{
+                "domain": "mathswe.com",
+                "policies": [{ "version": "2024-03-10", "status": "Active" }],
+                "retension": { "consent_days": 30, "personal_data_days": 30 }
+            }]"#,
+            r#"[{
+                "domain": "mathswe.com",
+                "policies": [{ "version": "2024-03-10", "status": "Active" }],
+                "retention": { "consent_day": 30, "personal_data_days": 30 }
+            }]"#,
+        ];
+
+        invalid_configs
+            .iter()
+            .for_each(|json| assert!(
+                matches!(DomainsConfig::from_json(json), Err(ConfigError(_))),
+                "{} is not a valid config",
+                json
+            ))
+    }
+}
@@ -1,34 +1,88 @@
 // Copyright (c) 2024 Tobias Briones. All rights reserved.
 // This file is part of https://github.com/mathswe/legal
 
+use std::fmt::{Display, Formatter};
+
 use chrono::{DateTime, Utc};
 use nanoid::nanoid;
-use serde::{Deserialize, Serialize};
-use strum_macros::EnumIter;
-
-use Domain::{MathSoftware, MathSoftwareEngineer, MathSweCom};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
 
-use crate::anonymous_ip::AnonymousIpv4;
+use crate::anonymous_ip::AnonymousIp;
+use crate::api_error::FieldError;
+use crate::config::DomainConfig;
+use crate::consent_mode::{ConsentModeMapping, ConsentModeSignals};
 use crate::geolocation::Geolocation;
+use crate::lifetime::LifetimeConfig;
+use crate::policy::PolicyVersion;
+use crate::privacy_signal::PrivacySignals;
+use crate::receipt::SigningKeys;
+use crate::retention::RetentionConfig;
+use crate::tcf::{TcfConfig, TcString, VendorList};
 
-#[derive(PartialEq, Clone, EnumIter, Debug, Serialize, Deserialize)]
-pub enum Domain {
-    MathSweCom,
-    MathSoftware,
-    MathSoftwareEngineer,
-}
+/// Defines a registrable domain name allowed by the server configuration, like
+/// `mathswe.com`. It's stored as its lowercase ASCII domain name, so IDNs are stored in
+/// punycode. Consents stored before the domains became configurable keep the former enum
+/// names, like `MathSweCom`, which are still read as their domain names.
+#[derive(PartialEq, Eq, Clone, Debug, Serialize)]
+pub struct Domain(String);
 
 impl Domain {
+    pub fn new(domain_name: impl Into<String>) -> Self {
+        let domain_name = domain_name.into();
+
+        Domain(
+            idna::domain_to_ascii(&domain_name)
+                .unwrap_or_else(|_| domain_name.to_lowercase())
+        )
+    }
+
     pub fn to_domain_name(&self) -> String {
-        match self {
-            MathSweCom => "mathswe.com".to_string(),
-            MathSoftware => "math.software".to_string(),
-            MathSoftwareEngineer => "mathsoftware.engineer".to_string(),
+        self.0.clone()
+    }
+
+    fn from_legacy_name(name: &str) -> Option<Self> {
+        match name {
+            "MathSweCom" => Some(Domain::new("mathswe.com")),
+            "MathSoftware" => Some(Domain::new("math.software")),
+            "MathSoftwareEngineer" => Some(Domain::new("mathsoftware.engineer")),
+            _ => None,
         }
     }
 }
 
+impl<'de> Deserialize<'de> for Domain {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+
+        Ok(Domain::from_legacy_name(&name).unwrap_or_else(|| Domain::new(name)))
+    }
+}
+
+impl Display for Domain {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Defines a field a request body must have, with the check of its JSON type and the
+/// message of the check failure.
+type RequiredField = (&'static str, fn(&Value) -> bool, &'static str);
+
+const CONSENT_REQ_FIELDS: [RequiredField; 2] = [
+    ("pref", Value::is_object, "Must be an object"),
+    ("policy_version", Value::is_string, "Must be a string"),
+];
+
+const CONSENT_PREF_FIELDS: [RequiredField; 4] = [
+    ("essential", Value::is_boolean, "Must be a boolean"),
+    ("functional", Value::is_boolean, "Must be a boolean"),
+    ("analytical", Value::is_boolean, "Must be a boolean"),
+    ("targeting", Value::is_boolean, "Must be a boolean"),
+];
+
 #[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct CookieConsentPref {
     essential: bool,
     functional: bool,
@@ -36,14 +90,195 @@ pub struct CookieConsentPref {
     targeting: bool,
 }
 
+impl CookieConsentPref {
+    /// Returns the preference that only accepts the essential cookies, which can't be refused,
+    /// so every non-essential category is rejected.
+    pub fn essential_only() -> Self {
+        CookieConsentPref {
+            essential: true,
+            functional: false,
+            analytical: false,
+            targeting: false,
+        }
+    }
+
+    pub fn essential(&self) -> bool {
+        self.essential
+    }
+
+    pub fn functional(&self) -> bool {
+        self.functional
+    }
+
+    pub fn analytical(&self) -> bool {
+        self.analytical
+    }
+
+    pub fn targeting(&self) -> bool {
+        self.targeting
+    }
+
+    /// Validates the rules of each cookie category. Essential cookies are required for the
+    /// site to work, so they can't be refused, while the other categories are the choice of
+    /// the user.
+    pub fn validate(&self) -> Result<(), Vec<FieldError>> {
+        if self.essential {
+            Ok(())
+        } else {
+            Err(vec![FieldError::new("/pref/essential", "Essential cookies can't be refused")])
+        }
+    }
+
+    /// Returns the preference that honors the given `PrivacySignals`, and whether it
+    /// overrides this preference. If the GPC signal is present, the user opted out of selling
+    /// and sharing their data, so targeting cookies are rejected whatever they chose.
+    pub fn honor(self, signals: &PrivacySignals) -> (Self, bool) {
+        if signals.gpc() && self.targeting {
+            (CookieConsentPref { targeting: false, ..self }, true)
+        } else {
+            (self, false)
+        }
+    }
+}
+
+/// Defines the body the client sends to register a consent, that is, the preference the user
+/// gave and the version of the cookie policy they saw.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CookieConsentReq {
+    pref: CookieConsentPref,
+    policy_version: String,
+}
+
+impl CookieConsentReq {
+    /// Reads the request from a JSON body, which must have exactly its fields with their
+    /// types, or returns the error of each missing, unknown, or mistyped field.
+    pub fn from_json(json: Value) -> Result<Self, Vec<FieldError>> {
+        let mut errors = field_errors("", &json, &CONSENT_REQ_FIELDS);
+
+        if let Some(pref) = json.get("pref").filter(|pref| pref.is_object()) {
+            errors.extend(field_errors("/pref", pref, &CONSENT_PREF_FIELDS));
+        }
+
+        if errors.is_empty() {
+            serde_json::from_value(json).map_err(|e| vec![FieldError::new("", e.to_string())])
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Validates the rules of the cookie categories, and the requested policy version against
+    /// the configuration of the `Domain` the request comes from.
+    pub fn validate(
+        self,
+        config: &DomainConfig,
+    ) -> Result<(CookieConsentPref, PolicyVersion), Vec<FieldError>> {
+        let pref_errors = self.pref.validate().err().unwrap_or_default();
+        let policy_version = PolicyVersion::validate(self.policy_version, config.policies())
+            .map_err(|e| FieldError::new("/policy_version", e.to_string()));
+
+        match policy_version {
+            Ok(policy_version) if pref_errors.is_empty() => Ok((self.pref, policy_version)),
+            result => Err(pref_errors.into_iter().chain(result.err()).collect()),
+        }
+    }
+}
+
+/// Returns the errors of the fields of a JSON object at the given JSON Pointer, which must
+/// have exactly the given fields.
+fn field_errors(pointer: &str, json: &Value, fields: &[RequiredField]) -> Vec<FieldError> {
+    let Some(object) = json.as_object() else {
+        return vec![FieldError::new(pointer, "Must be an object")];
+    };
+    let field_pointer = |name: &str| {
+        format!("{}/{}", pointer, name.replace('~', "~0").replace('/', "~1"))
+    };
+    let invalid_fields = fields
+        .iter()
+        .filter_map(|(name, is_valid, message)| match object.get(*name) {
+            None => Some(FieldError::new(field_pointer(name), "Missing field")),
+            Some(value) if !is_valid(value) => Some(FieldError::new(field_pointer(name), *message)),
+            Some(_) => None,
+        });
+    let unknown_fields = object
+        .keys()
+        .filter(|key| fields.iter().all(|(name, _, _)| name != key))
+        .map(|key| FieldError::new(field_pointer(key), "Unknown field"));
+
+    invalid_fields.chain(unknown_fields).collect()
+}
+
+/// Defines the payload of a registered consent. If `previous_id` is present, the consent
+/// updates the preference of the consent with that ID. If `withdrawn_id` is present, the
+/// consent is a withdrawal of the consent with that ID, so its preference only accepts
+/// essential cookies. If `pref_overridden` is `true`, the `pref` the user sent was overridden
+/// to honor the `privacy_signals`. The consent is deleted at `retained_until`, and its
+/// personal data is removed earlier, so `personal_data_redacted` becomes `true`.
 #[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
 pub struct CookieConsentValue {
     domain: Domain,
     pref: CookieConsentPref,
+    #[serde(default)]
+    policy_version: Option<PolicyVersion>,
     created_at: DateTime<Utc>,
     geolocation: Geolocation,
-    anonymous_ip: Option<AnonymousIpv4>,
+    anonymous_ip: Option<AnonymousIp>,
     user_agent: String,
+    #[serde(default)]
+    privacy_signals: PrivacySignals,
+    #[serde(default)]
+    pref_overridden: bool,
+    #[serde(default)]
+    previous_id: Option<String>,
+    #[serde(default)]
+    withdrawn_id: Option<String>,
+    #[serde(default)]
+    retained_until: Option<DateTime<Utc>>,
+    #[serde(default)]
+    personal_data_redacted: bool,
+}
+
+impl CookieConsentValue {
+    pub fn domain(&self) -> &Domain {
+        &self.domain
+    }
+
+    pub fn pref(&self) -> &CookieConsentPref {
+        &self.pref
+    }
+
+    pub fn created_at(&self) -> DateTime<Utc> {
+        self.created_at
+    }
+
+    pub fn geolocation(&self) -> &Geolocation {
+        &self.geolocation
+    }
+
+    pub fn policy_version(&self) -> Option<&PolicyVersion> {
+        self.policy_version.as_ref()
+    }
+
+    pub fn retained_until(&self) -> Option<DateTime<Utc>> {
+        self.retained_until
+    }
+
+    pub fn personal_data_redacted(&self) -> bool {
+        self.personal_data_redacted
+    }
+
+    pub fn previous_id(&self) -> Option<&String> {
+        self.previous_id.as_ref()
+    }
+
+    pub fn withdrawn_id(&self) -> Option<&String> {
+        self.withdrawn_id.as_ref()
+    }
+
+    /// Returns the ID of the consent this one replaces, either by updating or withdrawing it.
+    pub fn predecessor_id(&self) -> Option<&String> {
+        self.previous_id.as_ref().or(self.withdrawn_id.as_ref())
+    }
 }
 
 #[derive(PartialEq, Debug, Serialize, Deserialize)]
@@ -56,38 +291,176 @@ impl CookieConsent {
     pub fn new(
         domain: Domain,
         pref: CookieConsentPref,
+        policy_version: PolicyVersion,
+        geolocation: Geolocation,
+        anonymous_ip: Option<AnonymousIp>,
+        user_agent: String,
+        privacy_signals: PrivacySignals,
+    ) -> Self {
+        Self::create(
+            domain,
+            pref,
+            Some(policy_version),
+            geolocation,
+            anonymous_ip,
+            user_agent,
+            privacy_signals,
+        )
+    }
+
+    fn create(
+        domain: Domain,
+        pref: CookieConsentPref,
+        policy_version: Option<PolicyVersion>,
         geolocation: Geolocation,
-        anonymous_ip: Option<AnonymousIpv4>,
+        anonymous_ip: Option<AnonymousIp>,
         user_agent: String,
+        privacy_signals: PrivacySignals,
     ) -> Self {
+        let (pref, pref_overridden) = pref.honor(&privacy_signals);
+
         CookieConsent {
             id: nanoid!(),
             value: CookieConsentValue {
                 domain,
                 pref,
+                policy_version,
                 created_at: Utc::now(),
                 geolocation,
                 anonymous_ip,
                 user_agent,
+                privacy_signals,
+                pref_overridden,
+                previous_id: None,
+                withdrawn_id: None,
+                retained_until: None,
+                personal_data_redacted: false,
             },
         }
     }
 
+    /// Creates a new consent that updates the preference of the consent with ID
+    /// `previous_id`. The previous consent is kept as is, so the chain of consents gives the
+    /// history of the user choices.
+    #[allow(clippy::too_many_arguments)]
+    pub fn update(
+        previous_id: String,
+        domain: Domain,
+        pref: CookieConsentPref,
+        policy_version: PolicyVersion,
+        geolocation: Geolocation,
+        anonymous_ip: Option<AnonymousIp>,
+        user_agent: String,
+        privacy_signals: PrivacySignals,
+    ) -> Self {
+        let CookieConsent { id, value } = Self::new(
+            domain,
+            pref,
+            policy_version,
+            geolocation,
+            anonymous_ip,
+            user_agent,
+            privacy_signals,
+        );
+
+        CookieConsent {
+            id,
+            value: CookieConsentValue { previous_id: Some(previous_id), ..value },
+        }
+    }
+
+    /// Creates a new consent that withdraws the consent with ID `withdrawn_id`, given under the
+    /// `policy_version`. The withdrawn consent is kept as is, so both records remain for
+    /// auditing.
+    pub fn withdrawal(
+        withdrawn_id: String,
+        policy_version: Option<PolicyVersion>,
+        domain: Domain,
+        geolocation: Geolocation,
+        anonymous_ip: Option<AnonymousIp>,
+        user_agent: String,
+        privacy_signals: PrivacySignals,
+    ) -> Self {
+        let CookieConsent { id, value } = Self::create(
+            domain,
+            CookieConsentPref::essential_only(),
+            policy_version,
+            geolocation,
+            anonymous_ip,
+            user_agent,
+            privacy_signals,
+        );
+
+        CookieConsent {
+            id,
+            value: CookieConsentValue { withdrawn_id: Some(withdrawn_id), ..value },
+        }
+    }
+
+    /// Returns the consent retained until the end of the consent retention period.
+    pub fn retained_for(self, retention: &RetentionConfig) -> Self {
+        let retained_until = retention.consent_expiration(self.value.created_at);
+
+        CookieConsent {
+            value: CookieConsentValue { retained_until: Some(retained_until), ..self.value },
+            ..self
+        }
+    }
+
+    /// Returns the consent without its personal data, that is, its anonymous IP, user agent,
+    /// and city, once their retention period is over.
+    pub fn redact_personal_data(self) -> Self {
+        let value = self.value;
+
+        CookieConsent {
+            value: CookieConsentValue {
+                geolocation: value.geolocation.without_city(),
+                anonymous_ip: None,
+                user_agent: String::new(),
+                personal_data_redacted: true,
+                ..value
+            },
+            ..self
+        }
+    }
+
+    pub fn domain(&self) -> &Domain {
+        self.value.domain()
+    }
+
+    pub fn from_kv(id: String, value: CookieConsentValue) -> Self {
+        CookieConsent { id, value }
+    }
+
     pub fn to_kv(&self) -> (String, CookieConsentValue) {
         (self.id.to_string(), self.value.clone())
     }
 
+    #[allow(dead_code)]
     pub fn to_json(&self) -> String {
         serde_json::to_string(self).unwrap()
     }
 }
 
-#[derive(PartialEq, Debug, Serialize, Deserialize)]
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
 pub struct ClientCookieConsent {
     id: String,
     pref: CookieConsentPref,
+    policy_version: Option<PolicyVersion>,
     created_at: DateTime<Utc>,
     geolocation: Geolocation,
+    privacy_signals: PrivacySignals,
+    pref_overridden: bool,
+    previous_id: Option<String>,
+    withdrawn_id: Option<String>,
+    #[serde(default)]
+    consent_mode: Option<ConsentModeSignals>,
+    #[serde(default)]
+    tc_string: Option<String>,
+    #[serde(default)]
+    expires_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    receipt: Option<String>,
 }
 
 impl ClientCookieConsent {
@@ -95,11 +468,60 @@ impl ClientCookieConsent {
         ClientCookieConsent {
             id: id.clone(),
             pref: value.pref,
+            policy_version: value.policy_version.clone(),
             created_at: value.created_at,
             geolocation: value.geolocation.clone(),
+            privacy_signals: value.privacy_signals,
+            pref_overridden: value.pref_overridden,
+            previous_id: value.previous_id.clone(),
+            withdrawn_id: value.withdrawn_id.clone(),
+            consent_mode: None,
+            tc_string: None,
+            expires_at: None,
+            receipt: None,
         }
     }
 
+    /// Returns this consent with the date it expires according to the lifetime of the
+    /// jurisdiction it was given from, so the sites know when to ask the user again.
+    pub fn with_expiration(self, lifetime: &LifetimeConfig) -> Self {
+        let expires_at = lifetime.expiration(self.created_at, &self.geolocation);
+
+        ClientCookieConsent { expires_at: Some(expires_at), ..self }
+    }
+
+    /// Returns this consent with its Google Consent Mode v2 signals, so the sites don't have
+    /// to map them from the preference.
+    pub fn with_consent_mode(self, mapping: &ConsentModeMapping) -> Self {
+        let consent_mode = ConsentModeSignals::from_pref(&self.pref, mapping);
+
+        ClientCookieConsent { consent_mode: Some(consent_mode), ..self }
+    }
+
+    /// Returns this consent with its IAB TCF v2.2 TC string, so advertising partners can read
+    /// the consent.
+    pub fn with_tc_string(self, config: &TcfConfig, vendor_list: &VendorList) -> Self {
+        let tc_string = TcString::from_consent(&self.pref, self.created_at, config, vendor_list);
+
+        ClientCookieConsent { tc_string: Some(tc_string.encode()), ..self }
+    }
+
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        self.expires_at
+    }
+
+    /// Returns this consent with its receipt signed for the `Domain` it was given to, so the
+    /// client can store it in a cookie that other services can verify without the store.
+    pub fn with_receipt(self, domain: &Domain, keys: &SigningKeys) -> Self {
+        let receipt = keys.sign(domain, &self);
+
+        ClientCookieConsent { receipt: Some(receipt), ..self }
+    }
+
+    pub fn without_receipt(self) -> Self {
+        ClientCookieConsent { receipt: None, ..self }
+    }
+
     pub fn to_json(&self) -> String {
         serde_json::to_string(self).unwrap()
     }
@@ -108,6 +530,9 @@ impl ClientCookieConsent {
 #[cfg(test)]
 mod tests {
     use std::net::Ipv4Addr;
+
+    use crate::policy::KnownPolicy;
+
     use super::*;
 
     #[test]
@@ -126,12 +551,13 @@ mod tests {
 
     #[test]
     fn cookie_consent_serialization() {
-        let consent = CookieConsent::new(MathSweCom, CookieConsentPref {
+        let consent = CookieConsent::new(Domain::new("mathswe.com"), CookieConsentPref {
             essential: true,
             functional: false,
             analytical: true,
             targeting: false,
-        }, dummy_geolocation(), dummy_ip(), dummy_user_agent());
+        }, dummy_policy_version(), dummy_geolocation(), dummy_ip(), dummy_user_agent(),
+            no_signals());
         let json = serde_json::to_string(&consent).unwrap();
         let deserialized_consent = serde_json::from_str::<CookieConsent>(&json).unwrap();
 
@@ -152,17 +578,24 @@ mod tests {
         let synthetic_consent = CookieConsent {
             id: String::from("abc"),
             value: CookieConsentValue {
-                domain: MathSweCom,
+                domain: Domain::new("mathswe.com"),
                 pref: CookieConsentPref {
                     essential: true,
                     functional: false,
                     analytical: true,
                     targeting: false,
                 },
+                policy_version: Some(dummy_policy_version()),
                 created_at: "2024-03-10 17:49:01.613437 UTC".parse().unwrap(),
                 geolocation: dummy_geolocation(),
                 anonymous_ip: dummy_ip(),
                 user_agent: dummy_user_agent(),
+                privacy_signals: no_signals(),
+                pref_overridden: false,
+                previous_id: None,
+                withdrawn_id: None,
+                retained_until: None,
+                personal_data_redacted: false,
             },
         };
         let json = serde_json::to_string(&synthetic_consent).unwrap();
@@ -180,21 +613,46 @@ mod tests {
         );
     }
 
+    #[test]
+    fn cookie_consent_kv_conversion() {
+        let consent = CookieConsent::new(Domain::new("math.software"), CookieConsentPref {
+            essential: true,
+            functional: true,
+            analytical: false,
+            targeting: false,
+        }, dummy_policy_version(), dummy_geolocation(), dummy_ip(), dummy_user_agent(),
+            no_signals());
+        let (id, value) = consent.to_kv();
+
+        assert_eq!(
+            consent,
+            CookieConsent::from_kv(id, value),
+            "consent is restored from its stored key and value"
+        );
+    }
+
     #[test]
     fn synthetic_cookie_consent_response() {
         let id = String::from("xyz123");
         let value = CookieConsentValue {
-            domain: MathSoftwareEngineer,
+            domain: Domain::new("mathsoftware.engineer"),
             pref: CookieConsentPref {
                 essential: true,
                 functional: false,
                 analytical: true,
                 targeting: true,
             },
+            policy_version: Some(dummy_policy_version()),
             created_at: "2024-04-09 17:49:01.613437 UTC".parse().unwrap(),
             geolocation: dummy_geolocation(),
             anonymous_ip: dummy_ip(),
             user_agent: dummy_user_agent(),
+            privacy_signals: no_signals(),
+            pref_overridden: false,
+            previous_id: None,
+            withdrawn_id: None,
+            retained_until: None,
+            personal_data_redacted: false,
         };
         let synthetic_consent = CookieConsent { id: id.clone(), value: value.clone() };
         let response = ClientCookieConsent::from(&synthetic_consent);
@@ -203,8 +661,17 @@ mod tests {
             ClientCookieConsent {
                 id,
                 pref: value.pref,
+                policy_version: value.policy_version,
                 created_at: value.created_at,
                 geolocation: value.geolocation,
+                privacy_signals: value.privacy_signals,
+                pref_overridden: false,
+                previous_id: None,
+                withdrawn_id: None,
+                consent_mode: None,
+                tc_string: None,
+                expires_at: None,
+                receipt: None,
             },
             response,
             "client consent response matches the underlying server consent"
@@ -225,8 +692,302 @@ mod tests {
         );
     }
 
-    fn dummy_ip() -> Option<AnonymousIpv4> {
-        Some(AnonymousIpv4::from_ipv4(Ipv4Addr::new(1, 1, 1, 1)))
+    #[test]
+    fn withdrawal_rejects_non_essential_cookies() {
+        let consent = CookieConsent::new(Domain::new("mathswe.com"), CookieConsentPref {
+            essential: true,
+            functional: true,
+            analytical: true,
+            targeting: true,
+        }, dummy_policy_version(), dummy_geolocation(), dummy_ip(), dummy_user_agent(),
+            no_signals());
+        let withdrawal = CookieConsent::withdrawal(
+            consent.id.clone(),
+            consent.value.policy_version.clone(),
+            Domain::new("mathswe.com"),
+            dummy_geolocation(),
+            dummy_ip(),
+            dummy_user_agent(),
+            no_signals(),
+        );
+
+        assert_ne!(consent.id, withdrawal.id, "withdrawal is stored as a new consent");
+        assert_eq!(Some(consent.id), withdrawal.value.withdrawn_id);
+        assert_eq!(
+            consent.value.policy_version,
+            withdrawal.value.policy_version,
+            "withdrawal keeps the policy version of the withdrawn consent"
+        );
+        assert_eq!(
+            CookieConsentPref {
+                essential: true,
+                functional: false,
+                analytical: false,
+                targeting: false,
+            },
+            withdrawal.value.pref,
+            "withdrawal only accepts essential cookies"
+        );
+    }
+
+    #[test]
+    fn deserializes_consent_without_withdrawal() {
+        let json = r#"{
+            "domain": "MathSweCom",
+            "pref": {
+                "essential": true,
+                "functional": false,
+                "analytical": true,
+                "targeting": false
+            },
+            "created_at": "2024-03-10T17:49:01.613437Z",
+            "geolocation": {
+                "time_zone": "America/Tegucigalpa",
+                "country": null,
+                "city": null,
+                "region": null,
+                "region_code": null
+            },
+            "anonymous_ip": "1.1.1.0",
+            "user_agent": "Mozilla/5.0"
+        }"#;
+        let value = serde_json::from_str::<CookieConsentValue>(json).unwrap();
+
+        assert_eq!(None, value.withdrawn_id, "stored consents have no withdrawal");
+        assert_eq!(None, value.previous_id, "stored consents have no previous consent");
+        assert_eq!(None, value.policy_version, "stored consents have no policy version");
+        assert_eq!(no_signals(), value.privacy_signals, "stored consents have no signals");
+        assert_eq!(
+            Domain::new("mathswe.com"),
+            value.domain,
+            "stored consents keep their domain enum name"
+        );
+    }
+
+    #[test]
+    fn update_chains_to_previous_consent() {
+        let consent = CookieConsent::new(Domain::new("math.software"), CookieConsentPref {
+            essential: true,
+            functional: true,
+            analytical: true,
+            targeting: true,
+        }, dummy_policy_version(), dummy_geolocation(), dummy_ip(), dummy_user_agent(),
+            no_signals());
+        let new_pref = CookieConsentPref {
+            essential: true,
+            functional: true,
+            analytical: false,
+            targeting: false,
+        };
+        let update = CookieConsent::update(
+            consent.id.clone(),
+            Domain::new("math.software"),
+            new_pref,
+            dummy_policy_version(),
+            dummy_geolocation(),
+            dummy_ip(),
+            dummy_user_agent(),
+            no_signals(),
+        );
+
+        assert_ne!(consent.id, update.id, "update is stored as a new consent");
+        assert_eq!(new_pref, update.value.pref);
+        assert_eq!(Some(&consent.id), update.value.predecessor_id());
+        assert_eq!(None, consent.value.predecessor_id(), "first consent has no predecessor");
+
+        let withdrawal = CookieConsent::withdrawal(
+            update.id.clone(),
+            update.value.policy_version.clone(),
+            Domain::new("math.software"),
+            dummy_geolocation(),
+            dummy_ip(),
+            dummy_user_agent(),
+            no_signals(),
+        );
+
+        assert_eq!(
+            Some(&update.id),
+            withdrawal.value.predecessor_id(),
+            "withdrawal chains to the withdrawn consent"
+        );
+    }
+
+    #[test]
+    fn gpc_signal_rejects_targeting_cookies() {
+        let all_accepted = CookieConsentPref {
+            essential: true,
+            functional: true,
+            analytical: true,
+            targeting: true,
+        };
+        let consent_with = |privacy_signals| CookieConsent::new(
+            Domain::new("mathswe.com"),
+            all_accepted,
+            dummy_policy_version(),
+            dummy_geolocation(),
+            dummy_ip(),
+            dummy_user_agent(),
+            privacy_signals,
+        );
+        let gpc_consent = consent_with(PrivacySignals::new(true, false));
+        let dnt_consent = consent_with(PrivacySignals::new(false, true));
+
+        assert_eq!(
+            CookieConsentPref { targeting: false, ..all_accepted },
+            gpc_consent.value.pref,
+            "GPC opts out of targeting cookies"
+        );
+        assert!(gpc_consent.value.pref_overridden, "consent tells its pref was overridden");
+        assert_eq!(all_accepted, dnt_consent.value.pref, "DNT is only recorded");
+        assert!(!dnt_consent.value.pref_overridden);
+        assert_eq!(
+            (CookieConsentPref::essential_only(), false),
+            CookieConsentPref::essential_only().honor(&PrivacySignals::new(true, true)),
+            "a preference without targeting cookies is not overridden"
+        );
+    }
+
+    #[test]
+    fn validates_consent_request_policy_version() {
+        let json = r#"{
+            "pref": {
+                "essential": true,
+                "functional": true,
+                "analytical": false,
+                "targeting": false
+            },
+            "policy_version": "2024-03-10"
+        }"#;
+        let req = CookieConsentReq::from_json(serde_json::from_str(json).unwrap()).unwrap();
+
+        assert_eq!(
+            Ok((
+                CookieConsentPref {
+                    essential: true,
+                    functional: true,
+                    analytical: false,
+                    targeting: false,
+                },
+                dummy_policy_version(),
+            )),
+            req.validate(&dummy_domain_config())
+        );
+
+        let unknown_req = CookieConsentReq {
+            pref: CookieConsentPref::essential_only(),
+            policy_version: "1970-01-01".to_string(),
+        };
+
+        assert_eq!(
+            Err(vec![
+                FieldError::new("/policy_version", "Unknown cookie policy version: 1970-01-01"),
+            ]),
+            unknown_req.validate(&dummy_domain_config())
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_consent_request_fields() {
+        let pref = r#""essential": true, "functional": true, "analytical": true"#;
+        let cases = [
+            (
+                format!(r#"{{ "pref": {{ {} }}, "policy_version": "2024-03-10" }}"#, pref),
+                vec![("/pref/targeting", "Missing field")],
+            ),
+            (
+                format!(r#"{{ "pref": {{ {}, "targeting": 1 }} }}"#, pref),
+                vec![
+                    ("/policy_version", "Missing field"),
+                    ("/pref/targeting", "Must be a boolean"),
+                ],
+            ),
+            (
+                format!(
+                    r#"{{ "pref": {{ {}, "targeting": true, "marketing": true }},
+                    "policy_version": "2024-03-10", "user/id": "alice" }}"#,
+                    pref
+                ),
+                vec![("/user~1id", "Unknown field"), ("/pref/marketing", "Unknown field")],
+            ),
+            (
+                r#"{ "pref": null, "policy_version": 20240310 }"#.to_string(),
+                vec![("/pref", "Must be an object"), ("/policy_version", "Must be a string")],
+            ),
+            ("[]".to_string(), vec![("", "Must be an object")]),
+        ];
+
+        cases
+            .iter()
+            .for_each(|(json, errors)| assert_eq!(
+                Err(
+                    errors
+                        .iter()
+                        .map(|(field, message)| FieldError::new(*field, *message))
+                        .collect::<Vec<_>>()
+                ),
+                CookieConsentReq::from_json(serde_json::from_str(json).unwrap()),
+                "{} is not a valid consent request",
+                json
+            ));
+        assert!(
+            serde_json::from_str::<CookieConsentPref>(
+                r#"{ "essential": true, "functional": true, "analytical": true,
+                "targeting": true, "marketing": true }"#
+            ).is_err(),
+            "the preference denies unknown fields when it's deserialized directly"
+        );
+    }
+
+    #[test]
+    fn rejects_refused_essential_cookies() {
+        let pref = CookieConsentPref { essential: false, ..CookieConsentPref::essential_only() };
+        let essential_error = FieldError::new(
+            "/pref/essential",
+            "Essential cookies can't be refused",
+        );
+        let cases = [
+            ("2024-03-10", vec![essential_error.clone()]),
+            (
+                "1970-01-01",
+                vec![
+                    essential_error.clone(),
+                    FieldError::new("/policy_version", "Unknown cookie policy version: 1970-01-01"),
+                ],
+            ),
+        ];
+
+        assert_eq!(Err(vec![essential_error.clone()]), pref.validate());
+        assert!(CookieConsentPref::essential_only().validate().is_ok());
+
+        cases
+            .iter()
+            .for_each(|(policy_version, errors)| assert_eq!(
+                Err(errors.clone()),
+                CookieConsentReq { pref, policy_version: policy_version.to_string() }
+                    .validate(&dummy_domain_config()),
+                "every invalid field of the request is reported"
+            ));
+    }
+
+    fn dummy_policy_version() -> PolicyVersion {
+        PolicyVersion::validate("2024-03-10".to_string(), &[KnownPolicy::active("2024-03-10")])
+            .unwrap()
+    }
+
+    fn dummy_domain_config() -> DomainConfig {
+        DomainConfig::new(
+            Domain::new("mathswe.com"),
+            None,
+            vec![KnownPolicy::active("2024-03-10")],
+        )
+    }
+
+    fn no_signals() -> PrivacySignals {
+        PrivacySignals::default()
+    }
+
+    fn dummy_ip() -> Option<AnonymousIp> {
+        Some(AnonymousIp::from_ipv4(Ipv4Addr::new(1, 1, 1, 1)))
     }
 
     fn dummy_geolocation() -> Geolocation {
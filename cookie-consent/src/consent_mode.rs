@@ -0,0 +1,173 @@
+// Copyright (c) 2024 Tobias Briones. All rights reserved.
+// This file is part of https://github.com/mathswe/legal
+
+use serde::{Deserialize, Serialize};
+
+use crate::consent::CookieConsentPref;
+use crate::consent_mode::CookieCategory::{Analytical, Essential, Functional, Targeting};
+
+/// Defines the cookie categories of a `CookieConsentPref`.
+#[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CookieCategory {
+    Essential,
+    Functional,
+    Analytical,
+    Targeting,
+}
+
+impl CookieCategory {
+    pub fn is_accepted(&self, pref: &CookieConsentPref) -> bool {
+        match self {
+            Essential => pref.essential(),
+            Functional => pref.functional(),
+            Analytical => pref.analytical(),
+            Targeting => pref.targeting(),
+        }
+    }
+}
+
+/// Defines the state of a Google Consent Mode signal.
+#[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConsentStatus {
+    Granted,
+    Denied,
+}
+
+/// Defines the cookie categories each Google Consent Mode v2 signal requires, so a signal is
+/// granted only if the user accepted all its categories. By default, `security_storage`
+/// requires essential cookies, `functionality_storage` functional cookies,
+/// `analytics_storage` analytical cookies, and the ad signals require targeting cookies.
+#[derive(PartialEq, Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConsentModeMapping {
+    ad_storage: Vec<CookieCategory>,
+    analytics_storage: Vec<CookieCategory>,
+    ad_user_data: Vec<CookieCategory>,
+    ad_personalization: Vec<CookieCategory>,
+    functionality_storage: Vec<CookieCategory>,
+    security_storage: Vec<CookieCategory>,
+}
+
+impl Default for ConsentModeMapping {
+    fn default() -> Self {
+        ConsentModeMapping {
+            ad_storage: vec![Targeting],
+            analytics_storage: vec![Analytical],
+            ad_user_data: vec![Targeting],
+            ad_personalization: vec![Targeting],
+            functionality_storage: vec![Functional],
+            security_storage: vec![Essential],
+        }
+    }
+}
+
+/// Defines the Google Consent Mode v2 signals of a consent, which the sites pass to their
+/// Google tags as is.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct ConsentModeSignals {
+    ad_storage: ConsentStatus,
+    analytics_storage: ConsentStatus,
+    ad_user_data: ConsentStatus,
+    ad_personalization: ConsentStatus,
+    functionality_storage: ConsentStatus,
+    security_storage: ConsentStatus,
+}
+
+impl ConsentModeSignals {
+    pub fn from_pref(pref: &CookieConsentPref, mapping: &ConsentModeMapping) -> Self {
+        let status = |categories: &Vec<CookieCategory>| {
+            if categories.iter().all(|category| category.is_accepted(pref)) {
+                ConsentStatus::Granted
+            } else {
+                ConsentStatus::Denied
+            }
+        };
+
+        ConsentModeSignals {
+            ad_storage: status(&mapping.ad_storage),
+            analytics_storage: status(&mapping.analytics_storage),
+            ad_user_data: status(&mapping.ad_user_data),
+            ad_personalization: status(&mapping.ad_personalization),
+            functionality_storage: status(&mapping.functionality_storage),
+            security_storage: status(&mapping.security_storage),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::consent::CookieConsentPref;
+    use crate::consent_mode::{ConsentModeMapping, ConsentModeSignals};
+    use crate::consent_mode::ConsentStatus::{Denied, Granted};
+
+    #[test]
+    fn maps_pref_to_signals() {
+        let pref_cases = [
+            (
+                CookieConsentPref::essential_only(),
+                [Denied, Denied, Denied, Denied, Denied, Granted],
+            ),
+            (
+                pref(true, false, true, false),
+                [Denied, Granted, Denied, Denied, Denied, Granted],
+            ),
+            (
+                pref(true, true, false, true),
+                [Granted, Denied, Granted, Granted, Granted, Granted],
+            ),
+            (
+                pref(true, true, true, true),
+                [Granted, Granted, Granted, Granted, Granted, Granted],
+            ),
+        ];
+
+        pref_cases
+            .iter()
+            .for_each(|(pref, [ad, analytics, user_data, personal, functional, security])| {
+                assert_eq!(
+                    ConsentModeSignals {
+                        ad_storage: *ad,
+                        analytics_storage: *analytics,
+                        ad_user_data: *user_data,
+                        ad_personalization: *personal,
+                        functionality_storage: *functional,
+                        security_storage: *security,
+                    },
+                    ConsentModeSignals::from_pref(pref, &ConsentModeMapping::default()),
+                    "{:?} maps to the default signals",
+                    pref
+                )
+            })
+    }
+
+    #[test]
+    fn maps_pref_with_configured_mapping() {
+        let mapping = serde_json::from_str::<ConsentModeMapping>(r#"{
+            "ad_storage": ["targeting", "analytical"],
+            "functionality_storage": []
+        }"#).unwrap();
+        let signals = ConsentModeSignals::from_pref(&pref(true, false, false, true), &mapping);
+        let json = serde_json::to_value(&signals).unwrap();
+
+        assert_eq!("denied", json["ad_storage"], "ad storage requires both categories");
+        assert_eq!("granted", json["ad_user_data"], "missing signals keep their default");
+        assert_eq!("granted", json["functionality_storage"], "no category is always granted");
+        assert_eq!("denied", json["analytics_storage"]);
+    }
+
+    fn pref(
+        essential: bool,
+        functional: bool,
+        analytical: bool,
+        targeting: bool,
+    ) -> CookieConsentPref {
+        serde_json::from_value(serde_json::json!({
+            "essential": essential,
+            "functional": functional,
+            "analytical": analytical,
+            "targeting": targeting
+        })).unwrap()
+    }
+}
@@ -0,0 +1,387 @@
+// Copyright (c) 2024 Tobias Briones. All rights reserved.
+// This file is part of https://github.com/mathswe/legal
+
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::config::DomainConfig;
+use crate::consent::CookieConsent;
+use crate::consent_mode::CookieCategory;
+use crate::consent_mode::CookieCategory::{Analytical, Essential, Functional, Targeting};
+use crate::geolocation::Geolocation;
+use crate::policy::PolicyVersion;
+
+const RECEIPT_VERSION: &str = "KI-CR-v1.1.0";
+
+const COLLECTION_METHOD: &str = "Cookie consent banner";
+
+const RECEIPT_LANGUAGE: &str = "en";
+
+/// Jurisdiction of the consents whose `Geolocation` has no country.
+const UNKNOWN_JURISDICTION: &str = "unknown";
+
+const PII_CATEGORIES: [&str; 1] = ["Online identifiers"];
+
+const TERMINATION: &str = "Until the cookies expire or the user withdraws their consent";
+
+/// Defines the data controller of a `Domain` stated in its consent receipts, and the URL of
+/// its cookie policy.
+#[derive(PartialEq, Clone, Debug, Deserialize)]
+pub struct ControllerConfig {
+    name: String,
+    contact: String,
+    address: String,
+    email: String,
+    phone: String,
+    url: String,
+    policy_url: String,
+}
+
+impl ControllerConfig {
+    /// Returns the controller assumed for a `Domain` without controller settings, that is,
+    /// the domain itself with its site as the policy URL.
+    fn from_domain(config: &DomainConfig) -> Self {
+        let site = format!("https://{}", config.domain());
+
+        ControllerConfig {
+            name: config.domain().to_string(),
+            contact: String::new(),
+            address: String::new(),
+            email: String::new(),
+            phone: String::new(),
+            url: site.clone(),
+            policy_url: site,
+        }
+    }
+
+    /// Validates that the controller has a name and its URLs are absolute `https` URLs.
+    pub fn validate(&self) -> Result<(), String> {
+        let is_https = |url: &str| Url::parse(url).is_ok_and(|url| url.scheme() == "https");
+
+        if self.name.trim().is_empty() {
+            Err("a controller without name".to_string())
+        } else if !is_https(&self.url) || !is_https(&self.policy_url) {
+            Err("a controller with an invalid URL".to_string())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Defines the consent receipt of a `CookieConsent` in the Kantara Initiative Consent Receipt
+/// v1.1 format, which the ISO/IEC TS 27560 consent record structure is based on, so
+/// auditors and users get the consent evidence in a standard format. The `policyVersion` is
+/// an extension field with the cookie policy version the user saw.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConsentReceipt {
+    version: String,
+    jurisdiction: String,
+    consent_timestamp: i64,
+    collection_method: String,
+    #[serde(rename = "consentReceiptID")]
+    consent_receipt_id: String,
+    language: String,
+    pii_principal_id: String,
+    pii_controllers: Vec<PiiController>,
+    policy_url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    policy_version: Option<PolicyVersion>,
+    services: Vec<ReceiptService>,
+    sensitive: bool,
+    spi_cat: Vec<String>,
+}
+
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PiiController {
+    pii_controller: String,
+    on_behalf: bool,
+    contact: String,
+    address: String,
+    email: String,
+    phone: String,
+    pii_controller_url: String,
+}
+
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct ReceiptService {
+    service: String,
+    purposes: Vec<ReceiptPurpose>,
+}
+
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReceiptPurpose {
+    purpose: String,
+    purpose_category: Vec<String>,
+    consent_type: String,
+    pii_category: Vec<String>,
+    primary_purpose: bool,
+    termination: String,
+    third_party_disclosure: bool,
+}
+
+impl ReceiptPurpose {
+    fn from_category(category: CookieCategory) -> Self {
+        let (purpose, purpose_category, consent_type) = match category {
+            Essential => ("Essential cookies for the site to work", "Core Function", "IMPLICIT"),
+            Functional => (
+                "Functional cookies that remember the user choices",
+                "Personalized Experience",
+                "EXPLICIT",
+            ),
+            Analytical => (
+                "Analytical cookies that measure how the site is used",
+                "Improve Performance",
+                "EXPLICIT",
+            ),
+            Targeting => (
+                "Targeting cookies that personalize ads with advertising partners",
+                "Marketing Third Parties",
+                "EXPLICIT",
+            ),
+        };
+
+        ReceiptPurpose {
+            purpose: purpose.to_string(),
+            purpose_category: vec![purpose_category.to_string()],
+            consent_type: consent_type.to_string(),
+            pii_category: PII_CATEGORIES.iter().map(|category| category.to_string()).collect(),
+            primary_purpose: category == Essential,
+            termination: TERMINATION.to_string(),
+            third_party_disclosure: category == Targeting,
+        }
+    }
+}
+
+impl ConsentReceipt {
+    /// Returns the receipt of the consent, whose purposes are the cookie categories the user
+    /// accepted. The consent ID is both the receipt ID and the principal ID, since the user
+    /// is only identified by the consent ID stored in their cookies.
+    pub fn from_consent(consent: &CookieConsent, config: &DomainConfig) -> Self {
+        let (id, value) = consent.to_kv();
+        let controller = config
+            .controller()
+            .cloned()
+            .unwrap_or(ControllerConfig::from_domain(config));
+        let purposes = [Essential, Functional, Analytical, Targeting]
+            .into_iter()
+            .filter(|category| category.is_accepted(value.pref()))
+            .map(ReceiptPurpose::from_category)
+            .collect();
+
+        ConsentReceipt {
+            version: RECEIPT_VERSION.to_string(),
+            jurisdiction: jurisdiction(value.geolocation()),
+            consent_timestamp: value.created_at().timestamp(),
+            collection_method: COLLECTION_METHOD.to_string(),
+            consent_receipt_id: id.clone(),
+            language: RECEIPT_LANGUAGE.to_string(),
+            pii_principal_id: id,
+            pii_controllers: vec![PiiController {
+                pii_controller: controller.name,
+                on_behalf: false,
+                contact: controller.contact,
+                address: controller.address,
+                email: controller.email,
+                phone: controller.phone,
+                pii_controller_url: controller.url,
+            }],
+            policy_url: controller.policy_url,
+            policy_version: value.policy_version().cloned(),
+            services: vec![ReceiptService {
+                service: format!("Cookies of {}", value.domain()),
+                purposes,
+            }],
+            sensitive: false,
+            spi_cat: vec![],
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+/// Returns the ISO 3166 code of the consent location, that is, its country followed by its
+/// region code if present, like `US-CA`.
+fn jurisdiction(geolocation: &Geolocation) -> String {
+    match (geolocation.country(), geolocation.region_code()) {
+        (Some(country), Some(region_code)) => format!("{}-{}", country, region_code),
+        (Some(country), None) => country.clone(),
+        (None, _) => UNKNOWN_JURISDICTION.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonschema::JSONSchema;
+    use serde_json::Value;
+
+    use crate::config::DomainsConfig;
+    use crate::consent::{CookieConsent, CookieConsentPref, Domain};
+    use crate::consent_receipt::{ConsentReceipt, jurisdiction};
+    use crate::geolocation::Geolocation;
+    use crate::policy::{KnownPolicy, PolicyVersion};
+    use crate::privacy_signal::PrivacySignals;
+
+    const RECEIPT_SCHEMA: &str = include_str!("../kantara/consent-receipt-v1.1.schema.json");
+
+    const CONFIG_JSON: &str = r#"[
+        {
+            "domain": "mathswe.com",
+            "policies": [{ "version": "2024-03-10", "status": "Active" }],
+            "controller": {
+                "name": "MathSwe",
+                "contact": "Privacy Team",
+                "address": "Tegucigalpa, Honduras",
+                "email": "privacy@mathswe.com",
+                "phone": "+504 0000-0000",
+                "url": "https://mathswe.com",
+                "policy_url": "https://mathswe.com/legal/cookie-policy"
+            }
+        },
+        {
+            "domain": "math.software",
+            "policies": [{ "version": "2024-03-10", "status": "Active" }]
+        }
+    ]"#;
+
+    #[test]
+    fn conforms_to_kantara_schema() {
+        let schema = JSONSchema::compile(&serde_json::from_str(RECEIPT_SCHEMA).unwrap()).unwrap();
+        let config = DomainsConfig::from_json(CONFIG_JSON).unwrap();
+        let prefs = [CookieConsentPref::essential_only(), all_accepted_pref()];
+
+        config
+            .iter()
+            .flat_map(|domain_config| prefs.iter().map(move |pref| (domain_config, pref)))
+            .for_each(|(domain_config, pref)| {
+                let consent = dummy_consent(domain_config.domain().clone(), *pref);
+                let receipt = ConsentReceipt::from_consent(&consent, domain_config);
+                let json = serde_json::to_value(&receipt).unwrap();
+                let errors = schema
+                    .validate(&json)
+                    .err()
+                    .map(|errors| errors.map(|e| e.to_string()).collect::<Vec<_>>());
+
+                assert_eq!(None, errors, "receipt {} conforms to the schema", json)
+            })
+    }
+
+    #[test]
+    fn maps_accepted_categories_to_purposes() {
+        let config = DomainsConfig::from_json(CONFIG_JSON).unwrap();
+        let domain_config = config.default_domain();
+        let pref_cases = [
+            (CookieConsentPref::essential_only(), vec!["Core Function"]),
+            (
+                all_accepted_pref(),
+                vec![
+                    "Core Function",
+                    "Personalized Experience",
+                    "Improve Performance",
+                    "Marketing Third Parties",
+                ],
+            ),
+        ];
+
+        pref_cases
+            .iter()
+            .for_each(|(pref, categories)| {
+                let consent = dummy_consent(Domain::new("mathswe.com"), *pref);
+                let json = serde_json::to_value(
+                    ConsentReceipt::from_consent(&consent, domain_config)
+                ).unwrap();
+                let purposes = json["services"][0]["purposes"].as_array().unwrap();
+                let purpose_categories = purposes
+                    .iter()
+                    .map(|purpose| purpose["purposeCategory"][0].as_str().unwrap())
+                    .collect::<Vec<_>>();
+                let third_party = purposes
+                    .iter()
+                    .filter(|purpose| purpose["thirdPartyDisclosure"] == Value::Bool(true))
+                    .count();
+
+                assert_eq!(*categories, purpose_categories, "{:?} purposes", pref);
+                assert_eq!(
+                    usize::from(pref.targeting()),
+                    third_party,
+                    "only targeting cookies are disclosed to third parties"
+                );
+                assert_eq!("MathSwe", json["piiControllers"][0]["piiController"]);
+                assert_eq!("https://mathswe.com/legal/cookie-policy", json["policyUrl"]);
+                assert_eq!("2024-03-10", json["policyVersion"]);
+                assert_eq!(json["consentReceiptID"], json["piiPrincipalId"]);
+            })
+    }
+
+    #[test]
+    fn falls_back_to_domain_controller() {
+        let config = DomainsConfig::from_json(CONFIG_JSON).unwrap();
+        let domain = Domain::new("math.software");
+        let consent = dummy_consent(domain.clone(), CookieConsentPref::essential_only());
+        let json = serde_json::to_value(
+            ConsentReceipt::from_consent(&consent, config.get(&domain).unwrap())
+        ).unwrap();
+
+        assert_eq!("math.software", json["piiControllers"][0]["piiController"]);
+        assert_eq!("https://math.software", json["piiControllers"][0]["piiControllerUrl"]);
+        assert_eq!("https://math.software", json["policyUrl"]);
+    }
+
+    #[test]
+    fn reads_jurisdiction_from_geolocation() {
+        let geolocation = |country: Option<&str>, region_code: Option<&str>| Geolocation::new(
+            chrono_tz::Tz::UTC,
+            country.map(str::to_string),
+            None,
+            None,
+            region_code.map(str::to_string),
+        );
+        let geolocation_cases = [
+            (geolocation(Some("US"), Some("CA")), "US-CA"),
+            (geolocation(Some("DE"), None), "DE"),
+            (geolocation(None, Some("CA")), "unknown"),
+            (Geolocation::from_cf(None), "unknown"),
+        ];
+
+        geolocation_cases
+            .iter()
+            .for_each(|(geolocation, expected)| assert_eq!(
+                *expected,
+                jurisdiction(geolocation),
+                "{:?} jurisdiction",
+                geolocation
+            ))
+    }
+
+    fn dummy_consent(domain: Domain, pref: CookieConsentPref) -> CookieConsent {
+        CookieConsent::new(
+            domain,
+            pref,
+            PolicyVersion::validate("2024-03-10".to_string(), &[KnownPolicy::active("2024-03-10")])
+                .unwrap(),
+            Geolocation::new(
+                chrono_tz::Tz::Europe__Berlin,
+                Some("DE".to_string()),
+                Some("Berlin".to_string()),
+                Some("Land Berlin".to_string()),
+                Some("BE".to_string()),
+            ),
+            None,
+            "Mozilla/5.0".to_string(),
+            PrivacySignals::default(),
+        )
+    }
+
+    fn all_accepted_pref() -> CookieConsentPref {
+        serde_json::from_str(r#"{
+            "essential": true,
+            "functional": true,
+            "analytical": true,
+            "targeting": true
+        }"#).unwrap()
+    }
+}
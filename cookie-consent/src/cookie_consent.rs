@@ -1,83 +1,1053 @@
 // Copyright (c) 2024 Tobias Briones. All rights reserved.
 // This file is part of https://github.com/mathswe/legal
 
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
 use std::str::FromStr;
 
-use worker::{Error, Request, Response, RouteContext};
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use worker::{Env, Error, Request, Response, RouteContext};
 
-use crate::anonymous_ip::AnonymousIpv4;
-    use crate::consent::{ClientCookieConsent, CookieConsent, CookieConsentPref, Domain};
+use crate::anonymous_ip::AnonymousIp;
+use crate::api_error::{ApiError, FieldError};
+use crate::config::DomainConfig;
+use crate::consent::{
+    ClientCookieConsent,
+    CookieConsent,
+    CookieConsentPref,
+    CookieConsentReq,
+    Domain,
+};
+use crate::consent_receipt::ConsentReceipt;
 use crate::geolocation::Geolocation;
-use crate::server::{forbidden, internal_error, OriginProxy};
+use crate::lifetime::ConsentStatus;
+use crate::log::RequestLog;
+use crate::policy::PolicyVersion;
+use crate::privacy_signal::PrivacySignals;
+use crate::rate_limit::{check_rate_limit, RateClient, RateCounter, RateLimit};
+use crate::receipt::{SigningKeys, VerificationReq};
+use crate::server::{ipv6_prefix_length, OriginProxy, ServiceData};
+use crate::stats::{count_consent, StatsCounter};
+use crate::store::{ConsentStore, StoreError};
+use crate::tcf::VendorList;
 
-pub async fn post_consent(
+/// Maximum number of consents walked when reading the history of a consent.
+const MAX_HISTORY_LENGTH: usize = 100;
+
+/// Defines the reasons a consent operation can fail.
+#[derive(PartialEq, Debug)]
+pub enum ConsentError {
+    NotFound,
+    Store(StoreError),
+}
+
+impl From<StoreError> for ConsentError {
+    fn from(error: StoreError) -> Self {
+        ConsentError::Store(error)
+    }
+}
+
+impl ConsentError {
+    /// Returns the `ApiError` of this error, whose store failure is logged as the given event.
+    pub fn to_api_error(self, event: &'static str) -> ApiError {
+        match self {
+            ConsentError::NotFound => ApiError::NotFound,
+            ConsentError::Store(e) => ApiError::storage(event, e),
+        }
+    }
+}
+
+/// Registers the consent of the request, unless its client exceeded the rate limit of the
+/// `Domain`. If the rate limit can't be checked, the consent is registered anyway, so users
+/// can still give their consent.
+pub async fn post_consent<S: ConsentStore + RateCounter + StatsCounter + 'static>(
     mut req: Request,
-    ctx: RouteContext<()>,
+    ctx: RouteContext<ServiceData<S>>,
 ) -> Result<Response, Error> {
+    let log = RequestLog::from_req(&req);
     let origin_option = OriginProxy::from_req(&req, &ctx)?;
 
     if origin_option.is_none() {
-        return forbidden();
+        return ApiError::ForbiddenOrigin.to_response(&log);
     }
 
     let origin = origin_option.unwrap();
-    let domain = origin.clone().domain();
-    let json = req.json::<CookieConsentPref>().await;
+    let log = log.with_domain(origin.config().domain());
+    let keys = match signing_keys(&ctx.env) {
+        Ok(keys) => keys,
+        Err(e) => return e.to_response(&log).and_then(|res| origin.handle_cors(res)),
+    };
+    let ip = anonymous_ip(&req, &ctx);
+    let user_agent = user_agent(&req);
+    let client = RateClient::new(origin.clone().domain(), ip.clone(), user_agent.clone());
+    let rate_limit = origin.config().rate_limit();
+
+    match check_rate_limit(ctx.data.store(), &client, rate_limit, Utc::now()).await {
+        Ok(RateLimit::Allowed) => {}
+        Ok(RateLimit::Limited { retry_after }) => {
+            return ApiError::RateLimited { retry_after }
+                .to_response(&log)
+                .and_then(|res| origin.handle_cors(res));
+        }
+        Err(e) => log.warn("rate_limit_check_failed", e),
+    }
+
+    let json = req.json::<Value>().await;
     let geolocation = Geolocation::from_req(&req);
-    let ip = req
-        .headers()
-        .get("cf-connecting-ip")
-        .unwrap_or(None)
-        .map(|raw_ip| Ipv4Addr::from_str(&raw_ip))
-        .and_then(Result::ok)
-        .map(AnonymousIpv4::from_ipv4);
+    let privacy_signals = PrivacySignals::from_req(&req);
 
-    let user_agent = req
-        .headers()
-        .get("user-agent")
-        .unwrap_or(None)
-        .unwrap_or("".to_string());
+    match json.map(|body| read_consent_req(body, origin.config())) {
+        Ok(Ok((pref, policy_version))) => consent_response(
+            register_consent(
+                ctx.data.store(),
+                ctx.data.store(),
+                &log,
+                origin.config(),
+                pref,
+                policy_version,
+                geolocation,
+                ip,
+                user_agent,
+                privacy_signals,
+            ).await,
+            origin.config(),
+            keys.as_ref(),
+            &log,
+            "consent_store_failed",
+        ),
+        Ok(Err(errors)) => ApiError::Validation(errors).to_response(&log),
+        Err(e) => ApiError::BadBody(e.to_string()).to_response(&log),
+    }.and_then(|res| origin.handle_cors(res))
+}
 
-    match json {
-        Ok(pref) => register_consent(
-            ctx,
-            domain,
-            pref,
+pub async fn get_consent<S: ConsentStore + 'static>(
+    req: Request,
+    ctx: RouteContext<ServiceData<S>>,
+) -> Result<Response, Error> {
+    let log = RequestLog::from_req(&req);
+    let origin_option = OriginProxy::from_req(&req, &ctx)?;
+
+    if origin_option.is_none() {
+        return ApiError::ForbiddenOrigin.to_response(&log);
+    }
+
+    let origin = origin_option.unwrap();
+    let log = log.with_domain(origin.config().domain());
+    let keys = match signing_keys(&ctx.env) {
+        Ok(keys) => keys,
+        Err(e) => return e.to_response(&log).and_then(|res| origin.handle_cors(res)),
+    };
+    let id = ctx.param("id").cloned().unwrap_or_default();
+
+    consent_response(
+        find_consent(ctx.data.store(), &id, origin.config()).await,
+        origin.config(),
+        keys.as_ref(),
+        &log,
+        "consent_read_failed",
+    ).and_then(|res| origin.handle_cors(res))
+}
+
+pub async fn post_withdrawal<S: ConsentStore + 'static>(
+    req: Request,
+    ctx: RouteContext<ServiceData<S>>,
+) -> Result<Response, Error> {
+    let log = RequestLog::from_req(&req);
+    let origin_option = OriginProxy::from_req(&req, &ctx)?;
+
+    if origin_option.is_none() {
+        return ApiError::ForbiddenOrigin.to_response(&log);
+    }
+
+    let origin = origin_option.unwrap();
+    let log = log.with_domain(origin.config().domain());
+    let keys = match signing_keys(&ctx.env) {
+        Ok(keys) => keys,
+        Err(e) => return e.to_response(&log).and_then(|res| origin.handle_cors(res)),
+    };
+    let id = ctx.param("id").cloned().unwrap_or_default();
+    let geolocation = Geolocation::from_req(&req);
+    let ip = anonymous_ip(&req, &ctx);
+    let user_agent = user_agent(&req);
+    let privacy_signals = PrivacySignals::from_req(&req);
+
+    consent_response(
+        withdraw_consent(
+            ctx.data.store(),
+            id,
+            origin.config(),
             geolocation,
             ip,
             user_agent,
+            privacy_signals,
         ).await,
-        Err(e) => Response::error(format!("Invalid JSON body: {}", e), 400),
+        origin.config(),
+        keys.as_ref(),
+        &log,
+        "consent_withdrawal_store_failed",
+    ).and_then(|res| origin.handle_cors(res))
+}
+
+pub async fn post_update<S: ConsentStore + 'static>(
+    mut req: Request,
+    ctx: RouteContext<ServiceData<S>>,
+) -> Result<Response, Error> {
+    let log = RequestLog::from_req(&req);
+    let origin_option = OriginProxy::from_req(&req, &ctx)?;
+
+    if origin_option.is_none() {
+        return ApiError::ForbiddenOrigin.to_response(&log);
+    }
+
+    let origin = origin_option.unwrap();
+    let log = log.with_domain(origin.config().domain());
+    let keys = match signing_keys(&ctx.env) {
+        Ok(keys) => keys,
+        Err(e) => return e.to_response(&log).and_then(|res| origin.handle_cors(res)),
+    };
+    let id = ctx.param("id").cloned().unwrap_or_default();
+    let json = req.json::<Value>().await;
+    let geolocation = Geolocation::from_req(&req);
+    let ip = anonymous_ip(&req, &ctx);
+    let user_agent = user_agent(&req);
+    let privacy_signals = PrivacySignals::from_req(&req);
+
+    match json.map(|body| read_consent_req(body, origin.config())) {
+        Ok(Ok((pref, policy_version))) => consent_response(
+            update_consent(
+                ctx.data.store(),
+                id,
+                origin.config(),
+                pref,
+                policy_version,
+                geolocation,
+                ip,
+                user_agent,
+                privacy_signals,
+            ).await,
+            origin.config(),
+            keys.as_ref(),
+            &log,
+            "consent_update_store_failed",
+        ),
+        Ok(Err(errors)) => ApiError::Validation(errors).to_response(&log),
+        Err(e) => ApiError::BadBody(e.to_string()).to_response(&log),
     }.and_then(|res| origin.handle_cors(res))
 }
 
-async fn register_consent(
-    ctx: RouteContext<()>,
-    domain: Domain,
+pub async fn get_history<S: ConsentStore + 'static>(
+    req: Request,
+    ctx: RouteContext<ServiceData<S>>,
+) -> Result<Response, Error> {
+    let log = RequestLog::from_req(&req);
+    let origin_option = OriginProxy::from_req(&req, &ctx)?;
+
+    if origin_option.is_none() {
+        return ApiError::ForbiddenOrigin.to_response(&log);
+    }
+
+    let origin = origin_option.unwrap();
+    let log = log.with_domain(origin.config().domain());
+    let id = ctx.param("id").cloned().unwrap_or_default();
+
+    match find_history(ctx.data.store(), id, origin.config()).await {
+        Ok(history) => Response::from_json(
+            &history
+                .into_iter()
+                .map(|consent| with_domain_signals(consent, origin.config()))
+                .collect::<Vec<_>>()
+        ),
+        Err(e) => e.to_api_error("consent_history_read_failed").to_response(&log),
+    }.and_then(|res| origin.handle_cors(res))
+}
+
+pub async fn get_consent_receipt<S: ConsentStore + 'static>(
+    req: Request,
+    ctx: RouteContext<ServiceData<S>>,
+) -> Result<Response, Error> {
+    let log = RequestLog::from_req(&req);
+    let origin_option = OriginProxy::from_req(&req, &ctx)?;
+
+    if origin_option.is_none() {
+        return ApiError::ForbiddenOrigin.to_response(&log);
+    }
+
+    let origin = origin_option.unwrap();
+    let log = log.with_domain(origin.config().domain());
+    let id = ctx.param("id").cloned().unwrap_or_default();
+
+    match find_consent_receipt(ctx.data.store(), &id, origin.config()).await {
+        Ok(receipt) => Response::ok(receipt.to_json()),
+        Err(e) => e.to_api_error("consent_receipt_read_failed").to_response(&log),
+    }.and_then(|res| origin.handle_cors(res))
+}
+
+/// Tells whether the consent with the given ID is still valid for the requesting `Domain`, or
+/// the client has to ask the user again.
+pub async fn get_consent_status<S: ConsentStore + 'static>(
+    req: Request,
+    ctx: RouteContext<ServiceData<S>>,
+) -> Result<Response, Error> {
+    let log = RequestLog::from_req(&req);
+    let origin_option = OriginProxy::from_req(&req, &ctx)?;
+
+    if origin_option.is_none() {
+        return ApiError::ForbiddenOrigin.to_response(&log);
+    }
+
+    let origin = origin_option.unwrap();
+    let log = log.with_domain(origin.config().domain());
+    let id = ctx.param("id").cloned().unwrap_or_default();
+
+    match find_consent_status(ctx.data.store(), &id, origin.config(), Utc::now()).await {
+        Ok(status) => Response::ok(status.to_json()),
+        Err(e) => e.to_api_error("consent_status_read_failed").to_response(&log),
+    }.and_then(|res| origin.handle_cors(res))
+}
+
+/// Verifies that a consent receipt was signed by this service for the `Domain` of the request
+/// and hasn't expired. It doesn't read the store, so it doesn't tell whether the consent was
+/// withdrawn or updated since. Requests without an `Origin` are allowed since they come from
+/// servers, which can give the `domain` in the body.
+pub async fn post_verification<S: ConsentStore + 'static>(
+    mut req: Request,
+    ctx: RouteContext<ServiceData<S>>,
+) -> Result<Response, Error> {
+    let log = RequestLog::from_req(&req);
+    let origin_option = OriginProxy::from_req(&req, &ctx)?;
+    let keys = signing_keys(&ctx.env);
+    let json = req.json::<VerificationReq>().await;
+
+    match (keys, json) {
+        (Ok(Some(keys)), Ok(body)) => match keys.verify(
+            &body.receipt,
+            origin_option.as_ref().map(|origin| origin.config().domain()).or(body.domain.as_ref()),
+            Utc::now(),
+        ) {
+            Ok(claims) => Response::ok(claims.to_json()),
+            Err(e) => ApiError::Validation(vec![FieldError::new("/receipt", e.to_string())])
+                .to_response(&log),
+        },
+        (Ok(None), _) => ApiError::internal(
+            "signing_keys_missing",
+            "Consent signing keys are not configured",
+        ).to_response(&log),
+        (Err(e), _) => e.to_response(&log),
+        (_, Err(e)) => ApiError::BadBody(e.to_string()).to_response(&log),
+    }.and_then(|res| match origin_option {
+        Some(origin) => origin.handle_cors(res),
+        None => Ok(res),
+    })
+}
+
+/// Registers a consent and adds it to the statistics of its `Domain`. The consent is already
+/// stored when it's counted, so failing to count it is logged without failing its
+/// registration.
+#[allow(clippy::too_many_arguments)]
+pub async fn register_consent(
+    store: &impl ConsentStore,
+    counter: &impl StatsCounter,
+    log: &RequestLog,
+    config: &DomainConfig,
     pref: CookieConsentPref,
+    policy_version: PolicyVersion,
     geolocation: Geolocation,
-    anonymous_ip: Option<AnonymousIpv4>,
+    anonymous_ip: Option<AnonymousIp>,
     user_agent: String,
-) -> Result<Response, Error> {
-    let cookie_consent_kv = "COOKIE_CONSENT";
+    privacy_signals: PrivacySignals,
+) -> Result<ClientCookieConsent, ConsentError> {
     let consent = CookieConsent::new(
-        domain,
+        config.domain().clone(),
         pref,
+        policy_version,
+        geolocation,
+        anonymous_ip,
+        user_agent,
+        privacy_signals,
+    ).retained_for(config.retention());
+
+    store.put(&consent).await?;
+    if let Err(e) = count_consent(counter, &consent).await {
+        log.warn("consent_count_failed", e);
+    }
+
+    Ok(ClientCookieConsent::from(&consent))
+}
+
+/// Returns the consent with the given ID. A consent given to another `Domain` is not found, so
+/// a site can't read the consents, nor get the receipts, of another site.
+pub async fn find_consent(
+    store: &impl ConsentStore,
+    id: &str,
+    config: &DomainConfig,
+) -> Result<ClientCookieConsent, ConsentError> {
+    store
+        .get(id)
+        .await?
+        .filter(|consent| consent.domain() == config.domain())
+        .map(|consent| ClientCookieConsent::from(&consent))
+        .ok_or(ConsentError::NotFound)
+}
+
+/// Returns the Kantara consent receipt of the consent with the given ID, stating the
+/// controller of the given `Domain` configuration. A consent given to another `Domain` is not
+/// found, so a site can't issue receipts of consents it didn't collect.
+pub async fn find_consent_receipt(
+    store: &impl ConsentStore,
+    id: &str,
+    config: &DomainConfig,
+) -> Result<ConsentReceipt, ConsentError> {
+    store
+        .get(id)
+        .await?
+        .filter(|consent| consent.domain() == config.domain())
+        .map(|consent| ConsentReceipt::from_consent(&consent, config))
+        .ok_or(ConsentError::NotFound)
+}
+
+/// Returns the status of the consent with the given ID for the given `Domain` configuration at
+/// the given time. A consent given to another `Domain` is not found, so a site can't tell
+/// whether another site has a consent with that ID, nor its status.
+pub async fn find_consent_status(
+    store: &impl ConsentStore,
+    id: &str,
+    config: &DomainConfig,
+    now: DateTime<Utc>,
+) -> Result<ConsentStatus, ConsentError> {
+    store
+        .get(id)
+        .await?
+        .filter(|consent| consent.domain() == config.domain())
+        .map(|consent| ConsentStatus::of(&consent, config, now))
+        .ok_or(ConsentError::NotFound)
+}
+
+/// Withdraws the consent with the given ID by storing a new consent that only accepts the
+/// essential cookies. A consent given to another `Domain` is not found, so a site can't
+/// withdraw the consents of another site.
+pub async fn withdraw_consent(
+    store: &impl ConsentStore,
+    id: String,
+    config: &DomainConfig,
+    geolocation: Geolocation,
+    anonymous_ip: Option<AnonymousIp>,
+    user_agent: String,
+    privacy_signals: PrivacySignals,
+) -> Result<ClientCookieConsent, ConsentError> {
+    let withdrawn = store
+        .get(&id)
+        .await?
+        .filter(|consent| consent.domain() == config.domain())
+        .ok_or(ConsentError::NotFound)?;
+    let (_, withdrawn_value) = withdrawn.to_kv();
+    let withdrawal = CookieConsent::withdrawal(
+        id,
+        withdrawn_value.policy_version().cloned(),
+        config.domain().clone(),
         geolocation,
         anonymous_ip,
         user_agent,
-    );
-    let client_consent = ClientCookieConsent::from(&consent);
-    let (id, value) = consent.to_kv();
-
-    ctx
-        .kv(cookie_consent_kv)?
-        .put(&id, value)?
-        .execute()
-        .await
-        .map_or_else(
-            |e| internal_error("Fail to store cookie consent", e),
-            |_| Response::ok(client_consent.to_json()),
+        privacy_signals,
+    ).retained_for(config.retention());
+
+    store.put(&withdrawal).await?;
+
+    Ok(ClientCookieConsent::from(&withdrawal))
+}
+
+/// Updates the consent with the given ID by storing a new consent with the given preference.
+/// A consent given to another `Domain` is not found, so a site can't update the consents of
+/// another site.
+#[allow(clippy::too_many_arguments)]
+pub async fn update_consent(
+    store: &impl ConsentStore,
+    id: String,
+    config: &DomainConfig,
+    pref: CookieConsentPref,
+    policy_version: PolicyVersion,
+    geolocation: Geolocation,
+    anonymous_ip: Option<AnonymousIp>,
+    user_agent: String,
+    privacy_signals: PrivacySignals,
+) -> Result<ClientCookieConsent, ConsentError> {
+    store
+        .get(&id)
+        .await?
+        .filter(|consent| consent.domain() == config.domain())
+        .ok_or(ConsentError::NotFound)?;
+
+    let update = CookieConsent::update(
+        id,
+        config.domain().clone(),
+        pref,
+        policy_version,
+        geolocation,
+        anonymous_ip,
+        user_agent,
+        privacy_signals,
+    ).retained_for(config.retention());
+
+    store.put(&update).await?;
+
+    Ok(ClientCookieConsent::from(&update))
+}
+
+/// Returns the consent with the given ID followed by all its predecessors, from the newest
+/// to the oldest one. A consent given to another `Domain` is not found, and the history stops
+/// at any predecessor given to another `Domain`, so a site can't read the consents of another
+/// site.
+pub async fn find_history(
+    store: &impl ConsentStore,
+    id: String,
+    config: &DomainConfig,
+) -> Result<Vec<ClientCookieConsent>, ConsentError> {
+    let mut history = Vec::new();
+    let mut next_id = Some(id);
+
+    while let Some(id) = next_id.take() {
+        if history.len() == MAX_HISTORY_LENGTH {
+            break;
+        }
+
+        let consent = store
+            .get(&id)
+            .await?
+            .filter(|consent| consent.domain() == config.domain());
+
+        if let Some(consent) = consent {
+            let (_, value) = consent.to_kv();
+
+            next_id = value.predecessor_id().cloned();
+            history.push(ClientCookieConsent::from(&consent));
+        }
+    }
+
+    if history.is_empty() {
+        Err(ConsentError::NotFound)
+    } else {
+        Ok(history)
+    }
+}
+
+/// Reads the consent request of a JSON body and validates it against the configuration of the
+/// `Domain` the request comes from, returning the error of each invalid field.
+pub fn read_consent_req(
+    json: Value,
+    config: &DomainConfig,
+) -> Result<(CookieConsentPref, PolicyVersion), Vec<FieldError>> {
+    CookieConsentReq::from_json(json).and_then(|req| req.validate(config))
+}
+
+/// Reads the signing keys of the receipts. Handlers read them before writing to the store, so
+/// invalid keys fail the request before the consent is stored, and a retry doesn't store it
+/// twice.
+fn signing_keys(env: &Env) -> Result<Option<SigningKeys>, ApiError> {
+    SigningKeys::from_env(env).map_err(|e| ApiError::internal("signing_keys_invalid", e))
+}
+
+fn consent_response(
+    result: Result<ClientCookieConsent, ConsentError>,
+    config: &DomainConfig,
+    keys: Option<&SigningKeys>,
+    log: &RequestLog,
+    error_event: &'static str,
+) -> Result<Response, Error> {
+    match result {
+        Ok(client_consent) => Response::ok(
+            with_receipt(
+                with_domain_signals(client_consent, config),
+                config.domain(),
+                keys,
+            ).to_json()
+        ),
+        Err(e) => e.to_api_error(error_event).to_response(log),
+    }
+}
+
+/// Returns the consent with the signals the `Domain` configures, that is, its expiration, its
+/// Google Consent Mode signals, and its TC string if the `Domain` has TCF settings.
+pub fn with_domain_signals(
+    consent: ClientCookieConsent,
+    config: &DomainConfig,
+) -> ClientCookieConsent {
+    let consent = consent
+        .with_expiration(config.lifetime())
+        .with_consent_mode(config.consent_mode());
+
+    match config.tcf() {
+        Some(tcf) => consent.with_tc_string(tcf, VendorList::local()),
+        None => consent,
+    }
+}
+
+/// Returns the consent with its receipt signed for the `Domain` if the signing keys are
+/// configured.
+pub fn with_receipt(
+    consent: ClientCookieConsent,
+    domain: &Domain,
+    keys: Option<&SigningKeys>,
+) -> ClientCookieConsent {
+    match keys {
+        Some(keys) => consent.with_receipt(domain, keys),
+        None => consent,
+    }
+}
+
+fn anonymous_ip<D>(req: &Request, ctx: &RouteContext<D>) -> Option<AnonymousIp> {
+    let prefix_length = ipv6_prefix_length(ctx);
+
+    req
+        .headers()
+        .get("cf-connecting-ip")
+        .unwrap_or(None)
+        .map(|raw_ip| IpAddr::from_str(&raw_ip))
+        .and_then(Result::ok)
+        .map(|ip| AnonymousIp::from_ip(ip, prefix_length))
+}
+
+fn user_agent(req: &Request) -> String {
+    req
+        .headers()
+        .get("user-agent")
+        .unwrap_or(None)
+        .unwrap_or("".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use chrono::{DateTime, Days, NaiveDate, Utc};
+    use futures::executor::block_on;
+
+    use crate::config::DomainConfig;
+    use crate::consent::{ClientCookieConsent, CookieConsent, CookieConsentPref};
+    use crate::consent::Domain;
+    use crate::cookie_consent::{
+        ConsentError,
+        find_consent,
+        find_consent_receipt,
+        find_consent_status,
+        find_history,
+        register_consent,
+        update_consent,
+        withdraw_consent,
+    };
+    use crate::geolocation::Geolocation;
+    use crate::policy::{KnownPolicy, PolicyVersion};
+    use crate::privacy_signal::PrivacySignals;
+    use crate::log::RequestLog;
+    use crate::stats::{CountedConsent, DailyCounts, MemoryStatsCounter, StatsCounter};
+    use crate::store::{ConsentStore, MemoryConsentStore, StoreError};
+
+    /// Fails every count, like a stats store that's unavailable.
+    struct FailingStatsCounter;
+
+    #[async_trait(?Send)]
+    impl StatsCounter for FailingStatsCounter {
+        async fn add_count(&self, _consent: &CountedConsent) -> Result<(), StoreError> {
+            Err(StoreError::new("Stats unavailable"))
+        }
+
+        async fn get_counts(
+            &self,
+            _domain: &Domain,
+            _day: NaiveDate,
+        ) -> Result<DailyCounts, StoreError> {
+            Err(StoreError::new("Stats unavailable"))
+        }
+    }
+
+    #[test]
+    fn registers_and_finds_consent() {
+        let store = MemoryConsentStore::default();
+        let consent = block_on(register(&store, all_accepted_pref())).unwrap();
+        let id = consent_id(&consent);
+
+        assert_eq!(Ok(consent), block_on(find_consent(&store, &id, &dummy_config())));
+        assert_eq!(
+            Err(ConsentError::NotFound),
+            block_on(find_consent(&store, "unknown", &dummy_config()))
+        );
+    }
+
+    #[test]
+    fn finds_consent_of_its_domain_only() {
+        let store = MemoryConsentStore::default();
+        let consent = block_on(register(&store, all_accepted_pref())).unwrap();
+        let id = consent_id(&consent);
+
+        assert_eq!(
+            Err(ConsentError::NotFound),
+            block_on(find_consent(&store, &id, &other_config())),
+            "consents of mathswe.com are not found from math.software"
+        );
+    }
+
+    #[test]
+    fn counts_registered_consents() {
+        let store = MemoryConsentStore::default();
+        let counter = MemoryStatsCounter::default();
+        let config = dummy_config();
+        let consent = block_on(register_consent(
+            &store,
+            &counter,
+            &dummy_log(),
+            &config,
+            all_accepted_pref(),
+            dummy_policy_version(),
+            dummy_geolocation(),
+            None,
+            dummy_user_agent(),
+            PrivacySignals::default(),
+        )).unwrap();
+        let created_at = serde_json::to_value(&consent).unwrap()["created_at"]
+            .as_str()
+            .unwrap()
+            .parse::<DateTime<Utc>>()
+            .unwrap();
+        let counts = block_on(counter.get_counts(config.domain(), created_at.date_naive()));
+        let counts = serde_json::to_value(counts.unwrap()).unwrap();
+
+        assert_eq!(1, counts["counts"]["consents"]);
+        assert_eq!(1, counts["counts"]["targeting"]);
+    }
+
+    #[test]
+    fn registers_consent_if_counting_fails() {
+        let store = MemoryConsentStore::default();
+        let consent = block_on(register_consent(
+            &store,
+            &FailingStatsCounter,
+            &dummy_log(),
+            &dummy_config(),
+            all_accepted_pref(),
+            dummy_policy_version(),
+            dummy_geolocation(),
+            None,
+            dummy_user_agent(),
+            PrivacySignals::default(),
+        )).unwrap();
+
+        assert_eq!(
+            Ok(consent.clone()),
+            block_on(find_consent(&store, &consent_id(&consent), &dummy_config()))
+        );
+    }
+
+    #[test]
+    fn finds_consent_receipt() {
+        let store = MemoryConsentStore::default();
+        let config = dummy_config();
+        let consent = block_on(register(&store, all_accepted_pref())).unwrap();
+        let id = consent_id(&consent);
+        let receipt = block_on(find_consent_receipt(&store, &id, &config)).unwrap();
+        let receipt_json = serde_json::to_value(&receipt).unwrap();
+
+        assert_eq!(id, receipt_json["consentReceiptID"]);
+        assert_eq!(4, receipt_json["services"][0]["purposes"].as_array().unwrap().len());
+        assert_eq!(
+            Err(ConsentError::NotFound),
+            block_on(find_consent_receipt(&store, "unknown", &config))
+        );
+    }
+
+    #[test]
+    fn finds_consent_receipt_of_its_domain_only() {
+        let store = MemoryConsentStore::default();
+        let consent = block_on(register(&store, all_accepted_pref())).unwrap();
+        let id = consent_id(&consent);
+
+        assert_eq!(
+            Err(ConsentError::NotFound),
+            block_on(find_consent_receipt(&store, &id, &other_config())),
+            "consents of mathswe.com have no receipt from math.software"
+        );
+    }
+
+    #[test]
+    fn finds_consent_status() {
+        let store = MemoryConsentStore::default();
+        let config = dummy_config();
+        let consent = block_on(register(&store, all_accepted_pref())).unwrap();
+        let id = consent_id(&consent);
+        let status_at = |days| {
+            let now = Utc::now() + Days::new(days);
+            let status = block_on(find_consent_status(&store, &id, &config, now)).unwrap();
+
+            serde_json::to_value(status).unwrap()
+        };
+
+        assert_eq!(true, status_at(364)["valid"]);
+        assert_eq!(
+            serde_json::to_value(consent.with_expiration(config.lifetime())).unwrap()["expires_at"],
+            status_at(0)["expires_at"],
+            "the status tells the same expiration as the consent"
+        );
+        assert_eq!(false, status_at(365)["valid"]);
+        assert_eq!("Expired", status_at(365)["reprompt_reason"]);
+        assert_eq!(
+            Err(ConsentError::NotFound),
+            block_on(find_consent_status(&store, "unknown", &config, Utc::now()))
+        );
+    }
+
+    #[test]
+    fn finds_consent_status_of_its_domain_only() {
+        let store = MemoryConsentStore::default();
+        let consent = block_on(register(&store, all_accepted_pref())).unwrap();
+        let id = consent_id(&consent);
+
+        assert_eq!(
+            Err(ConsentError::NotFound),
+            block_on(find_consent_status(&store, &id, &other_config(), Utc::now())),
+            "consents of mathswe.com have no status for math.software"
+        );
+    }
+
+    #[test]
+    fn withdraws_consent() {
+        let store = MemoryConsentStore::default();
+        let consent = block_on(register(&store, all_accepted_pref())).unwrap();
+        let id = consent_id(&consent);
+        let withdrawal = block_on(withdraw_consent(
+            &store,
+            id.clone(),
+            &dummy_config(),
+            dummy_geolocation(),
+            None,
+            dummy_user_agent(),
+            PrivacySignals::default(),
+        )).unwrap();
+        let withdrawal_json = serde_json::to_value(&withdrawal).unwrap();
+
+        assert_eq!(id, withdrawal_json["withdrawn_id"]);
+        assert_eq!(
+            serde_json::to_value(CookieConsentPref::essential_only()).unwrap(),
+            withdrawal_json["pref"]
+        );
+        assert_eq!(
+            Ok(consent),
+            block_on(find_consent(&store, &id, &dummy_config())),
+            "withdrawn consent is kept as is"
+        );
+        assert_eq!(
+            Err(ConsentError::NotFound),
+            block_on(withdraw_consent(
+                &store,
+                "unknown".to_string(),
+                &dummy_config(),
+                dummy_geolocation(),
+                None,
+                dummy_user_agent(),
+                PrivacySignals::default(),
+            ))
+        );
+    }
+
+    #[test]
+    fn withdraws_consent_of_its_domain_only() {
+        let store = MemoryConsentStore::default();
+        let consent = block_on(register(&store, all_accepted_pref())).unwrap();
+        let id = consent_id(&consent);
+
+        assert_eq!(
+            Err(ConsentError::NotFound),
+            block_on(withdraw_consent(
+                &store,
+                id.clone(),
+                &other_config(),
+                dummy_geolocation(),
+                None,
+                dummy_user_agent(),
+                PrivacySignals::default(),
+            )),
+            "consents of mathswe.com can't be withdrawn from math.software"
+        );
+        assert_eq!(Ok(vec![consent]), block_on(find_history(&store, id, &dummy_config())));
+    }
+
+    #[test]
+    fn updates_consent_of_its_domain_only() {
+        let store = MemoryConsentStore::default();
+        let consent = block_on(register(&store, all_accepted_pref())).unwrap();
+        let id = consent_id(&consent);
+
+        assert_eq!(
+            Err(ConsentError::NotFound),
+            block_on(update_consent(
+                &store,
+                id.clone(),
+                &other_config(),
+                CookieConsentPref::essential_only(),
+                dummy_policy_version(),
+                dummy_geolocation(),
+                None,
+                dummy_user_agent(),
+                PrivacySignals::default(),
+            )),
+            "consents of mathswe.com can't be updated from math.software"
+        );
+        assert_eq!(Ok(vec![consent]), block_on(find_history(&store, id, &dummy_config())));
+    }
+
+    #[test]
+    fn finds_history_of_updated_consent() {
+        let store = MemoryConsentStore::default();
+        let first = block_on(register(&store, all_accepted_pref())).unwrap();
+        let second = block_on(update(&store, consent_id(&first))).unwrap();
+        let third = block_on(withdraw_consent(
+            &store,
+            consent_id(&second),
+            &dummy_config(),
+            dummy_geolocation(),
+            None,
+            dummy_user_agent(),
+            PrivacySignals::default(),
+        )).unwrap();
+
+        assert_eq!(
+            Ok(vec![third.clone(), second.clone(), first.clone()]),
+            block_on(find_history(&store, consent_id(&third), &dummy_config())),
+            "history goes from the newest to the oldest consent"
+        );
+        assert_eq!(
+            Ok(vec![second.clone(), first.clone()]),
+            block_on(find_history(&store, consent_id(&second), &dummy_config())),
+            "history starts from the given consent"
+        );
+        assert_eq!(
+            Ok(vec![first.clone()]),
+            block_on(find_history(&store, consent_id(&first), &dummy_config()))
+        );
+        assert_eq!(
+            Err(ConsentError::NotFound),
+            block_on(find_history(&store, "unknown".to_string(), &dummy_config()))
+        );
+        assert_eq!(
+            Err(ConsentError::NotFound),
+            block_on(update(&store, "unknown".to_string()))
+        );
+    }
+
+    #[test]
+    fn finds_history_of_its_domain_only() {
+        let store = MemoryConsentStore::default();
+        let foreign = block_on(register_consent(
+            &store,
+            &MemoryStatsCounter::default(),
+            &dummy_log(),
+            &other_config(),
+            all_accepted_pref(),
+            dummy_policy_version(),
+            dummy_geolocation(),
+            None,
+            dummy_user_agent(),
+            PrivacySignals::default(),
+        )).unwrap();
+        let linked = CookieConsent::update(
+            consent_id(&foreign),
+            Domain::new("mathswe.com"),
+            CookieConsentPref::essential_only(),
+            dummy_policy_version(),
+            dummy_geolocation(),
+            None,
+            dummy_user_agent(),
+            PrivacySignals::default(),
+        );
+        let linked_id = consent_id(&ClientCookieConsent::from(&linked));
+
+        block_on(store.put(&linked)).unwrap();
+
+        assert_eq!(
+            Err(ConsentError::NotFound),
+            block_on(find_history(&store, consent_id(&foreign), &dummy_config())),
+            "consents of math.software are not found from mathswe.com"
+        );
+        assert_eq!(
+            Ok(vec![ClientCookieConsent::from(&linked)]),
+            block_on(find_history(&store, linked_id, &dummy_config())),
+            "history stops at predecessors of another domain"
+        );
+    }
+
+    async fn register(
+        store: &MemoryConsentStore,
+        pref: CookieConsentPref,
+    ) -> Result<ClientCookieConsent, ConsentError> {
+        register_consent(
+            store,
+            &MemoryStatsCounter::default(),
+            &dummy_log(),
+            &dummy_config(),
+            pref,
+            dummy_policy_version(),
+            dummy_geolocation(),
+            None,
+            dummy_user_agent(),
+            PrivacySignals::default(),
+        ).await
+    }
+
+    async fn update(
+        store: &MemoryConsentStore,
+        id: String,
+    ) -> Result<ClientCookieConsent, ConsentError> {
+        update_consent(
+            store,
+            id,
+            &dummy_config(),
+            CookieConsentPref::essential_only(),
+            dummy_policy_version(),
+            dummy_geolocation(),
+            None,
+            dummy_user_agent(),
+            PrivacySignals::default(),
+        ).await
+    }
+
+    fn consent_id(consent: &ClientCookieConsent) -> String {
+        serde_json::to_value(consent).unwrap()["id"].as_str().unwrap().to_string()
+    }
+
+    fn all_accepted_pref() -> CookieConsentPref {
+        serde_json::from_str(r#"{
+            "essential": true,
+            "functional": true,
+            "analytical": true,
+            "targeting": true
+        }"#).unwrap()
+    }
+
+    fn dummy_log() -> RequestLog {
+        RequestLog::new(None, "POST", "/")
+    }
+
+    fn dummy_config() -> DomainConfig {
+        DomainConfig::new(
+            Domain::new("mathswe.com"),
+            None,
+            vec![KnownPolicy::active("2024-03-10")],
+        )
+    }
+
+    fn other_config() -> DomainConfig {
+        DomainConfig::new(
+            Domain::new("math.software"),
+            None,
+            vec![KnownPolicy::active("2024-03-10")],
         )
+    }
+
+    fn dummy_policy_version() -> PolicyVersion {
+        PolicyVersion::validate("2024-03-10".to_string(), &[KnownPolicy::active("2024-03-10")])
+            .unwrap()
+    }
+
+    fn dummy_geolocation() -> Geolocation {
+        Geolocation::empty_with(chrono_tz::Tz::Europe__Berlin)
+    }
+
+    fn dummy_user_agent() -> String {
+        "Mozilla/5.0 (X11; Linux x86_64; rv:123.0) Gecko/20100101 Firefox/123.0".to_string()
+    }
 }
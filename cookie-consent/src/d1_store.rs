@@ -0,0 +1,53 @@
+// Copyright (c) 2024 Tobias Briones. All rights reserved.
+// This file is part of https://github.com/mathswe/legal
+
+use async_trait::async_trait;
+use serde_json::Value;
+use wasm_bindgen::JsValue;
+use worker::{D1Database, Env, Error};
+
+use crate::sql_store::{SqlConsentStore, SqlDatabase};
+use crate::store::StoreError;
+
+const COOKIE_CONSENT_D1: &str = "COOKIE_CONSENT_DB";
+
+/// Stores the consents in the `COOKIE_CONSENT_DB` D1 database, whose schema is created by
+/// the migrations of the `migrations` directory.
+pub type D1ConsentStore = SqlConsentStore<D1Database>;
+
+impl D1ConsentStore {
+    pub fn from_env(env: &Env) -> Result<Self, Error> {
+        env.d1(COOKIE_CONSENT_D1).map(SqlConsentStore::new)
+    }
+}
+
+fn js_params(params: &[Value]) -> Vec<JsValue> {
+    params
+        .iter()
+        .map(|param| match param {
+            Value::Null => JsValue::NULL,
+            Value::Bool(value) => JsValue::from_bool(*value),
+            Value::Number(number) => JsValue::from_f64(number.as_f64().unwrap_or_default()),
+            Value::String(text) => JsValue::from_str(text),
+            other => JsValue::from_str(&other.to_string()),
+        })
+        .collect()
+}
+
+#[async_trait(?Send)]
+impl SqlDatabase for D1Database {
+    async fn execute(&self, sql: &str, params: &[Value]) -> Result<(), StoreError> {
+        self.prepare(sql).bind(&js_params(params))?.run().await?;
+        Ok(())
+    }
+
+    async fn query(&self, sql: &str, params: &[Value]) -> Result<Vec<Vec<Value>>, StoreError> {
+        Ok(self.prepare(sql).bind(&js_params(params))?.raw::<Value>().await?)
+    }
+}
+
+impl From<Error> for StoreError {
+    fn from(error: Error) -> Self {
+        StoreError::new(error.to_string())
+    }
+}
@@ -2,9 +2,13 @@
 // This file is part of https://github.com/mathswe/legal
 
 use std::str::FromStr;
+use js_sys::Reflect;
 use serde::{Deserialize, Serialize};
+use wasm_bindgen::JsValue;
 use worker::Request;
 
+/// Defines the location of a request. It's `incomplete` if the request didn't provide its
+/// location or provided one that's not valid, so the time zone falls back to UTC.
 #[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
 pub struct Geolocation {
     #[serde(with = "chrono_tz_serde")]
@@ -13,9 +17,31 @@ pub struct Geolocation {
     city: Option<String>,
     region: Option<String>,
     region_code: Option<String>,
+    #[serde(default)]
+    incomplete: bool,
+}
+
+/// Defines the raw location fields of the request `cf` object, which might be absent.
+#[derive(Default)]
+pub struct CfGeolocation {
+    pub time_zone: Option<String>,
+    pub country: Option<String>,
+    pub city: Option<String>,
+    pub region: Option<String>,
+    pub region_code: Option<String>,
 }
 
 impl Geolocation {
+    pub fn new(
+        time_zone: chrono_tz::Tz,
+        country: Option<String>,
+        city: Option<String>,
+        region: Option<String>,
+        region_code: Option<String>,
+    ) -> Self {
+        Geolocation { time_zone, country, city, region, region_code, incomplete: false }
+    }
+
     #[allow(dead_code)]
     pub fn empty_with(time_zone: chrono_tz::Tz) -> Self {
         Geolocation {
@@ -24,22 +50,81 @@ impl Geolocation {
             city: None,
             region: None,
             region_code: None,
+            incomplete: false,
         }
     }
 
+    pub fn country(&self) -> Option<&String> {
+        self.country.as_ref()
+    }
+
+    pub fn city(&self) -> Option<&String> {
+        self.city.as_ref()
+    }
+
+    pub fn region_code(&self) -> Option<&String> {
+        self.region_code.as_ref()
+    }
+
+    /// Returns the location without its city, which is the part of the location that's
+    /// personal data.
+    pub fn without_city(self) -> Self {
+        Geolocation { city: None, ..self }
+    }
+
+    /// Returns the `Geolocation` of the request `cf` object, or an incomplete one if the
+    /// request has no `cf` object or its location is not valid.
     pub fn from_req(req: &Request) -> Self {
-        let cf = req.cf().unwrap();
+        let cf = req
+            .cf()
+            .map(|cf| CfGeolocation {
+                time_zone: cf_time_zone(req),
+                country: cf.country(),
+                city: cf.city(),
+                region: cf.region(),
+                region_code: cf.region_code(),
+            });
 
-        Geolocation {
-            time_zone: chrono_tz::Tz::from_str(&cf.timezone_name()).unwrap(),
-            country: cf.country(),
-            city: cf.city(),
-            region: cf.region(),
-            region_code: cf.region_code(),
+        Self::from_cf(cf)
+    }
+
+    /// Returns the `Geolocation` of the raw `cf` fields. If there are no fields, it's an
+    /// incomplete location in UTC. If the time zone is absent or unknown, it's an incomplete
+    /// location in UTC that keeps the rest of the fields.
+    pub fn from_cf(cf: Option<CfGeolocation>) -> Self {
+        match cf {
+            Some(cf) => {
+                let time_zone = cf
+                    .time_zone
+                    .as_deref()
+                    .map(chrono_tz::Tz::from_str)
+                    .and_then(Result::ok);
+
+                Geolocation {
+                    time_zone: time_zone.unwrap_or(chrono_tz::Tz::UTC),
+                    country: cf.country,
+                    city: cf.city,
+                    region: cf.region,
+                    region_code: cf.region_code,
+                    incomplete: time_zone.is_none(),
+                }
+            }
+            None => Geolocation {
+                incomplete: true,
+                ..Self::empty_with(chrono_tz::Tz::UTC)
+            },
         }
     }
 }
 
+/// Reads the time zone of the request `cf` object without assuming it's present.
+fn cf_time_zone(req: &Request) -> Option<String> {
+    Reflect::get(req.inner(), &JsValue::from_str("cf"))
+        .and_then(|cf| Reflect::get(&cf, &JsValue::from_str("timezone")))
+        .ok()
+        .and_then(|time_zone| time_zone.as_string())
+}
+
 mod chrono_tz_serde {
     use std::str::FromStr;
     use chrono_tz::Tz;
@@ -60,3 +145,82 @@ mod chrono_tz_serde {
         Tz::from_str(&tz_str).map_err(serde::de::Error::custom)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::geolocation::{CfGeolocation, Geolocation};
+
+    #[test]
+    fn reads_complete_cf_geolocation() {
+        let cf = CfGeolocation {
+            time_zone: Some("America/Tegucigalpa".to_string()),
+            country: Some("HN".to_string()),
+            city: Some("Tegucigalpa".to_string()),
+            region: Some("Francisco Morazan Department".to_string()),
+            region_code: Some("FM".to_string()),
+        };
+
+        assert_eq!(
+            Geolocation::new(
+                chrono_tz::Tz::America__Tegucigalpa,
+                Some("HN".to_string()),
+                Some("Tegucigalpa".to_string()),
+                Some("Francisco Morazan Department".to_string()),
+                Some("FM".to_string()),
+            ),
+            Geolocation::from_cf(Some(cf))
+        );
+    }
+
+    #[test]
+    fn falls_back_to_utc_without_cf() {
+        let geolocation = Geolocation::from_cf(None);
+
+        assert_eq!(chrono_tz::Tz::UTC, geolocation.time_zone);
+        assert_eq!(None, geolocation.country);
+        assert!(geolocation.incomplete, "missing cf gives an incomplete geolocation");
+    }
+
+    #[test]
+    fn falls_back_to_utc_with_malformed_time_zone() {
+        let malformed_time_zones = [
+            None,
+            Some(""),
+            Some("Mars/Olympus_Mons"),
+            Some("america/tegucigalpa "),
+        ];
+
+        malformed_time_zones
+            .iter()
+            .for_each(|time_zone| {
+                let cf = CfGeolocation {
+                    time_zone: time_zone.map(str::to_string),
+                    country: Some("HN".to_string()),
+                    ..CfGeolocation::default()
+                };
+                let geolocation = Geolocation::from_cf(Some(cf));
+
+                assert_eq!(chrono_tz::Tz::UTC, geolocation.time_zone);
+                assert_eq!(
+                    Some("HN".to_string()),
+                    geolocation.country,
+                    "valid cf fields are kept"
+                );
+                assert!(geolocation.incomplete, "{:?} is not a valid time zone", time_zone);
+            })
+    }
+
+    #[test]
+    fn deserializes_stored_geolocation() {
+        let json = r#"{
+            "time_zone": "America/Tegucigalpa",
+            "country": "HN",
+            "city": null,
+            "region": null,
+            "region_code": null
+        }"#;
+        let geolocation = serde_json::from_str::<Geolocation>(json).unwrap();
+
+        assert!(!geolocation.incomplete, "stored geolocations are complete");
+    }
+}
@@ -0,0 +1,208 @@
+// Copyright (c) 2024 Tobias Briones. All rights reserved.
+// This file is part of https://github.com/mathswe/legal
+
+use async_trait::async_trait;
+use chrono::{NaiveDate, Utc};
+use nanoid::nanoid;
+use worker::{Env, Error};
+use worker::kv::{KvError, KvStore};
+
+use crate::consent::{CookieConsent, CookieConsentValue, Domain};
+use crate::rate_limit::RateCounter;
+use crate::stats::{CountedConsent, DailyCounts, stats_key, StatsCounter};
+use crate::store::{
+    ConsentPage,
+    ConsentStore,
+    index_day_prefix,
+    index_key,
+    index_key_id,
+    StoreError,
+};
+
+const COOKIE_CONSENT_KV: &str = "COOKIE_CONSENT";
+
+/// Minimum expiration TTL in seconds Workers KV accepts.
+const MIN_KV_TTL: u64 = 60;
+
+/// Stores the consents in the `COOKIE_CONSENT` Workers KV namespace, where each key is the
+/// consent ID and each value is its `CookieConsentValue`. The namespace also keeps the rate
+/// limit counters and the daily statistics under keys with a prefix like `rate:` or
+/// `stats:`, which are never consent IDs since these can't have `:`, and the index keys of
+/// `index_key`, which list the consents of a `Domain` by day. A consent and its index key
+/// expire when the consent is `retained_until`.
+pub struct KvConsentStore(KvStore);
+
+impl KvConsentStore {
+    pub fn from_env(env: &Env) -> Result<Self, Error> {
+        env.kv(COOKIE_CONSENT_KV).map(KvConsentStore)
+    }
+}
+
+/// Returns the expiration of the consent as a UNIX timestamp in seconds, which is at least the
+/// minimum TTL from now, since Workers KV rejects earlier expirations.
+fn expiration(consent: &CookieConsent) -> Option<u64> {
+    let (_, value) = consent.to_kv();
+    let min_expiration = Utc::now().timestamp() + MIN_KV_TTL as i64;
+
+    value
+        .retained_until()
+        .map(|retained_until| retained_until.timestamp().max(min_expiration) as u64)
+}
+
+#[async_trait(?Send)]
+impl ConsentStore for KvConsentStore {
+    async fn put(&self, consent: &CookieConsent) -> Result<(), StoreError> {
+        let (id, value) = consent.to_kv();
+        let expiration = expiration(consent);
+        let put = self.0.put(&id, value)?;
+        let put_index = self.0.put(&index_key(consent), "")?;
+
+        match expiration {
+            Some(expiration) => {
+                put.expiration(expiration).execute().await?;
+                put_index.expiration(expiration).execute().await?;
+            }
+            None => {
+                put.execute().await?;
+                put_index.execute().await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<CookieConsent>, StoreError> {
+        self
+            .0
+            .get(id)
+            .json::<CookieConsentValue>()
+            .await
+            .map(|value| value.map(|value| CookieConsent::from_kv(id.to_string(), value)))
+            .map_err(StoreError::from)
+    }
+
+    async fn list(&self, cursor: Option<String>, limit: u64) -> Result<ConsentPage, StoreError> {
+        let list = self.0.list().limit(limit);
+        let list = match cursor {
+            Some(cursor) => list.cursor(cursor),
+            None => list,
+        };
+        let res = list.execute().await?;
+        let ids = res
+            .keys
+            .into_iter()
+            .map(|key| key.name)
+            .filter(|name| !name.contains(':'))
+            .collect();
+        let cursor = if res.list_complete { None } else { res.cursor };
+
+        Ok(ConsentPage { ids, cursor })
+    }
+
+    async fn list_day(
+        &self,
+        domain: &Domain,
+        day: NaiveDate,
+        cursor: Option<String>,
+        limit: u64,
+    ) -> Result<ConsentPage, StoreError> {
+        let list = self.0.list().prefix(index_day_prefix(domain, day)).limit(limit);
+        let list = match cursor {
+            Some(cursor) => list.cursor(cursor),
+            None => list,
+        };
+        let res = list.execute().await?;
+        let ids = res
+            .keys
+            .into_iter()
+            .map(|key| index_key_id(&key.name).to_string())
+            .collect();
+        let cursor = if res.list_complete { None } else { res.cursor };
+
+        Ok(ConsentPage { ids, cursor })
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), StoreError> {
+        if let Some(consent) = self.get(id).await? {
+            self.0.delete(&index_key(&consent)).await?;
+        }
+
+        self.0.delete(id).await.map_err(StoreError::from)
+    }
+}
+
+/// Counts the requests in the `COOKIE_CONSENT` namespace. Workers KV is eventually
+/// consistent, so concurrent requests from other locations might be counted late, which is
+/// enough to stop a client from flooding the namespace.
+#[async_trait(?Send)]
+impl RateCounter for KvConsentStore {
+    async fn increment(&self, key: &str, ttl: u64) -> Result<u64, StoreError> {
+        let count = self
+            .0
+            .get(key)
+            .text()
+            .await?
+            .and_then(|count| count.parse::<u64>().ok())
+            .unwrap_or(0) + 1;
+
+        self
+            .0
+            .put(key, count.to_string())?
+            .expiration_ttl(ttl.max(MIN_KV_TTL))
+            .execute()
+            .await?;
+
+        Ok(count)
+    }
+}
+
+/// Shards of the daily counts of a `Domain`, which are the last character of their keys.
+const STATS_SHARDS: [char; 16] = [
+    '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', 'a', 'b', 'c', 'd', 'e', 'f',
+];
+
+/// Expiration TTL of the daily counts in seconds, that is, about three years.
+const STATS_TTL: u64 = 3 * 365 * 24 * 60 * 60;
+
+/// Keeps the daily counts of a `Domain` in the `COOKIE_CONSENT` namespace, split into a
+/// shard key per character of `STATS_SHARDS`, like `stats:mathswe.com:2024-03-10:a`, whose
+/// value has the JSON counts. Workers KV allows about one write per second to a key, so each
+/// consent is counted in a random shard to spread the writes of concurrent consents, though
+/// two of them counted in the same shard at once keep one count. Reading the counts of a day
+/// reads every shard, however many consents there are. The counts expire after `STATS_TTL`.
+#[async_trait(?Send)]
+impl StatsCounter for KvConsentStore {
+    async fn add_count(&self, consent: &CountedConsent) -> Result<(), StoreError> {
+        let key = format!(
+            "{}:{}",
+            stats_key(consent.domain(), consent.day()),
+            nanoid!(1, &STATS_SHARDS),
+        );
+        let mut counts = self.0.get(&key).json::<DailyCounts>().await?.unwrap_or_default();
+
+        counts.add(consent.pref(), consent.country());
+
+        Ok(self.0.put(&key, counts)?.expiration_ttl(STATS_TTL).execute().await?)
+    }
+
+    async fn get_counts(&self, domain: &Domain, day: NaiveDate) -> Result<DailyCounts, StoreError> {
+        let key = stats_key(domain, day);
+        let mut counts = DailyCounts::default();
+
+        for shard in STATS_SHARDS {
+            let shard_key = format!("{}:{}", key, shard);
+
+            if let Some(shard_counts) = self.0.get(&shard_key).json::<DailyCounts>().await? {
+                counts.merge(&shard_counts);
+            }
+        }
+
+        Ok(counts)
+    }
+}
+
+impl From<KvError> for StoreError {
+    fn from(error: KvError) -> Self {
+        StoreError::new(error.to_string())
+    }
+}
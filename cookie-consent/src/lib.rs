@@ -1,23 +1,120 @@
 // Copyright (c) 2024 Tobias Briones. All rights reserved.
 // This file is part of https://github.com/mathswe/legal
 
+use chrono::Utc;
+use wasm_bindgen::prelude::wasm_bindgen;
 use worker::*;
 
-use crate::cookie_consent::post_consent;
+use crate::admin::{get_consents, get_stats, post_retention};
+use crate::api_error::ApiError;
+use crate::config::DomainsConfig;
+use crate::cookie_consent::{
+    get_consent,
+    get_consent_receipt,
+    get_consent_status,
+    get_history,
+    post_consent,
+    post_update,
+    post_verification,
+    post_withdrawal,
+};
+#[cfg(feature = "d1")]
+use crate::d1_store::D1ConsentStore;
+#[cfg(not(feature = "d1"))]
+use crate::kv_store::KvConsentStore;
+use crate::log::RequestLog;
+use crate::retention::{apply_retention_to_all, RETENTION_BATCH_SIZE};
+use crate::route::{Route, ROUTES};
+use crate::server::{CorsRouter, ServiceData};
 
-mod consent;
-mod cookie_consent;
-mod geolocation;
-mod anonymous_ip;
-mod client_req;
+pub mod admin;
+pub mod api_error;
+pub mod consent;
+pub mod consent_mode;
+pub mod consent_receipt;
+pub mod cookie_consent;
+pub mod geolocation;
+pub mod lifetime;
+pub mod log;
+pub mod policy;
+pub mod anonymous_ip;
+pub mod privacy_signal;
+pub mod rate_limit;
+pub mod receipt;
+pub mod retention;
+pub mod route;
+pub mod stats;
+pub mod tcf;
+pub mod client_req;
 mod server;
+pub mod store;
+pub mod config;
+pub mod sql_store;
+#[cfg(not(feature = "d1"))]
+mod kv_store;
+#[cfg(feature = "d1")]
+mod d1_store;
 
 #[event(fetch)]
 pub async fn main(req: Request, env: Env, _ctx: Context) -> Result<Response> {
-    let router = Router::new();
+    #[cfg(feature = "d1")]
+    let store = D1ConsentStore::from_env(&env)?;
+    #[cfg(not(feature = "d1"))]
+    let store = KvConsentStore::from_env(&env)?;
+    let domains = match DomainsConfig::from_env(&env) {
+        Ok(domains) => domains,
+        Err(e) => {
+            return ApiError::internal("domains_config_invalid", e)
+                .to_response(&RequestLog::from_req(&req));
+        }
+    };
+    let data = ServiceData::new(store, domains.clone());
 
-    router
-        .post_async("/", post_consent)
-        .run(req, env)
+    ROUTES
+        .into_iter()
+        .fold(CorsRouter::with_data(data), |router, route| match route {
+            Route::PostConsent => router.route_async(route, post_consent),
+            Route::PostVerification => router.route_async(route, post_verification),
+            Route::GetConsents => router.route_async(route, get_consents),
+            Route::PostRetention => router.route_async(route, post_retention),
+            Route::GetStats => router.route_async(route, get_stats),
+            Route::GetConsent => router.route_async(route, get_consent),
+            Route::PostWithdrawal => router.route_async(route, post_withdrawal),
+            Route::PostUpdate => router.route_async(route, post_update),
+            Route::GetHistory => router.route_async(route, get_history),
+            Route::GetReceipt => router.route_async(route, get_consent_receipt),
+            Route::GetStatus => router.route_async(route, get_consent_status),
+        })
+        .run(req, env, &domains)
         .await
 }
+
+/// Applies the retention rules to every stored consent on the schedule of the `crons`
+/// triggers, so consents are redacted and deleted in time without any admin request. worker
+/// 0.0.22 doesn't export the `ScheduledEvent` that `#[event(scheduled)]` expands to, so the
+/// handler is bound to the `scheduled` export with `wasm_bindgen` as the macro does.
+#[wasm_bindgen]
+pub async fn scheduled(
+    event: worker_sys::ScheduledEvent,
+    env: Env,
+    _ctx: worker_sys::ScheduleContext,
+) {
+    let log = RequestLog::new(None, "SCHEDULED", event.cron());
+    #[cfg(feature = "d1")]
+    let store = D1ConsentStore::from_env(&env);
+    #[cfg(not(feature = "d1"))]
+    let store = KvConsentStore::from_env(&env);
+    let store = match store {
+        Ok(store) => store,
+        Err(e) => return log.error("retention_failed", e),
+    };
+    let domains = match DomainsConfig::from_env(&env) {
+        Ok(domains) => domains,
+        Err(e) => return log.error("domains_config_invalid", e),
+    };
+
+    match apply_retention_to_all(&store, &domains, Utc::now(), RETENTION_BATCH_SIZE).await {
+        Ok(_) => log.info("retention_applied"),
+        Err(e) => log.error("retention_failed", e),
+    }
+}
@@ -0,0 +1,230 @@
+// Copyright (c) 2024 Tobias Briones. All rights reserved.
+// This file is part of https://github.com/mathswe/legal
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Days, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::config::DomainConfig;
+use crate::consent::CookieConsent;
+use crate::geolocation::Geolocation;
+
+/// Defines how many days a consent of a `Domain` is valid before the user has to be asked
+/// again, which is one year by default. Jurisdictions expecting another refresh period are
+/// configured in `countries` by their ISO 3166-1 alpha-2 code, e.g., `{ "FR": 180 }`, and
+/// apply to the consents given from them.
+#[derive(PartialEq, Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LifetimeConfig {
+    default_days: u32,
+    countries: BTreeMap<String, u32>,
+}
+
+impl Default for LifetimeConfig {
+    fn default() -> Self {
+        LifetimeConfig { default_days: 365, countries: BTreeMap::new() }
+    }
+}
+
+impl LifetimeConfig {
+    pub fn new(default_days: u32, countries: BTreeMap<String, u32>) -> Self {
+        LifetimeConfig { default_days, countries }
+    }
+
+    /// Returns the longest lifetime in days, so it can be checked against the retention of
+    /// the consents.
+    pub fn max_days(&self) -> u32 {
+        self.countries.values().copied().fold(self.default_days, u32::max)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let invalid_country = self
+            .countries
+            .keys()
+            .find(|code| code.len() != 2 || !code.chars().all(|c| c.is_ascii_uppercase()));
+
+        if let Some(code) = invalid_country {
+            Err(format!("an invalid lifetime country code {}", code))
+        } else if self.default_days == 0 || self.countries.values().any(|days| *days == 0) {
+            Err("an empty consent lifetime".to_string())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the lifetime in days of a consent given from the given `Geolocation`, which is
+    /// the default one if its country is unknown or not configured.
+    pub fn days(&self, geolocation: &Geolocation) -> u32 {
+        geolocation
+            .country()
+            .and_then(|country| self.countries.get(country))
+            .copied()
+            .unwrap_or(self.default_days)
+    }
+
+    /// Returns when a consent created at the given time from the given `Geolocation` expires.
+    pub fn expiration(
+        &self,
+        created_at: DateTime<Utc>,
+        geolocation: &Geolocation,
+    ) -> DateTime<Utc> {
+        created_at + Days::new(self.days(geolocation).into())
+    }
+}
+
+/// Defines why a consent is no longer valid, so the client has to ask the user again.
+#[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum RepromptReason {
+    /// The consent lifetime of its jurisdiction ended.
+    Expired,
+    /// The consent was given under a cookie policy version that is no longer active, or
+    /// without a policy version.
+    OutdatedPolicy,
+}
+
+/// Defines whether a stored consent is still valid for a `Domain`, so the client knows
+/// whether to keep it or to show the cookie banner again.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct ConsentStatus {
+    id: String,
+    valid: bool,
+    expires_at: DateTime<Utc>,
+    reprompt_reason: Option<RepromptReason>,
+}
+
+impl ConsentStatus {
+    /// Returns the status of a consent given to the `Domain` of the configuration.
+    pub fn of(consent: &CookieConsent, config: &DomainConfig, now: DateTime<Utc>) -> Self {
+        let (id, value) = consent.to_kv();
+        let expires_at = config.lifetime().expiration(value.created_at(), value.geolocation());
+        let policy_active = value
+            .policy_version()
+            .is_some_and(|version| version.is_active(config.policies()));
+        let reprompt_reason = if now >= expires_at {
+            Some(RepromptReason::Expired)
+        } else if !policy_active {
+            Some(RepromptReason::OutdatedPolicy)
+        } else {
+            None
+        };
+
+        ConsentStatus { id, valid: reprompt_reason.is_none(), expires_at, reprompt_reason }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use chrono::{DateTime, Utc};
+
+    use crate::config::DomainsConfig;
+    use crate::consent::Domain;
+    use crate::geolocation::Geolocation;
+    use crate::lifetime::{ConsentStatus, LifetimeConfig, RepromptReason};
+    use crate::store::ConsentBuilder;
+
+    const CONFIG_JSON: &str = r#"[
+        {
+            "domain": "mathswe.com",
+            "policies": [
+                { "version": "2024-01-01", "status": "Retired" },
+                { "version": "2024-03-10", "status": "Active" }
+            ],
+            "lifetime": { "default_days": 365, "countries": { "FR": 180 } }
+        },
+        {
+            "domain": "math.software",
+            "policies": [{ "version": "2024-03-10", "status": "Active" }]
+        }
+    ]"#;
+
+    #[test]
+    fn computes_expiration_per_jurisdiction() {
+        let lifetime = LifetimeConfig::new(365, BTreeMap::from([("FR".to_string(), 180)]));
+        let created_at = date("2024-03-10T12:00:00Z");
+        let expirations = [
+            (Some("FR"), "2024-09-06T12:00:00Z"),
+            (Some("DE"), "2025-03-10T12:00:00Z"),
+            (None, "2025-03-10T12:00:00Z"),
+        ];
+
+        expirations
+            .iter()
+            .for_each(|(country, expiration)| assert_eq!(
+                date(expiration),
+                lifetime.expiration(created_at, &geolocation(*country)),
+                "consents from {:?} expire at {}",
+                country,
+                expiration
+            ));
+        assert_eq!(365, lifetime.max_days());
+    }
+
+    #[test]
+    fn rejects_invalid_config() {
+        let invalid_configs = [
+            LifetimeConfig::new(0, BTreeMap::new()),
+            LifetimeConfig::new(365, BTreeMap::from([("FR".to_string(), 0)])),
+            LifetimeConfig::new(365, BTreeMap::from([("fr".to_string(), 180)])),
+            LifetimeConfig::new(365, BTreeMap::from([("FRA".to_string(), 180)])),
+        ];
+
+        assert!(LifetimeConfig::default().validate().is_ok());
+
+        invalid_configs
+            .iter()
+            .for_each(|config| assert!(config.validate().is_err(), "{:?} is not valid", config));
+    }
+
+    #[test]
+    fn tells_consent_status() {
+        let domains = DomainsConfig::from_json(CONFIG_JSON).unwrap();
+        let mathswe = domains.get(&Domain::new("mathswe.com")).unwrap();
+        let now = date("2024-07-01T00:00:00Z");
+        let cases = [
+            (ConsentBuilder::at("2024-03-10T00:00:00Z").country("DE").build(), mathswe, None),
+            (
+                ConsentBuilder::at("2024-01-01T00:00:00Z").country("FR").build(),
+                mathswe,
+                Some(RepromptReason::Expired),
+            ),
+            (
+                ConsentBuilder::at("2024-03-10T00:00:00Z")
+                    .policy_version("2024-01-01")
+                    .country("DE")
+                    .build(),
+                mathswe,
+                Some(RepromptReason::OutdatedPolicy),
+            ),
+        ];
+
+        cases
+            .iter()
+            .for_each(|(consent, config, reason)| {
+                let status = ConsentStatus::of(consent, config, now);
+
+                assert_eq!(*reason, status.reprompt_reason, "{:?}", consent);
+                assert_eq!(reason.is_none(), status.valid);
+            });
+    }
+
+    fn geolocation(country: Option<&str>) -> Geolocation {
+        Geolocation::new(
+            chrono_tz::Tz::UTC,
+            country.map(str::to_string),
+            None,
+            None,
+            None,
+        )
+    }
+
+    fn date(date: &str) -> DateTime<Utc> {
+        date.parse().unwrap()
+    }
+}
@@ -0,0 +1,195 @@
+// Copyright (c) 2024 Tobias Briones. All rights reserved.
+// This file is part of https://github.com/mathswe/legal
+
+use std::fmt::Display;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use nanoid::nanoid;
+use serde::Serialize;
+#[cfg(target_arch = "wasm32")]
+use worker::{console_error, console_log, console_warn};
+use worker::Request;
+
+use crate::consent::Domain;
+
+#[derive(PartialEq, Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Info,
+    Warn,
+    Error,
+}
+
+/// Defines the request a log line belongs to, so the lines of a request can be correlated in
+/// the Workers logs. Its `request_id` is the `cf-ray` of the request, or a generated ID if the
+/// request has none. It only keeps the method, path and `Domain` of the request, so the user
+/// agent and IP of the client never reach the logs.
+#[derive(PartialEq, Clone, Debug)]
+pub struct RequestLog {
+    request_id: String,
+    method: String,
+    path: String,
+    domain: Option<Domain>,
+}
+
+impl RequestLog {
+    pub fn new(
+        request_id: Option<String>,
+        method: impl Into<String>,
+        path: impl Into<String>,
+    ) -> Self {
+        RequestLog {
+            request_id: request_id
+                .filter(|id| !id.is_empty())
+                .unwrap_or_else(|| nanoid!()),
+            method: method.into(),
+            path: path.into(),
+            domain: None,
+        }
+    }
+
+    pub fn from_req(req: &Request) -> Self {
+        let ray = req.headers().get("cf-ray").unwrap_or(None);
+
+        Self::new(ray, req.method().to_string(), req.path())
+    }
+
+    /// Returns this log with the `Domain` the request comes from.
+    pub fn with_domain(self, domain: &Domain) -> Self {
+        RequestLog { domain: Some(domain.clone()), ..self }
+    }
+
+    pub fn request_id(&self) -> &str {
+        &self.request_id
+    }
+
+    pub fn info(&self, event: &str) {
+        self.entry(LogLevel::Info, event, None::<String>).write()
+    }
+
+    pub fn warn(&self, event: &str, error: impl Display) {
+        self.entry(LogLevel::Warn, event, Some(error)).write()
+    }
+
+    pub fn error(&self, event: &str, error: impl Display) {
+        self.entry(LogLevel::Error, event, Some(error)).write()
+    }
+
+    fn entry(&self, level: LogLevel, event: &str, error: Option<impl Display>) -> LogEntry {
+        LogEntry {
+            time: Utc::now(),
+            level,
+            event: event.to_string(),
+            request_id: self.request_id.clone(),
+            method: self.method.clone(),
+            path: self.path.clone(),
+            domain: self.domain.clone(),
+            error: error.map(|error| error.to_string()),
+        }
+    }
+}
+
+/// Defines a log line, which is written as a JSON object, so the Workers logs can be filtered
+/// by its fields. Its `event` is a stable snake case name, like `consent_store_failed`, that
+/// tells the failures apart.
+#[derive(PartialEq, Clone, Debug, Serialize)]
+pub struct LogEntry {
+    #[serde(serialize_with = "serialize_time")]
+    time: DateTime<Utc>,
+    level: LogLevel,
+    event: String,
+    request_id: String,
+    method: String,
+    path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    domain: Option<Domain>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl LogEntry {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn write(&self) {
+        match self.level {
+            LogLevel::Info => console_log!("{}", self.to_json()),
+            LogLevel::Warn => console_warn!("{}", self.to_json()),
+            LogLevel::Error => console_error!("{}", self.to_json()),
+        }
+    }
+
+    /// Writes the entry to the standard error outside the Workers runtime, like in the local
+    /// server and the tests, since there's no JavaScript console.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn write(&self) {
+        eprintln!("{}", self.to_json());
+    }
+}
+
+fn serialize_time<S: serde::Serializer>(
+    time: &DateTime<Utc>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&time.to_rfc3339_opts(SecondsFormat::Millis, true))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::consent::Domain;
+    use crate::log::{LogLevel, RequestLog};
+
+    #[test]
+    fn writes_json_entries() {
+        let log = RequestLog::new(Some("8a1b2c3d4e5f6789-FRA".to_string()), "POST", "/")
+            .with_domain(&Domain::new("mathswe.com"));
+        let entry = serde_json::to_value(
+            log.entry(LogLevel::Error, "consent_store_failed", Some("KV unavailable"))
+        ).unwrap();
+
+        assert_eq!("error", entry["level"]);
+        assert_eq!("consent_store_failed", entry["event"]);
+        assert_eq!("8a1b2c3d4e5f6789-FRA", entry["request_id"]);
+        assert_eq!("POST", entry["method"]);
+        assert_eq!("/", entry["path"]);
+        assert_eq!("mathswe.com", entry["domain"]);
+        assert_eq!("KV unavailable", entry["error"]);
+        assert!(entry["time"].as_str().unwrap().ends_with('Z'));
+    }
+
+    #[test]
+    fn omits_absent_fields() {
+        let log = RequestLog::new(None, "GET", "/abc");
+        let entry = serde_json::to_value(log.entry(LogLevel::Info, "request", None::<String>))
+            .unwrap();
+        let mut fields = entry
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        fields.sort();
+
+        assert_eq!(
+            vec!["event", "level", "method", "path", "request_id", "time"],
+            fields,
+            "log entries only have the request fields and never the client user agent or IP"
+        );
+    }
+
+    #[test]
+    fn generates_request_id_without_ray() {
+        let ids = [
+            RequestLog::new(None, "GET", "/").request_id().to_string(),
+            RequestLog::new(Some("".to_string()), "GET", "/").request_id().to_string(),
+        ];
+
+        ids
+            .iter()
+            .for_each(|id| assert_eq!(21, id.len(), "{} is a generated ID", id));
+        assert_ne!(ids[0], ids[1]);
+    }
+}
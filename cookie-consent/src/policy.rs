@@ -0,0 +1,136 @@
+// Copyright (c) 2024 Tobias Briones. All rights reserved.
+// This file is part of https://github.com/mathswe/legal
+
+use std::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+use crate::policy::PolicyStatus::{Active, Retired};
+
+/// Defines the version of the cookie policy and banner text a user saw when giving consent.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct PolicyVersion(String);
+
+impl PolicyVersion {
+    /// Validates the given raw version against the known cookie policy versions of a
+    /// `Domain`, so only active versions are accepted.
+    pub fn validate(version: String, policies: &[KnownPolicy]) -> Result<Self, PolicyError> {
+        match policies.iter().find(|policy| policy.version == version) {
+            Some(KnownPolicy { status: Active, .. }) => Ok(PolicyVersion(version)),
+            Some(KnownPolicy { status: Retired, .. }) => Err(PolicyError::Retired(version)),
+            None => Err(PolicyError::Unknown(version)),
+        }
+    }
+
+    /// Returns whether this version is still an active version of the given known cookie
+    /// policy versions, so a consent given under it doesn't have to be asked again.
+    pub fn is_active(&self, policies: &[KnownPolicy]) -> bool {
+        policies
+            .iter()
+            .any(|policy| policy.version == self.0 && policy.status == Active)
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Debug, Deserialize)]
+pub enum PolicyStatus {
+    Active,
+    Retired,
+}
+
+/// Defines a cookie policy version the server knows for a `Domain`. A new version has to be
+/// added to the domain configuration when its cookie policy or banner text changes, and the
+/// previous version has to be retired once users can no longer see it.
+#[derive(PartialEq, Clone, Debug, Deserialize)]
+pub struct KnownPolicy {
+    version: String,
+    status: PolicyStatus,
+}
+
+impl KnownPolicy {
+    pub fn active(version: impl Into<String>) -> Self {
+        KnownPolicy { version: version.into(), status: Active }
+    }
+
+    pub fn retired(version: impl Into<String>) -> Self {
+        KnownPolicy { version: version.into(), status: Retired }
+    }
+
+    pub fn status(&self) -> PolicyStatus {
+        self.status
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub enum PolicyError {
+    Unknown(String),
+    Retired(String),
+}
+
+impl Display for PolicyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PolicyError::Unknown(version) => write!(
+                f,
+                "Unknown cookie policy version: {}",
+                version
+            ),
+            PolicyError::Retired(version) => write!(
+                f,
+                "Retired cookie policy version: {}, the user must accept the current policy",
+                version
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::policy::{KnownPolicy, PolicyError, PolicyVersion};
+
+    fn policies() -> [KnownPolicy; 2] {
+        [KnownPolicy::retired("2024-01-01"), KnownPolicy::active("2024-03-10")]
+    }
+
+    #[test]
+    fn accepts_active_version() {
+        assert_eq!(
+            Ok(PolicyVersion("2024-03-10".to_string())),
+            PolicyVersion::validate("2024-03-10".to_string(), &policies())
+        );
+    }
+
+    #[test]
+    fn rejects_retired_version() {
+        assert_eq!(
+            Err(PolicyError::Retired("2024-01-01".to_string())),
+            PolicyVersion::validate("2024-01-01".to_string(), &policies())
+        );
+    }
+
+    #[test]
+    fn tells_active_versions() {
+        let versions = [("2024-03-10", true), ("2024-01-01", false), ("2023-12-31", false)];
+
+        versions
+            .iter()
+            .for_each(|(version, active)| assert_eq!(
+                *active,
+                PolicyVersion(version.to_string()).is_active(&policies()),
+                "{} is active: {}",
+                version,
+                active
+            ))
+    }
+
+    #[test]
+    fn rejects_unknown_version() {
+        let unknown_versions = ["", "2023-12-31", "2024-03-10 ", "latest"];
+
+        unknown_versions
+            .iter()
+            .for_each(|version| assert_eq!(
+                Err(PolicyError::Unknown(version.to_string())),
+                PolicyVersion::validate(version.to_string(), &policies())
+            ))
+    }
+}
@@ -0,0 +1,71 @@
+// Copyright (c) 2024 Tobias Briones. All rights reserved.
+// This file is part of https://github.com/mathswe/legal
+
+use serde::{Deserialize, Serialize};
+use worker::Request;
+
+/// Defines the privacy signals the browser sent along a consent, that is, Global Privacy
+/// Control from the `Sec-GPC` header and Do Not Track from the `DNT` header. Under CCPA/CPRA,
+/// the GPC signal is an opt-out of selling and sharing the user's personal data.
+#[derive(PartialEq, Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct PrivacySignals {
+    gpc: bool,
+    dnt: bool,
+}
+
+impl PrivacySignals {
+    pub fn new(gpc: bool, dnt: bool) -> Self {
+        PrivacySignals { gpc, dnt }
+    }
+
+    /// Returns the signals from the raw `Sec-GPC` and `DNT` header values, where a signal is
+    /// only present if its value is `1`.
+    pub fn from_headers(sec_gpc: Option<&str>, dnt: Option<&str>) -> Self {
+        fn is_enabled(value: Option<&str>) -> bool {
+            value.is_some_and(|value| value.trim() == "1")
+        }
+
+        PrivacySignals { gpc: is_enabled(sec_gpc), dnt: is_enabled(dnt) }
+    }
+
+    pub fn from_req(req: &Request) -> Self {
+        let header = |name| req.headers().get(name).unwrap_or(None);
+
+        Self::from_headers(header("Sec-GPC").as_deref(), header("DNT").as_deref())
+    }
+
+    pub fn gpc(&self) -> bool {
+        self.gpc
+    }
+
+    pub fn dnt(&self) -> bool {
+        self.dnt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::privacy_signal::PrivacySignals;
+
+    #[test]
+    fn reads_signals_from_headers() {
+        let header_cases = [
+            (None, None, PrivacySignals::new(false, false)),
+            (Some("1"), None, PrivacySignals::new(true, false)),
+            (None, Some("1"), PrivacySignals::new(false, true)),
+            (Some(" 1 "), Some("1"), PrivacySignals::new(true, true)),
+            (Some("0"), Some("0"), PrivacySignals::new(false, false)),
+            (Some(""), Some("true"), PrivacySignals::new(false, false)),
+        ];
+
+        header_cases
+            .iter()
+            .for_each(|(sec_gpc, dnt, expected)| assert_eq!(
+                *expected,
+                PrivacySignals::from_headers(*sec_gpc, *dnt),
+                "Sec-GPC: {:?}, DNT: {:?}",
+                sec_gpc,
+                dnt
+            ))
+    }
+}
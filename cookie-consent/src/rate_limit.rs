@@ -0,0 +1,230 @@
+// Copyright (c) 2024 Tobias Briones. All rights reserved.
+// This file is part of https://github.com/mathswe/legal
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::anonymous_ip::AnonymousIp;
+use crate::consent::Domain;
+use crate::store::StoreError;
+
+/// Prefix of the rate counter keys, so they don't collide with the consent IDs.
+pub const RATE_KEY_PREFIX: &str = "rate:";
+
+/// Defines the storage of the request counters of the rate limits, so the rate limits don't
+/// depend on a particular backend, like Workers KV.
+#[async_trait(?Send)]
+pub trait RateCounter {
+    /// Increments the counter of the given key and returns its new count. The counter can be
+    /// discarded after `ttl` seconds.
+    async fn increment(&self, key: &str, ttl: u64) -> Result<u64, StoreError>;
+}
+
+/// Keeps the request counters in memory, so they're lost once the counter is dropped.
+#[derive(Default)]
+pub struct MemoryRateCounter(RefCell<BTreeMap<String, u64>>);
+
+#[async_trait(?Send)]
+impl RateCounter for MemoryRateCounter {
+    async fn increment(&self, key: &str, _ttl: u64) -> Result<u64, StoreError> {
+        let mut counters = self.0.borrow_mut();
+        let count = counters.entry(key.to_string()).or_default();
+
+        *count += 1;
+        Ok(*count)
+    }
+}
+
+/// Defines the maximum number of consents a client can register in a window of
+/// `window_secs` seconds for a `Domain`, which is 20 consents per minute by default.
+#[derive(PartialEq, Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    max_requests: u64,
+    window_secs: u64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig { max_requests: 20, window_secs: 60 }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn new(max_requests: u64, window_secs: u64) -> Self {
+        RateLimitConfig { max_requests, window_secs }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_requests == 0 || self.window_secs == 0 {
+            Err("a rate limit without requests or window".to_string())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Defines the client a rate limit applies to, identified by its anonymous IP and user agent,
+/// so clients behind the same network are still told apart by their browser.
+#[derive(PartialEq, Clone, Debug)]
+pub struct RateClient {
+    domain: Domain,
+    anonymous_ip: Option<AnonymousIp>,
+    user_agent: String,
+}
+
+impl RateClient {
+    pub fn new(domain: Domain, anonymous_ip: Option<AnonymousIp>, user_agent: String) -> Self {
+        RateClient { domain, anonymous_ip, user_agent }
+    }
+
+    /// Returns the counter key of the client in the given window. The client is hashed, so
+    /// the counter keys don't store its IP and user agent.
+    fn key(&self, window: i64) -> String {
+        let mut hasher = Sha256::new();
+
+        hasher.update(self.anonymous_ip.as_ref().map(AnonymousIp::to_string).unwrap_or_default());
+        hasher.update([0]);
+        hasher.update(&self.user_agent);
+
+        let hash = hasher
+            .finalize()
+            .iter()
+            .take(16)
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+
+        format!("{}{}:{}:{}", RATE_KEY_PREFIX, self.domain, hash, window)
+    }
+}
+
+/// Defines whether a request is allowed by its rate limit, or the number of seconds the
+/// client has to wait to be allowed again.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum RateLimit {
+    Allowed,
+    Limited { retry_after: u64 },
+}
+
+/// Counts a request of the client in the current fixed window of the rate limit, and returns
+/// whether the request is allowed.
+pub async fn check_rate_limit(
+    counter: &impl RateCounter,
+    client: &RateClient,
+    config: &RateLimitConfig,
+    now: DateTime<Utc>,
+) -> Result<RateLimit, StoreError> {
+    let window_secs = config.window_secs as i64;
+    let window = now.timestamp().div_euclid(window_secs);
+    let count = counter.increment(&client.key(window), config.window_secs).await?;
+
+    if count <= config.max_requests {
+        Ok(RateLimit::Allowed)
+    } else {
+        let retry_after = (window + 1) * window_secs - now.timestamp();
+
+        Ok(RateLimit::Limited { retry_after: retry_after as u64 })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use chrono::{DateTime, Utc};
+    use futures::executor::block_on;
+
+    use crate::anonymous_ip::AnonymousIp;
+    use crate::consent::Domain;
+    use crate::rate_limit::{
+        check_rate_limit,
+        MemoryRateCounter,
+        RateClient,
+        RateLimit,
+        RateLimitConfig,
+    };
+
+    #[test]
+    fn limits_requests_per_window() {
+        let counter = MemoryRateCounter::default();
+        let config = RateLimitConfig::new(3, 60);
+        let client = dummy_client("1.1.1.1", "Mozilla/5.0");
+        let check = |now| block_on(check_rate_limit(&counter, &client, &config, now)).unwrap();
+
+        (0..3).for_each(|_| assert_eq!(RateLimit::Allowed, check(window_at(15))));
+
+        assert_eq!(RateLimit::Limited { retry_after: 45 }, check(window_at(15)));
+        assert_eq!(
+            RateLimit::Limited { retry_after: 1 },
+            check(window_at(59)),
+            "the limit lasts until the window ends"
+        );
+        assert_eq!(
+            RateLimit::Allowed,
+            check(window_at(60)),
+            "a new window starts a new count"
+        );
+    }
+
+    #[test]
+    fn counts_clients_apart() {
+        let counter = MemoryRateCounter::default();
+        let config = RateLimitConfig::new(1, 60);
+        let now = window_at(0);
+        let clients = [
+            dummy_client("1.1.1.1", "Mozilla/5.0"),
+            dummy_client("1.1.1.1", "curl/8.0"),
+            dummy_client("1.1.2.1", "Mozilla/5.0"),
+            RateClient::new(
+                Domain::new("math.software"),
+                Some(AnonymousIp::from_ipv4(Ipv4Addr::new(1, 1, 1, 1))),
+                "Mozilla/5.0".to_string(),
+            ),
+        ];
+
+        clients
+            .iter()
+            .for_each(|client| assert_eq!(
+                Ok(RateLimit::Allowed),
+                block_on(check_rate_limit(&counter, client, &config, now)),
+                "{:?} has its own count",
+                client
+            ));
+
+        assert_eq!(
+            Ok(RateLimit::Limited { retry_after: 60 }),
+            block_on(check_rate_limit(
+                &counter,
+                &dummy_client("1.1.1.200", "Mozilla/5.0"),
+                &config,
+                now,
+            )),
+            "clients are counted by their anonymous IP"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_config() {
+        assert!(RateLimitConfig::default().validate().is_ok());
+        assert!(RateLimitConfig::new(0, 60).validate().is_err());
+        assert!(RateLimitConfig::new(10, 0).validate().is_err());
+    }
+
+    fn dummy_client(ip: &str, user_agent: &str) -> RateClient {
+        RateClient::new(
+            Domain::new("mathswe.com"),
+            Some(AnonymousIp::from_ipv4(ip.parse().unwrap())),
+            user_agent.to_string(),
+        )
+    }
+
+    /// Returns the time `secs` seconds after the start of a one-minute window.
+    fn window_at(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_710_028_800 + secs, 0).unwrap()
+    }
+}
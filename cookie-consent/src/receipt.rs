@@ -0,0 +1,389 @@
+// Copyright (c) 2024 Tobias Briones. All rights reserved.
+// This file is part of https://github.com/mathswe/legal
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use worker::Env;
+
+use crate::consent::{ClientCookieConsent, Domain};
+
+const SIGNING_KEYS_SECRET: &str = "CONSENT_SIGNING_KEYS";
+
+const SIGNING_ALGORITHM: &str = "HS256";
+
+/// Minimum length in bytes of a signing key, so it's as long as the HMAC-SHA256 output.
+const MIN_KEY_LENGTH: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Defines the HMAC-SHA256 keys that sign the consent receipts by their key ID, so keys can
+/// be rotated. Receipts are signed with the active key, and verified with any known key, so
+/// a previous key has to be kept until the receipts it signed are no longer used.
+#[derive(Clone, Debug)]
+pub struct SigningKeys {
+    active_kid: String,
+    keys: BTreeMap<String, Vec<u8>>,
+}
+
+impl SigningKeys {
+    /// Reads the keys from the `CONSENT_SIGNING_KEYS` secret. It returns `None` if the secret
+    /// is absent, so receipts are not signed.
+    pub fn from_env(env: &Env) -> Result<Option<Self>, ReceiptError> {
+        match env.secret(SIGNING_KEYS_SECRET) {
+            Ok(secret) => Self::from_json(&secret.to_string()).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Parses the keys from a JSON like `{ "active_kid": "k1", "keys": { "k1": "..." } }`,
+    /// where every key is encoded in base64url and has at least 32 bytes.
+    pub fn from_json(json: &str) -> Result<Self, ReceiptError> {
+        #[derive(Deserialize)]
+        struct RawSigningKeys {
+            active_kid: String,
+            keys: BTreeMap<String, String>,
+        }
+
+        let raw = serde_json::from_str::<RawSigningKeys>(json)
+            .map_err(|e| ReceiptError::Invalid(format!("Invalid {}: {}", SIGNING_KEYS_SECRET, e)))?;
+        let mut keys = BTreeMap::new();
+
+        for (kid, key) in raw.keys {
+            let key = URL_SAFE_NO_PAD
+                .decode(key.trim_end_matches('='))
+                .ok()
+                .filter(|key| key.len() >= MIN_KEY_LENGTH)
+                .ok_or(ReceiptError::Invalid(format!("Invalid signing key: {}", kid)))?;
+
+            keys.insert(kid, key);
+        }
+
+        if !keys.contains_key(&raw.active_kid) {
+            let error = format!("Unknown active signing key: {}", raw.active_kid);
+
+            return Err(ReceiptError::Invalid(error));
+        }
+
+        Ok(SigningKeys { active_kid: raw.active_kid, keys })
+    }
+
+    /// Returns the receipt of the consent given to the `Domain`, that is, a JWS in compact
+    /// serialization whose payload is the consent with its domain, signed with the active key.
+    pub fn sign(&self, domain: &Domain, consent: &ClientCookieConsent) -> String {
+        let header = ReceiptHeader {
+            alg: SIGNING_ALGORITHM.to_string(),
+            kid: self.active_kid.clone(),
+        };
+        let claims = ReceiptClaims {
+            domain: domain.clone(),
+            consent: consent.clone().without_receipt(),
+        };
+        let signing_input = format!("{}.{}", encode_json(&header), encode_json(&claims));
+        let signature = self.mac(&self.active_kid, &signing_input).finalize().into_bytes();
+
+        format!("{}.{}", signing_input, URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    /// Returns the claims of the receipt if it was signed with a known key for the given
+    /// `Domain`, or any domain if it's not given, and it hasn't expired at `now`. It doesn't
+    /// read the store, so the consent might have been withdrawn or updated since.
+    pub fn verify(
+        &self,
+        receipt: &str,
+        domain: Option<&Domain>,
+        now: DateTime<Utc>,
+    ) -> Result<ReceiptClaims, ReceiptError> {
+        let invalid = |reason: &str| ReceiptError::Invalid(
+            format!("Invalid consent receipt: {}", reason)
+        );
+        let (signing_input, signature) = receipt
+            .rsplit_once('.')
+            .ok_or(invalid("malformed receipt"))?;
+        let (header, payload) = signing_input
+            .split_once('.')
+            .ok_or(invalid("malformed receipt"))?;
+        let header = decode_json::<ReceiptHeader>(header).ok_or(invalid("malformed header"))?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| invalid("malformed signature"))?;
+
+        if header.alg != SIGNING_ALGORITHM {
+            return Err(invalid("unsupported algorithm"));
+        }
+
+        if !self.keys.contains_key(&header.kid) {
+            return Err(invalid("unknown key ID"));
+        }
+
+        self
+            .mac(&header.kid, signing_input)
+            .verify_slice(&signature)
+            .map_err(|_| invalid("wrong signature"))?;
+
+        let claims = decode_json::<ReceiptClaims>(payload).ok_or(invalid("malformed payload"))?;
+
+        if domain.is_some_and(|domain| *domain != claims.domain) {
+            return Err(ReceiptError::OtherDomain(claims.domain));
+        }
+
+        match claims.consent.expires_at() {
+            Some(expires_at) if now > expires_at => Err(ReceiptError::Expired(expires_at)),
+            _ => Ok(claims),
+        }
+    }
+
+    fn mac(&self, kid: &str, signing_input: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.keys[kid])
+            .expect("HMAC accepts keys of any length");
+
+        mac.update(signing_input.as_bytes());
+        mac
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct ReceiptHeader {
+    alg: String,
+    kid: String,
+}
+
+/// Defines the signed payload of a receipt, that is, the consent and the `Domain` it was
+/// given to, so a receipt of a site is not accepted by another.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct ReceiptClaims {
+    domain: Domain,
+    #[serde(flatten)]
+    consent: ClientCookieConsent,
+}
+
+impl ReceiptClaims {
+    pub fn domain(&self) -> &Domain {
+        &self.domain
+    }
+
+    pub fn consent(&self) -> &ClientCookieConsent {
+        &self.consent
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+/// Defines the body to verify a consent receipt. Requests without an `Origin` can give the
+/// `domain` the receipt must be signed for.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct VerificationReq {
+    pub receipt: String,
+    #[serde(default)]
+    pub domain: Option<Domain>,
+}
+
+#[derive(PartialEq, Debug)]
+pub enum ReceiptError {
+    Invalid(String),
+    OtherDomain(Domain),
+    Expired(DateTime<Utc>),
+}
+
+impl Display for ReceiptError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReceiptError::Invalid(reason) => write!(f, "{}", reason),
+            ReceiptError::OtherDomain(domain) => {
+                write!(f, "Consent receipt was signed for another domain: {}", domain)
+            }
+            ReceiptError::Expired(expires_at) => {
+                write!(f, "Consent receipt expired at {}", expires_at.to_rfc3339())
+            }
+        }
+    }
+}
+
+fn encode_json<T: Serialize>(value: &T) -> String {
+    URL_SAFE_NO_PAD.encode(serde_json::to_vec(value).unwrap())
+}
+
+fn decode_json<T: for<'de> Deserialize<'de>>(encoded: &str) -> Option<T> {
+    URL_SAFE_NO_PAD
+        .decode(encoded)
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::Engine;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use chrono::{DateTime, TimeDelta, Utc};
+
+    use crate::consent::{ClientCookieConsent, CookieConsent, CookieConsentPref, Domain};
+    use crate::geolocation::Geolocation;
+    use crate::lifetime::LifetimeConfig;
+    use crate::policy::{KnownPolicy, PolicyVersion};
+    use crate::privacy_signal::PrivacySignals;
+    use crate::receipt::{ReceiptError, SigningKeys};
+
+    const SIGNING_KEYS_JSON: &str = r#"{
+        "active_kid": "2024-04",
+        "keys": {
+            "2024-03": "bWF0aHN3ZS1jb29raWUtY29uc2VudC1wcmV2aW91cy1rZXk",
+            "2024-04": "bWF0aHN3ZS1jb29raWUtY29uc2VudC1zaWduaW5nLWtleQ"
+        }
+    }"#;
+
+    #[test]
+    fn signs_and_verifies_consent() {
+        let keys = SigningKeys::from_json(SIGNING_KEYS_JSON).unwrap();
+        let domain = Domain::new("mathswe.com");
+        let consent = dummy_consent();
+        let receipt = keys.sign(&domain, &consent);
+        let header = receipt.split('.').next().unwrap();
+        let header_json = URL_SAFE_NO_PAD.decode(header).unwrap();
+        let claims = keys.verify(&receipt, Some(&domain), now()).unwrap();
+
+        assert_eq!(
+            r#"{"alg":"HS256","kid":"2024-04"}"#,
+            String::from_utf8(header_json).unwrap(),
+            "receipt is signed with the active key"
+        );
+        assert_eq!(&domain, claims.domain());
+        assert_eq!(&consent, claims.consent());
+        assert_eq!(
+            Ok(claims.clone()),
+            keys.verify(&receipt, None, now()),
+            "receipt is verified for any domain if none is given"
+        );
+        assert_eq!(
+            Ok(claims),
+            keys.verify(
+                &keys.sign(&domain, &consent.with_receipt(&domain, &keys)),
+                Some(&domain),
+                now(),
+            ),
+            "signed consents are signed without their receipt"
+        );
+    }
+
+    #[test]
+    fn verifies_receipts_of_rotated_keys() {
+        let previous_keys = SigningKeys::from_json(&SIGNING_KEYS_JSON.replace(
+            r#""active_kid": "2024-04""#,
+            r#""active_kid": "2024-03""#,
+        )).unwrap();
+        let keys = SigningKeys::from_json(SIGNING_KEYS_JSON).unwrap();
+        let domain = Domain::new("mathswe.com");
+        let consent = dummy_consent();
+        let claims = keys.verify(&previous_keys.sign(&domain, &consent), Some(&domain), now());
+
+        assert_eq!(Ok(&consent), claims.as_ref().map(|claims| claims.consent()));
+    }
+
+    #[test]
+    fn rejects_tampered_receipts() {
+        let keys = SigningKeys::from_json(SIGNING_KEYS_JSON).unwrap();
+        let domain = Domain::new("mathswe.com");
+        let receipt = keys.sign(&domain, &dummy_consent());
+        let parts = receipt.split('.').collect::<Vec<_>>();
+        let payload = String::from_utf8(URL_SAFE_NO_PAD.decode(parts[1]).unwrap()).unwrap();
+        let tampered_payload = URL_SAFE_NO_PAD
+            .encode(payload.replace(r#""targeting":false"#, r#""targeting":true"#));
+        let unknown_kid_header = URL_SAFE_NO_PAD.encode(r#"{"alg":"HS256","kid":"unknown"}"#);
+        let none_header = URL_SAFE_NO_PAD.encode(r#"{"alg":"none","kid":"2024-04"}"#);
+        let other_keys = SigningKeys::from_json(r#"{
+            "active_kid": "2024-04",
+            "keys": { "2024-04": "b3RoZXItbWF0aHN3ZS1jb29raWUtY29uc2VudC1zaWduaW5nLWtleQ" }
+        }"#).unwrap();
+        let invalid_receipts = [
+            "".to_string(),
+            "abc".to_string(),
+            format!("{}.{}", parts[0], parts[1]),
+            format!("{}.{}.{}", parts[0], tampered_payload, parts[2]),
+            format!("{}.{}.{}", unknown_kid_header, parts[1], parts[2]),
+            format!("{}.{}.", none_header, parts[1]),
+            format!("{}.{}.{}", parts[0], parts[1], &parts[2][1..]),
+            other_keys.sign(&domain, &dummy_consent()),
+        ];
+
+        invalid_receipts
+            .iter()
+            .for_each(|receipt| assert!(
+                matches!(
+                    keys.verify(receipt, Some(&domain), now()),
+                    Err(ReceiptError::Invalid(_))
+                ),
+                "{} is not a valid receipt",
+                receipt
+            ))
+    }
+
+    #[test]
+    fn rejects_receipts_of_other_domains() {
+        let keys = SigningKeys::from_json(SIGNING_KEYS_JSON).unwrap();
+        let receipt = keys.sign(&Domain::new("mathswe.com"), &dummy_consent());
+
+        assert_eq!(
+            Err(ReceiptError::OtherDomain(Domain::new("mathswe.com"))),
+            keys.verify(&receipt, Some(&Domain::new("math.software")), now())
+        );
+    }
+
+    #[test]
+    fn rejects_expired_receipts() {
+        let keys = SigningKeys::from_json(SIGNING_KEYS_JSON).unwrap();
+        let domain = Domain::new("mathswe.com");
+        let consent = dummy_consent();
+        let expires_at = consent.expires_at().unwrap();
+        let receipt = keys.sign(&domain, &consent);
+
+        assert!(keys.verify(&receipt, Some(&domain), expires_at).is_ok());
+        assert_eq!(
+            Err(ReceiptError::Expired(expires_at)),
+            keys.verify(&receipt, Some(&domain), expires_at + TimeDelta::try_seconds(1).unwrap())
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_signing_keys() {
+        let invalid_keys = [
+            "",
+            r#"{ "active_kid": "k1", "keys": {} }"#,
+            r#"{ "active_kid": "k1", "keys": { "k1": "c2hvcnQta2V5" } }"#,
+            r#"{ "active_kid": "k1", "keys": { "k1": "not base64!" } }"#,
+        ];
+
+        invalid_keys
+            .iter()
+            .for_each(|json| assert!(
+                SigningKeys::from_json(json).is_err(),
+                "{} are not valid signing keys",
+                json
+            ))
+    }
+
+    fn dummy_consent() -> ClientCookieConsent {
+        let consent = CookieConsent::new(
+            Domain::new("mathswe.com"),
+            CookieConsentPref::essential_only(),
+            PolicyVersion::validate("2024-03-10".to_string(), &[KnownPolicy::active("2024-03-10")])
+                .unwrap(),
+            Geolocation::empty_with(chrono_tz::Tz::UTC),
+            None,
+            "Mozilla/5.0".to_string(),
+            PrivacySignals::default(),
+        );
+
+        ClientCookieConsent::from(&consent).with_expiration(&LifetimeConfig::default())
+    }
+
+    fn now() -> DateTime<Utc> {
+        Utc::now()
+    }
+}
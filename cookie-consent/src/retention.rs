@@ -0,0 +1,267 @@
+// Copyright (c) 2024 Tobias Briones. All rights reserved.
+// This file is part of https://github.com/mathswe/legal
+
+use chrono::{DateTime, Days, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::config::DomainsConfig;
+use crate::consent::CookieConsent;
+use crate::store::{ConsentStore, StoreError};
+
+/// Maximum number of consents a retention run processes, so a run doesn't exceed the
+/// subrequests of a Worker request.
+pub const RETENTION_BATCH_SIZE: u64 = 100;
+
+/// Defines how many days each class of data of a consent is retained for a `Domain`. The
+/// whole consent record is retained for `consent_days`, three years by default, while its
+/// personal data, that is, the anonymous IP, user agent, and city, are retained for
+/// `personal_data_days`, one year by default.
+#[derive(PartialEq, Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    consent_days: u32,
+    personal_data_days: u32,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig { consent_days: 1095, personal_data_days: 365 }
+    }
+}
+
+impl RetentionConfig {
+    pub fn new(consent_days: u32, personal_data_days: u32) -> Self {
+        RetentionConfig { consent_days, personal_data_days }
+    }
+
+    pub fn consent_days(&self) -> u32 {
+        self.consent_days
+    }
+
+    /// Validates that the periods aren't empty, and the personal data isn't retained longer
+    /// than the consent record that has it.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.consent_days == 0 || self.personal_data_days == 0 {
+            Err("an empty retention period".to_string())
+        } else if self.personal_data_days > self.consent_days {
+            Err("a personal data retention longer than the consent retention".to_string())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns when a consent created at the given time has to be deleted.
+    pub fn consent_expiration(&self, created_at: DateTime<Utc>) -> DateTime<Utc> {
+        created_at + Days::new(self.consent_days.into())
+    }
+
+    /// Returns when the personal data of a consent created at the given time has to be
+    /// removed.
+    pub fn personal_data_expiration(&self, created_at: DateTime<Utc>) -> DateTime<Utc> {
+        created_at + Days::new(self.personal_data_days.into())
+    }
+}
+
+/// Defines the result of applying the retention rules to a page of stored consents. Its
+/// `cursor` is `None` once all the consents were processed.
+#[derive(PartialEq, Clone, Debug, Default, Serialize)]
+pub struct RetentionReport {
+    pub processed: u64,
+    pub deleted: u64,
+    pub redacted: u64,
+    pub rewritten: u64,
+    pub cursor: Option<String>,
+}
+
+/// Applies the retention rules of their `Domain` to a page of at most `limit` stored consents,
+/// starting after the given `cursor`, so consents stored before the rules or under previous
+/// rules also comply. Expired consents are deleted, consents past their personal data
+/// retention are redacted, and consents whose expiration doesn't match the rules are
+/// rewritten, so their store expiration is updated. Consents of domains no longer configured
+/// follow the default rules.
+pub async fn apply_retention(
+    store: &impl ConsentStore,
+    domains: &DomainsConfig,
+    now: DateTime<Utc>,
+    cursor: Option<String>,
+    limit: u64,
+) -> Result<RetentionReport, StoreError> {
+    let page = store.list(cursor, limit).await?;
+    let mut report = RetentionReport { cursor: page.cursor, ..RetentionReport::default() };
+
+    for id in page.ids {
+        let Some(consent) = store.get(&id).await? else {
+            continue;
+        };
+        let (_, value) = consent.to_kv();
+        let retention = domains
+            .get(value.domain())
+            .map(|config| config.retention().clone())
+            .unwrap_or_default();
+
+        report.processed += 1;
+
+        if now >= retention.consent_expiration(value.created_at()) {
+            store.delete(&id).await?;
+            report.deleted += 1;
+            continue;
+        }
+
+        let must_redact = !value.personal_data_redacted()
+            && now >= retention.personal_data_expiration(value.created_at());
+        let retained = CookieConsent::from_kv(id, value.clone()).retained_for(&retention);
+        let updated = if must_redact { retained.redact_personal_data() } else { retained };
+
+        if updated != consent {
+            store.put(&updated).await?;
+            report.rewritten += 1;
+        }
+
+        if must_redact {
+            report.redacted += 1;
+        }
+    }
+
+    Ok(report)
+}
+
+/// Applies the retention rules to every stored consent, batch by batch of at most `limit`
+/// consents, so the scheduled runs comply without anyone paging through `apply_retention`.
+/// It returns the report of all the batches.
+pub async fn apply_retention_to_all(
+    store: &impl ConsentStore,
+    domains: &DomainsConfig,
+    now: DateTime<Utc>,
+    limit: u64,
+) -> Result<RetentionReport, StoreError> {
+    let mut total = RetentionReport::default();
+    let mut cursor = None;
+
+    loop {
+        let report = apply_retention(store, domains, now, cursor, limit).await?;
+
+        total.processed += report.processed;
+        total.deleted += report.deleted;
+        total.redacted += report.redacted;
+        total.rewritten += report.rewritten;
+
+        match report.cursor {
+            Some(next_cursor) => cursor = Some(next_cursor),
+            None => return Ok(total),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+    use futures::executor::block_on;
+
+    use crate::config::DomainsConfig;
+    use crate::consent::CookieConsent;
+    use crate::retention::{
+        apply_retention,
+        apply_retention_to_all,
+        RetentionConfig,
+        RetentionReport,
+    };
+    use crate::store::{ConsentBuilder, ConsentStore, MemoryConsentStore};
+
+    const CONFIG_JSON: &str = r#"[
+        {
+            "domain": "mathswe.com",
+            "policies": [{ "version": "2024-03-10", "status": "Active" }],
+            "retention": { "consent_days": 60, "personal_data_days": 30 },
+            "lifetime": { "default_days": 30 }
+        }
+    ]"#;
+
+    #[test]
+    fn computes_expirations_per_data_class() {
+        let retention = RetentionConfig::new(60, 30);
+        let created_at = date("2024-03-10T12:00:00Z");
+
+        assert_eq!(date("2024-05-09T12:00:00Z"), retention.consent_expiration(created_at));
+        assert_eq!(date("2024-04-09T12:00:00Z"), retention.personal_data_expiration(created_at));
+    }
+
+    #[test]
+    fn rejects_invalid_config() {
+        assert!(RetentionConfig::default().validate().is_ok());
+        assert!(RetentionConfig::new(0, 0).validate().is_err());
+        assert!(RetentionConfig::new(30, 60).validate().is_err());
+    }
+
+    #[test]
+    fn applies_retention_to_stored_consents() {
+        let store = MemoryConsentStore::default();
+        let domains = DomainsConfig::from_json(CONFIG_JSON).unwrap();
+        let consent_at = |created_at| ConsentBuilder::at(created_at)
+            .country("DE")
+            .city("Berlin")
+            .build();
+        let expired = consent_at("2024-01-01T00:00:00Z");
+        let old = consent_at("2024-02-15T00:00:00Z");
+        let recent = consent_at("2024-03-05T00:00:00Z");
+        let now = date("2024-03-20T00:00:00Z");
+
+        [&expired, &old, &recent]
+            .iter()
+            .for_each(|consent| block_on(store.put(consent)).unwrap());
+
+        let report = block_on(apply_retention(&store, &domains, now, None, 10)).unwrap();
+        let stored = |consent: &CookieConsent| {
+            let (id, _) = consent.to_kv();
+
+            block_on(store.get(&id)).unwrap().map(|consent| consent.to_kv().1)
+        };
+        let old_value = stored(&old).unwrap();
+        let recent_value = stored(&recent).unwrap();
+
+        assert_eq!(
+            RetentionReport { processed: 3, deleted: 1, redacted: 1, rewritten: 2, cursor: None },
+            report
+        );
+        assert_eq!(None, stored(&expired), "expired consents are deleted");
+        assert!(old_value.personal_data_redacted());
+        assert_eq!("", serde_json::to_value(&old_value).unwrap()["user_agent"]);
+        assert_eq!(None, old_value.geolocation().city());
+        assert_eq!(Some(&"DE".to_string()), old_value.geolocation().country());
+        assert!(!recent_value.personal_data_redacted());
+        assert_eq!(Some(date("2024-05-04T00:00:00Z")), recent_value.retained_until());
+        assert_eq!(
+            RetentionReport { processed: 2, ..RetentionReport::default() },
+            block_on(apply_retention(&store, &domains, now, None, 10)).unwrap(),
+            "compliant consents aren't rewritten"
+        );
+    }
+
+    #[test]
+    fn applies_retention_to_all_batches() {
+        let store = MemoryConsentStore::default();
+        let domains = DomainsConfig::from_json(CONFIG_JSON).unwrap();
+        let consents = [
+            ConsentBuilder::at("2024-01-01T00:00:00Z").build(),
+            ConsentBuilder::at("2024-02-15T00:00:00Z").city("Berlin").build(),
+            ConsentBuilder::at("2024-03-05T00:00:00Z").build(),
+        ];
+        let now = date("2024-03-20T00:00:00Z");
+
+        consents
+            .iter()
+            .for_each(|consent| block_on(store.put(consent)).unwrap());
+
+        assert_eq!(
+            RetentionReport { processed: 3, deleted: 1, redacted: 1, rewritten: 2, cursor: None },
+            block_on(apply_retention_to_all(&store, &domains, now, 1)).unwrap()
+        );
+        assert_eq!(
+            RetentionReport { processed: 2, ..RetentionReport::default() },
+            block_on(apply_retention_to_all(&store, &domains, now, 1)).unwrap()
+        );
+    }
+
+    fn date(date: &str) -> DateTime<Utc> {
+        date.parse().unwrap()
+    }
+}
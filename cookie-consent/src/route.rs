@@ -0,0 +1,219 @@
+// Copyright (c) 2024 Tobias Briones. All rights reserved.
+// This file is part of https://github.com/mathswe/legal
+
+use worker::Method;
+
+use crate::api_error::ApiError;
+
+/// Defines the routes the service serves, so the Worker router and the local server serve
+/// the same routes with the same matching rules.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Route {
+    PostConsent,
+    PostVerification,
+    GetConsents,
+    PostRetention,
+    GetStats,
+    GetConsent,
+    PostWithdrawal,
+    PostUpdate,
+    GetHistory,
+    GetReceipt,
+    GetStatus,
+}
+
+/// Routes of the service, where the routes of static paths go before the ones with parameters,
+/// so a path matches its static route first.
+pub const ROUTES: [Route; 11] = [
+    Route::PostConsent,
+    Route::PostVerification,
+    Route::GetConsents,
+    Route::PostRetention,
+    Route::GetStats,
+    Route::GetConsent,
+    Route::PostWithdrawal,
+    Route::PostUpdate,
+    Route::GetHistory,
+    Route::GetReceipt,
+    Route::GetStatus,
+];
+
+impl Route {
+    pub fn method(self) -> Method {
+        match self {
+            Route::PostConsent
+            | Route::PostVerification
+            | Route::PostRetention
+            | Route::PostWithdrawal
+            | Route::PostUpdate => Method::Post,
+            Route::GetConsents
+            | Route::GetStats
+            | Route::GetConsent
+            | Route::GetHistory
+            | Route::GetReceipt
+            | Route::GetStatus => Method::Get,
+        }
+    }
+
+    /// Returns the path pattern of the route, whose `:name` segments match any non-empty
+    /// segment.
+    pub fn pattern(self) -> &'static str {
+        match self {
+            Route::PostConsent => "/",
+            Route::PostVerification => "/verify",
+            Route::GetConsents => "/admin/consents",
+            Route::PostRetention => "/admin/retention",
+            Route::GetStats => "/admin/stats",
+            Route::GetConsent => "/:id",
+            Route::PostWithdrawal => "/:id/withdraw",
+            Route::PostUpdate => "/:id/update",
+            Route::GetHistory => "/:id/history",
+            Route::GetReceipt => "/:id/receipt",
+            Route::GetStatus => "/:id/status",
+        }
+    }
+
+    pub fn matches(self, path: &str) -> bool {
+        matches_pattern(self.pattern(), path)
+    }
+
+    /// Returns the value of the `:name` segment of the path if the path matches the route.
+    pub fn param(self, path: &str, name: &str) -> Option<String> {
+        if !self.matches(path) {
+            return None;
+        }
+
+        self
+            .pattern()
+            .split('/')
+            .zip(path.split('/'))
+            .find(|(pattern, _)| pattern.strip_prefix(':') == Some(name))
+            .map(|(_, segment)| segment.to_string())
+    }
+}
+
+/// Returns each method of the routes that match the path once, in the order of the routes.
+pub fn route_methods(routes: &[Route], path: &str) -> Vec<Method> {
+    routes
+        .iter()
+        .filter(|route| route.matches(path))
+        .fold(vec![], |mut methods, route| {
+            if !methods.contains(&route.method()) {
+                methods.push(route.method());
+            }
+            methods
+        })
+}
+
+/// Returns the first route that matches the method and path, or `NotFound` if no route
+/// matches the path, or `MethodNotAllowed` with the methods of the routes that match it.
+pub fn find_route(routes: &[Route], method: &Method, path: &str) -> Result<Route, ApiError> {
+    let found = routes
+        .iter()
+        .find(|route| route.method() == *method && route.matches(path));
+
+    match found {
+        Some(route) => Ok(*route),
+        None => {
+            let methods = route_methods(routes, path);
+
+            if methods.is_empty() {
+                Err(ApiError::NotFound)
+            } else {
+                let allow = methods.iter().map(Method::to_string).collect();
+
+                Err(ApiError::MethodNotAllowed { allow })
+            }
+        }
+    }
+}
+
+fn matches_pattern(pattern: &str, path: &str) -> bool {
+    let pattern_segments = pattern.split('/').collect::<Vec<_>>();
+    let path_segments = path.split('/').collect::<Vec<_>>();
+
+    pattern_segments.len() == path_segments.len() && pattern_segments
+        .iter()
+        .zip(path_segments)
+        .all(|(pattern, segment)| match pattern.strip_prefix(':') {
+            Some(_) => !segment.is_empty(),
+            None => *pattern == segment,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use worker::Method;
+
+    use crate::api_error::ApiError;
+    use crate::route::{find_route, Route, route_methods, ROUTES};
+
+    #[test]
+    fn finds_routes_of_requests() {
+        let cases = [
+            (Method::Post, "/", Ok(Route::PostConsent)),
+            (Method::Post, "/verify", Ok(Route::PostVerification)),
+            (Method::Get, "/admin/consents", Ok(Route::GetConsents)),
+            (Method::Post, "/admin/retention", Ok(Route::PostRetention)),
+            (Method::Get, "/admin/stats", Ok(Route::GetStats)),
+            (Method::Get, "/verify", Ok(Route::GetConsent)),
+            (Method::Get, "/abc", Ok(Route::GetConsent)),
+            (Method::Post, "/abc/withdraw", Ok(Route::PostWithdrawal)),
+            (Method::Post, "/abc/update", Ok(Route::PostUpdate)),
+            (Method::Get, "/abc/history", Ok(Route::GetHistory)),
+            (Method::Get, "/abc/receipt", Ok(Route::GetReceipt)),
+            (Method::Get, "/abc/status", Ok(Route::GetStatus)),
+            (Method::Get, "/abc/unknown", Err(ApiError::NotFound)),
+            (Method::Get, "/abc/withdraw/more", Err(ApiError::NotFound)),
+            (Method::Get, "//abc", Err(ApiError::NotFound)),
+            (Method::Post, "//withdraw", Err(ApiError::NotFound)),
+            (Method::Get, "/", Err(not_allowed(&["POST"]))),
+            (Method::Delete, "/abc", Err(not_allowed(&["GET"]))),
+            (Method::Post, "/abc/history", Err(not_allowed(&["GET"]))),
+            (Method::Put, "/verify", Err(not_allowed(&["POST", "GET"]))),
+        ];
+
+        cases
+            .iter()
+            .for_each(|(method, path, result)| assert_eq!(
+                *result,
+                find_route(&ROUTES, method, path),
+                "{} {}",
+                method.to_string(),
+                path
+            ));
+    }
+
+    #[test]
+    fn collects_methods_of_each_path() {
+        let cases = [
+            ("/", vec![Method::Post]),
+            ("/verify", vec![Method::Post, Method::Get]),
+            ("/abc", vec![Method::Get]),
+            ("/abc/withdraw", vec![Method::Post]),
+            ("/abc/history", vec![Method::Get]),
+            ("/abc/unknown", vec![]),
+        ];
+
+        cases
+            .iter()
+            .for_each(|(path, methods)| assert_eq!(
+                *methods,
+                route_methods(&ROUTES, path),
+                "preflight of {} allows each method of its routes once",
+                path
+            ));
+    }
+
+    #[test]
+    fn reads_route_params() {
+        assert_eq!(Some("abc".to_string()), Route::GetConsent.param("/abc", "id"));
+        assert_eq!(Some("abc".to_string()), Route::PostUpdate.param("/abc/update", "id"));
+        assert_eq!(None, Route::PostUpdate.param("/abc/withdraw", "id"));
+        assert_eq!(None, Route::PostConsent.param("/", "id"));
+    }
+
+    fn not_allowed(methods: &[&str]) -> ApiError {
+        ApiError::MethodNotAllowed { allow: methods.iter().map(|m| m.to_string()).collect() }
+    }
+}
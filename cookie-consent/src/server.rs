@@ -1,30 +1,57 @@
 // Copyright (c) 2024 Tobias Briones. All rights reserved.
 // This file is part of https://github.com/mathswe/legal
 
-use std::fmt::Display;
-use worker::{console_log, Cors, Error, Method, Request, Response, RouteContext};
+use std::future::Future;
+
+use worker::{Cors, Env, Error, Method, Request, Response, RouteContext, Router};
+
+use crate::anonymous_ip::DEFAULT_IPV6_PREFIX_LENGTH;
+use crate::api_error::ApiError;
 use crate::client_req::Origin;
+use crate::config::{DomainConfig, DomainsConfig};
 use crate::consent::Domain;
-use crate::consent::Domain::MathSweCom;
+use crate::log::RequestLog;
+use crate::route::{find_route, Route, route_methods};
 
 /// Defines an `Origin` managed by the server by wrapping the actual `Origin` and defining
 /// operations to allow development mode, in which there is no origin at all. If a `OriginProxy`
 /// value exists is because its `Origin` is valid. Its `Origin` is `None` when the request comes
-/// from local mode.
+/// from local mode. It also keeps the configuration of the `Domain` the request comes from.
 #[derive(Clone)]
-pub struct OriginProxy(Option<Origin>);
+pub struct OriginProxy {
+    origin: Option<Origin>,
+    config: DomainConfig,
+}
 
 impl OriginProxy {
-    pub fn from_req(req: &Request, ctx: &RouteContext<()>) -> Result<Option<OriginProxy>, Error> {
-        let origin_option = Origin::from_req(req)?;
+    pub fn from_req<S>(
+        req: &Request,
+        ctx: &RouteContext<ServiceData<S>>,
+    ) -> Result<Option<OriginProxy>, Error> {
+        Self::from_domains(req, ctx.data.domains(), &ctx.env)
+    }
+
+    fn from_domains(
+        req: &Request,
+        domains_config: &DomainsConfig,
+        env: &Env,
+    ) -> Result<Option<OriginProxy>, Error> {
+        let origin_option = Origin::from_req(req, domains_config)?;
 
         match origin_option {
-            Some(origin) => Ok(Some(OriginProxy(Some(origin)))),
+            Some(origin) => Ok(
+                domains_config
+                    .get(&origin.clone().domain())
+                    .cloned()
+                    .map(|config| OriginProxy { origin: Some(origin), config })
+            ),
             None => {
-                let is_local_mode = is_local_dev_mode(&ctx)?;
+                let is_local_mode = is_local_dev_mode(env)?;
 
                 if is_local_mode {
-                    Ok(Some(OriginProxy(None)))
+                    let config = domains_config.default_domain().clone();
+
+                    Ok(Some(OriginProxy { origin: None, config }))
                 } else {
                     Ok(None)
                 }
@@ -33,12 +60,13 @@ impl OriginProxy {
     }
 
     /// Returns the `Domain` of `Origin`. If there's no `Origin`, local mode is assumed and
-    /// returns `MathSweCom` by default.
+    /// returns the first configured `Domain` by default.
     pub fn domain(self) -> Domain {
-        self
-            .0
-            .map(Origin::domain)
-            .unwrap_or(MathSweCom)
+        self.config.domain().clone()
+    }
+
+    pub fn config(&self) -> &DomainConfig {
+        &self.config
     }
 
     /// It handles CORS for the underlying `Origin` on the given `Response`. If this
@@ -46,24 +74,143 @@ impl OriginProxy {
     /// `Response` is returned without modifications.
     pub fn handle_cors(self, mut res: Response) -> Result<Response, Error> {
         self
-            .0
+            .origin
             .map(|origin| cors(res.cloned()?, origin))
             .unwrap_or(Ok(res))
     }
+
+    /// It handles a CORS preflight for the underlying `Origin` on the given `Response` by
+    /// allowing the given methods. If this `ProxyOrigin` doesn't have an `Origin`, then local
+    /// mode is assumed and the same `Response` is returned without modifications.
+    pub fn handle_preflight(self, res: Response, methods: Vec<Method>) -> Result<Response, Error> {
+        match self.origin {
+            Some(origin) => preflight_cors(res, origin, methods),
+            None => Ok(res),
+        }
+    }
+}
+
+/// Defines the data of the routes, that is, the consent store and the allowed domains, which
+/// are read once per request.
+pub struct ServiceData<S> {
+    store: S,
+    domains: DomainsConfig,
+}
+
+impl<S> ServiceData<S> {
+    pub fn new(store: S, domains: DomainsConfig) -> Self {
+        ServiceData { store, domains }
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn domains(&self) -> &DomainsConfig {
+        &self.domains
+    }
+}
+
+/// Defines a `Router` that keeps the `Route`s it serves, so it answers the CORS preflight
+/// (`OPTIONS`) requests with the methods the preflighted path allows, as new routes are added,
+/// and answers the requests no route matches with problem details. It matches the requests as
+/// `find_route` does, like the local server.
+pub struct CorsRouter<'a, D> {
+    router: Router<'a, D>,
+    routes: Vec<Route>,
+}
+
+impl<'a, D: 'a> CorsRouter<'a, D> {
+    pub fn with_data(data: D) -> Self {
+        CorsRouter { router: Router::with_data(data), routes: vec![] }
+    }
+
+    /// Serves the route with the given handler. `OPTIONS` requests are the CORS preflights,
+    /// and Workers don't serve `CONNECT` nor `TRACE` requests, so no route has these methods.
+    pub fn route_async<T>(self, route: Route, func: fn(Request, RouteContext<D>) -> T) -> Self
+    where
+        T: Future<Output=Result<Response, Error>> + 'a,
+    {
+        let CorsRouter { router, mut routes } = self;
+        let pattern = route.pattern();
+        let router = match route.method() {
+            Method::Head => router.head_async(pattern, func),
+            Method::Get => router.get_async(pattern, func),
+            Method::Post => router.post_async(pattern, func),
+            Method::Put => router.put_async(pattern, func),
+            Method::Patch => router.patch_async(pattern, func),
+            Method::Delete => router.delete_async(pattern, func),
+            method @ (Method::Options | Method::Connect | Method::Trace) => {
+                unreachable!("No route serves {} requests", method.to_string())
+            }
+        };
+
+        routes.push(route);
+
+        CorsRouter { router, routes }
+    }
+
+    /// Answers the request if it's a CORS preflight of the given domains, or runs the matching
+    /// route otherwise. Preflight requests from invalid origins are forbidden, preflight
+    /// requests of paths no route matches are answered as `NotFound`, requests no route matches
+    /// are answered as `NotFound` or `MethodNotAllowed`, and errors the routes don't handle are
+    /// logged and answered as an `ApiError`.
+    pub async fn run(
+        self,
+        req: Request,
+        env: Env,
+        domains: &DomainsConfig,
+    ) -> Result<Response, Error> {
+        let log = RequestLog::from_req(&req);
+
+        if req.method() != Method::Options {
+            if let Err(e) = find_route(&self.routes, &req.method(), &req.path()) {
+                return e.to_response(&log);
+            }
+
+            return self
+                .router
+                .run(req, env)
+                .await
+                .or_else(|e| ApiError::internal("request_failed", e).to_response(&log));
+        }
+
+        let origin_option = OriginProxy::from_domains(&req, domains, &env)?;
+
+        if origin_option.is_none() {
+            return ApiError::ForbiddenOrigin.to_response(&log);
+        }
+
+        let origin = origin_option.unwrap();
+        let methods = route_methods(&self.routes, &req.path());
+
+        if methods.is_empty() {
+            return ApiError::NotFound.to_response(&log);
+        }
+
+        no_content().and_then(|res| origin.handle_preflight(res, methods))
+    }
 }
 
-pub fn forbidden() -> Result<Response, Error> {
+pub fn no_content() -> Result<Response, Error> {
     Response::empty()
-        .map(|res| res.with_status(403))
+        .map(|res| res.with_status(204))
 }
 
-pub fn internal_error(msg: impl Into<String>, error: impl Display) -> Result<Response, Error> {
-    console_log!("{}", format!("{}", error));
-    Response::error(msg, 500)
+/// Returns the network prefix length to keep from IPv6 addresses when anonymizing them. It's
+/// read from the `IPV6_PREFIX_LENGTH` variable and defaults to
+/// `DEFAULT_IPV6_PREFIX_LENGTH` if the variable is absent or invalid.
+pub fn ipv6_prefix_length<D>(ctx: &RouteContext<D>) -> u8 {
+    ctx
+        .env
+        .var("IPV6_PREFIX_LENGTH")
+        .ok()
+        .and_then(|var| var.to_string().parse::<u8>().ok())
+        .unwrap_or(DEFAULT_IPV6_PREFIX_LENGTH)
 }
 
-fn is_local_dev_mode(ctx: &RouteContext<()>) -> Result<bool, Error> {
-    let mode = ctx.env.var("MODE")?.to_string();
+fn is_local_dev_mode(env: &Env) -> Result<bool, Error> {
+    let mode = env.var("MODE")?.to_string();
 
     Ok(mode == "local")
 }
@@ -72,7 +219,16 @@ fn cors(res: Response, origin: Origin) -> Result<Response, Error> {
     res
         .with_cors(&Cors::new()
             .with_origins(vec![origin.to_string()])
-            .with_methods(vec![Method::Post])
+            .with_exposed_headers(vec!["Retry-After"])
+        )
+}
+
+fn preflight_cors(res: Response, origin: Origin, methods: Vec<Method>) -> Result<Response, Error> {
+    res
+        .with_cors(&Cors::new()
+            .with_origins(vec![origin.to_string()])
+            .with_methods(methods)
+            .with_allowed_headers(vec!["Content-Type"])
             .with_max_age(86400)
         )
 }
@@ -0,0 +1,479 @@
+// Copyright (c) 2024 Tobias Briones. All rights reserved.
+// This file is part of https://github.com/mathswe/legal
+
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use serde_json::Value;
+
+use crate::consent::{CookieConsent, CookieConsentValue, Domain};
+use crate::rate_limit::RateCounter;
+use crate::stats::{ConsentCounts, CountedConsent, DailyCounts, StatsCounter};
+use crate::store::{ConsentPage, ConsentStore, StoreError};
+
+/// Versioned migrations of the SQL schema, in the order they're applied. They're the files of
+/// the `migrations` directory, which wrangler applies to D1, so a schema change has to be a
+/// new migration appended here.
+pub const MIGRATIONS: [&str; 1] = [
+    include_str!("../migrations/0001_create_consents.sql"),
+];
+
+const PUT_CONSENT: &str = "INSERT OR REPLACE INTO consents (
+    id, domain, created_at, country, essential, functional, analytical, targeting,
+    policy_version, previous_id, withdrawn_id, retained_until, value
+) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)";
+
+const GET_CONSENT: &str = "SELECT value FROM consents WHERE id = ?1";
+
+const LIST_CONSENTS: &str = "SELECT id FROM consents WHERE id > ?1 ORDER BY id LIMIT ?2";
+
+const LIST_DAY_CONSENTS: &str = "SELECT id, created_at || ':' || id FROM consents
+    WHERE domain = ?1 AND created_at >= ?2 AND created_at < ?3 AND created_at || ':' || id > ?4
+    ORDER BY created_at, id LIMIT ?5";
+
+const DELETE_CONSENT: &str = "DELETE FROM consents WHERE id = ?1";
+
+const DELETE_EXPIRED_COUNTERS: &str = "DELETE FROM rate_counters WHERE expires_at <= ?1";
+
+const INCREMENT_COUNTER: &str = "INSERT INTO rate_counters (key, count, expires_at)
+    VALUES (?1, 1, ?2)
+    ON CONFLICT (key) DO UPDATE SET count = count + 1
+    RETURNING count";
+
+const ADD_COUNT: &str = "INSERT INTO consent_counts (
+    domain, day, country, consents, essential, functional, analytical, targeting
+) VALUES (?1, ?2, ?3, 1, ?4, ?5, ?6, ?7)
+ON CONFLICT (domain, day, country) DO UPDATE SET
+    consents = consents + 1,
+    essential = essential + excluded.essential,
+    functional = functional + excluded.functional,
+    analytical = analytical + excluded.analytical,
+    targeting = targeting + excluded.targeting";
+
+const GET_COUNTS: &str = "SELECT
+    country, consents, essential, functional, analytical, targeting
+FROM consent_counts WHERE domain = ?1 AND day = ?2";
+
+/// Defines a SQLite database the SQL statements run on, like Cloudflare D1, so the same SQL
+/// runs on plain SQLite natively. Parameters are bound by position, and rows are returned as
+/// their column values.
+#[async_trait(?Send)]
+pub trait SqlDatabase {
+    async fn execute(&self, sql: &str, params: &[Value]) -> Result<(), StoreError>;
+
+    async fn query(&self, sql: &str, params: &[Value]) -> Result<Vec<Vec<Value>>, StoreError>;
+}
+
+/// Stores the consents in the `consents` table of a SQL database, where each row has the
+/// whole `CookieConsentValue` as JSON, and its fields to query the consents by, like their
+/// `Domain`, creation time, country, and accepted cookie categories. The database also keeps
+/// the rate limit counters and the daily statistics in their own tables. SQL databases have no
+/// expiration, so the consents are only deleted by the retention runs.
+pub struct SqlConsentStore<D>(D);
+
+impl<D: SqlDatabase> SqlConsentStore<D> {
+    pub fn new(database: D) -> Self {
+        SqlConsentStore(database)
+    }
+}
+
+/// Returns the date as text with a fixed width, like `2024-03-10T12:30:00.000Z`, so dates
+/// compare in SQL as text.
+fn timestamp(date: DateTime<Utc>) -> String {
+    date.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
+
+fn day_start(day: NaiveDate) -> String {
+    timestamp(day.and_time(NaiveTime::MIN).and_utc())
+}
+
+fn consent_params(consent: &CookieConsent) -> Vec<Value> {
+    let (id, value) = consent.to_kv();
+    let pref = value.pref();
+
+    vec![
+        id.into(),
+        value.domain().to_string().into(),
+        timestamp(value.created_at()).into(),
+        value.geolocation().country().cloned().into(),
+        i64::from(pref.essential()).into(),
+        i64::from(pref.functional()).into(),
+        i64::from(pref.analytical()).into(),
+        i64::from(pref.targeting()).into(),
+        serde_json::to_value(value.policy_version()).unwrap(),
+        value.previous_id().cloned().into(),
+        value.withdrawn_id().cloned().into(),
+        value.retained_until().map(timestamp).into(),
+        serde_json::to_string(&value).unwrap().into(),
+    ]
+}
+
+/// Returns the first column of the first row, if any.
+fn first_column(rows: Vec<Vec<Value>>) -> Option<Value> {
+    rows.into_iter().next().and_then(|row| row.into_iter().next())
+}
+
+/// Returns the integer of a column, which some databases like D1 return as a float.
+fn as_u64(column: &Value) -> Option<u64> {
+    column.as_u64().or(column.as_f64().map(|number| number as u64))
+}
+
+fn text_column(column: &Value) -> Result<String, StoreError> {
+    column
+        .as_str()
+        .map(str::to_string)
+        .ok_or(StoreError::new(format!("Expected a text column but got {}", column)))
+}
+
+fn parse_json<T: serde::de::DeserializeOwned>(column: &Value) -> Result<T, StoreError> {
+    serde_json::from_str(&text_column(column)?)
+        .map_err(|e| StoreError::new(format!("Invalid stored JSON: {}", e)))
+}
+
+/// Returns the page of the listed rows, whose first column is the consent ID and whose last
+/// column is their cursor, and where one more row than the `limit` tells there are more rows.
+fn page(rows: Vec<Vec<Value>>, limit: u64) -> Result<ConsentPage, StoreError> {
+    let has_more = rows.len() as u64 > limit;
+    let rows = rows.into_iter().take(limit as usize).collect::<Vec<_>>();
+    let ids = rows
+        .iter()
+        .map(|row| text_column(row.first().unwrap_or(&Value::Null)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let cursor = match (has_more, rows.last().and_then(|row| row.last())) {
+        (true, Some(column)) => Some(text_column(column)?),
+        _ => None,
+    };
+
+    Ok(ConsentPage { ids, cursor })
+}
+
+#[async_trait(?Send)]
+impl<D: SqlDatabase> ConsentStore for SqlConsentStore<D> {
+    async fn put(&self, consent: &CookieConsent) -> Result<(), StoreError> {
+        self.0.execute(PUT_CONSENT, &consent_params(consent)).await
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<CookieConsent>, StoreError> {
+        let rows = self.0.query(GET_CONSENT, &[id.into()]).await?;
+
+        first_column(rows)
+            .map(|column| parse_json::<CookieConsentValue>(&column))
+            .transpose()
+            .map(|value| value.map(|value| CookieConsent::from_kv(id.to_string(), value)))
+    }
+
+    async fn list(&self, cursor: Option<String>, limit: u64) -> Result<ConsentPage, StoreError> {
+        let params = [cursor.unwrap_or_default().into(), (limit + 1).into()];
+
+        page(self.0.query(LIST_CONSENTS, &params).await?, limit)
+    }
+
+    async fn list_day(
+        &self,
+        domain: &Domain,
+        day: NaiveDate,
+        cursor: Option<String>,
+        limit: u64,
+    ) -> Result<ConsentPage, StoreError> {
+        let next_day = day.succ_opt().unwrap_or(NaiveDate::MAX);
+        let params = [
+            domain.to_string().into(),
+            day_start(day).into(),
+            day_start(next_day).into(),
+            cursor.unwrap_or_default().into(),
+            (limit + 1).into(),
+        ];
+
+        page(self.0.query(LIST_DAY_CONSENTS, &params).await?, limit)
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), StoreError> {
+        self.0.execute(DELETE_CONSENT, &[id.into()]).await
+    }
+}
+
+/// Counts the requests in the `rate_counters` table, where the counter is incremented in a
+/// single statement, and the expired counters are deleted as new requests are counted.
+#[async_trait(?Send)]
+impl<D: SqlDatabase> RateCounter for SqlConsentStore<D> {
+    async fn increment(&self, key: &str, ttl: u64) -> Result<u64, StoreError> {
+        let now = Utc::now().timestamp();
+        let expires_at = now + ttl as i64;
+
+        self.0.execute(DELETE_EXPIRED_COUNTERS, &[now.into()]).await?;
+
+        let rows = self.0.query(INCREMENT_COUNTER, &[key.into(), expires_at.into()]).await?;
+
+        first_column(rows)
+            .as_ref()
+            .and_then(as_u64)
+            .ok_or(StoreError::new("Missing incremented rate counter"))
+    }
+}
+
+/// Counts the consents in the `consent_counts` table, where the counts of a domain, day, and
+/// country are incremented in a single statement, so concurrent consents don't overwrite each
+/// other's counts.
+#[async_trait(?Send)]
+impl<D: SqlDatabase> StatsCounter for SqlConsentStore<D> {
+    async fn add_count(&self, consent: &CountedConsent) -> Result<(), StoreError> {
+        let pref = consent.pref();
+        let params = [
+            consent.domain().to_string().into(),
+            consent.day().to_string().into(),
+            consent.country().into(),
+            u64::from(pref.essential()).into(),
+            u64::from(pref.functional()).into(),
+            u64::from(pref.analytical()).into(),
+            u64::from(pref.targeting()).into(),
+        ];
+
+        self.0.execute(ADD_COUNT, &params).await
+    }
+
+    async fn get_counts(&self, domain: &Domain, day: NaiveDate) -> Result<DailyCounts, StoreError> {
+        let params = [domain.to_string().into(), day.to_string().into()];
+        let mut counts = DailyCounts::default();
+
+        for row in self.0.query(GET_COUNTS, &params).await? {
+            let column = |index: usize| row.get(index).and_then(as_u64).unwrap_or_default();
+            let country = row.first().and_then(Value::as_str).unwrap_or_default();
+
+            counts.add_country(
+                country,
+                &ConsentCounts::new(column(1), column(2), column(3), column(4), column(5)),
+            );
+        }
+
+        Ok(counts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use chrono::NaiveDate;
+    use futures::executor::block_on;
+    use rusqlite::Connection;
+    use rusqlite::types::{Value as SqliteValue, ValueRef};
+    use serde_json::Value;
+
+    use crate::consent::Domain;
+    use crate::rate_limit::RateCounter;
+    use crate::sql_store::{MIGRATIONS, SqlConsentStore, SqlDatabase};
+    use crate::stats::{count_consent, DailyCounts, StatsCounter};
+    use crate::store::{ConsentBuilder, ConsentPage, ConsentStore, StoreError};
+
+    /// Runs the SQL statements on an in-memory SQLite database with the migrations applied.
+    struct SqliteDatabase(Connection);
+
+    impl SqliteDatabase {
+        fn migrated() -> Self {
+            let connection = Connection::open_in_memory().unwrap();
+
+            MIGRATIONS
+                .iter()
+                .for_each(|migration| connection.execute_batch(migration).unwrap());
+
+            SqliteDatabase(connection)
+        }
+    }
+
+    fn sqlite_params(params: &[Value]) -> Vec<SqliteValue> {
+        params
+            .iter()
+            .map(|param| match param {
+                Value::Null => SqliteValue::Null,
+                Value::Bool(value) => SqliteValue::Integer((*value).into()),
+                Value::Number(number) => number
+                    .as_i64()
+                    .map(SqliteValue::Integer)
+                    .unwrap_or(SqliteValue::Real(number.as_f64().unwrap_or_default())),
+                Value::String(text) => SqliteValue::Text(text.clone()),
+                other => SqliteValue::Text(other.to_string()),
+            })
+            .collect()
+    }
+
+    fn sqlite_error(error: rusqlite::Error) -> StoreError {
+        StoreError::new(error.to_string())
+    }
+
+    #[async_trait(?Send)]
+    impl SqlDatabase for SqliteDatabase {
+        async fn execute(&self, sql: &str, params: &[Value]) -> Result<(), StoreError> {
+            self
+                .0
+                .execute(sql, rusqlite::params_from_iter(sqlite_params(params)))
+                .map(|_| ())
+                .map_err(sqlite_error)
+        }
+
+        async fn query(
+            &self,
+            sql: &str,
+            params: &[Value],
+        ) -> Result<Vec<Vec<Value>>, StoreError> {
+            let mut statement = self.0.prepare(sql).map_err(sqlite_error)?;
+            let columns = statement.column_count();
+            let mut rows = statement
+                .query(rusqlite::params_from_iter(sqlite_params(params)))
+                .map_err(sqlite_error)?;
+            let mut result = Vec::new();
+
+            while let Some(row) = rows.next().map_err(sqlite_error)? {
+                let values = (0..columns)
+                    .map(|i| match row.get_ref(i).unwrap() {
+                        ValueRef::Null => Value::Null,
+                        ValueRef::Integer(value) => value.into(),
+                        ValueRef::Real(value) => value.into(),
+                        ValueRef::Text(text) => String::from_utf8_lossy(text).into(),
+                        ValueRef::Blob(_) => Value::Null,
+                    })
+                    .collect();
+
+                result.push(values);
+            }
+
+            Ok(result)
+        }
+    }
+
+    #[test]
+    fn puts_gets_and_deletes_consent() {
+        let store = SqlConsentStore::new(SqliteDatabase::migrated());
+        let consent = ConsentBuilder::at("2024-03-10T12:00:00Z")
+            .country("DE")
+            .accept_all(true)
+            .build();
+        let (id, _) = consent.to_kv();
+
+        assert_eq!(None, block_on(store.get(&id)).unwrap());
+
+        block_on(store.put(&consent)).unwrap();
+        block_on(store.put(&consent)).unwrap();
+
+        assert_eq!(Some(consent), block_on(store.get(&id)).unwrap());
+
+        block_on(store.delete(&id)).unwrap();
+
+        assert_eq!(None, block_on(store.get(&id)).unwrap());
+    }
+
+    #[test]
+    fn lists_consents_by_pages() {
+        let store = SqlConsentStore::new(SqliteDatabase::migrated());
+        let mut ids = (0..5)
+            .map(|_| ConsentBuilder::at("2024-03-10T12:00:00Z").build())
+            .map(|consent| {
+                block_on(store.put(&consent)).unwrap();
+                consent.to_kv().0
+            })
+            .collect::<Vec<_>>();
+
+        ids.sort();
+
+        let first_page = block_on(store.list(None, 2)).unwrap();
+        let second_page = block_on(store.list(first_page.cursor.clone(), 2)).unwrap();
+        let last_page = block_on(store.list(second_page.cursor.clone(), 2)).unwrap();
+
+        assert_eq!(ids[0..2].to_vec(), first_page.ids);
+        assert_eq!(ids[2..4].to_vec(), second_page.ids);
+        assert_eq!(ConsentPage { ids: ids[4..].to_vec(), cursor: None }, last_page);
+    }
+
+    #[test]
+    fn lists_consents_of_domain_by_day() {
+        let store = SqlConsentStore::new(SqliteDatabase::migrated());
+        let consents = [
+            ConsentBuilder::at("2024-03-10T23:00:00Z").build(),
+            ConsentBuilder::at("2024-03-10T08:00:00Z").build(),
+            ConsentBuilder::at("2024-03-10T12:00:00Z").build(),
+            ConsentBuilder::at("2024-03-11T00:00:00Z").build(),
+            ConsentBuilder::at("2024-03-10T09:00:00Z").domain("math.software").build(),
+        ];
+        let id = |i: usize| consents[i].to_kv().0;
+        let domain = Domain::new("mathswe.com");
+        let day = NaiveDate::from_ymd_opt(2024, 3, 10).unwrap();
+
+        consents
+            .iter()
+            .for_each(|consent| block_on(store.put(consent)).unwrap());
+
+        let first_page = block_on(store.list_day(&domain, day, None, 2)).unwrap();
+        let last_page = block_on(store.list_day(&domain, day, first_page.cursor.clone(), 2))
+            .unwrap();
+
+        assert_eq!(vec![id(1), id(2)], first_page.ids, "consents are listed by creation time");
+        assert_eq!(ConsentPage { ids: vec![id(0)], cursor: None }, last_page);
+    }
+
+    #[test]
+    fn queries_consents_by_their_fields() {
+        let database = SqliteDatabase::migrated();
+        let math_software = |created_at, country| ConsentBuilder::at(created_at)
+            .domain("math.software")
+            .country(country);
+        let consents = [
+            math_software("2024-03-05T10:00:00Z", "DE").accept_all(true).build(),
+            math_software("2024-03-20T10:00:00Z", "DE").build(),
+            math_software("2024-03-21T10:00:00Z", "FR").accept_all(true).build(),
+            math_software("2024-04-01T10:00:00Z", "DE").accept_all(true).build(),
+            ConsentBuilder::at("2024-03-05T10:00:00Z").country("DE").accept_all(true).build(),
+        ];
+        let store = SqlConsentStore::new(database);
+
+        consents
+            .iter()
+            .for_each(|consent| block_on(store.put(consent)).unwrap());
+
+        let SqlConsentStore(database) = store;
+        let rows = block_on(database.query(
+            "SELECT id FROM consents
+                WHERE domain = 'math.software' AND country = 'DE' AND targeting = 1
+                AND created_at >= '2024-03-01' AND created_at < '2024-04-01'",
+            &[],
+        )).unwrap();
+
+        assert_eq!(vec![vec![Value::from(consents[0].to_kv().0)]], rows);
+    }
+
+    #[test]
+    fn increments_rate_counters() {
+        let store = SqlConsentStore::new(SqliteDatabase::migrated());
+
+        assert_eq!(Ok(1), block_on(store.increment("rate:mathswe.com:abc:1", 60)));
+        assert_eq!(Ok(2), block_on(store.increment("rate:mathswe.com:abc:1", 60)));
+        assert_eq!(Ok(1), block_on(store.increment("rate:mathswe.com:def:1", 60)));
+    }
+
+    #[test]
+    fn keeps_daily_counts() {
+        let store = SqlConsentStore::new(SqliteDatabase::migrated());
+        let domain = Domain::new("mathswe.com");
+        let day = "2024-03-10".parse().unwrap();
+        let consents = [
+            ConsentBuilder::at("2024-03-10T12:00:00Z").country("DE").accept_all(true).build(),
+            ConsentBuilder::at("2024-03-10T13:00:00Z").country("DE").accept_all(true).build(),
+            ConsentBuilder::at("2024-03-10T14:00:00Z").build(),
+            ConsentBuilder::at("2024-03-10T14:00:00Z")
+                .domain("math.software")
+                .country("DE")
+                .accept_all(true)
+                .build(),
+        ];
+
+        assert_eq!(Ok(DailyCounts::default()), block_on(store.get_counts(&domain, day)));
+
+        consents
+            .iter()
+            .for_each(|consent| block_on(count_consent(&store, consent)).unwrap());
+
+        let counts = serde_json::to_value(block_on(store.get_counts(&domain, day)).unwrap())
+            .unwrap();
+
+        assert_eq!(3, counts["counts"]["consents"]);
+        assert_eq!(2, counts["counts"]["targeting"]);
+        assert_eq!(2, counts["countries"]["DE"]["targeting"]);
+        assert_eq!(1, counts["countries"]["unknown"]["essential"]);
+    }
+}
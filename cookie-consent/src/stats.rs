@@ -0,0 +1,393 @@
+// Copyright (c) 2024 Tobias Briones. All rights reserved.
+// This file is part of https://github.com/mathswe/legal
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+
+use crate::consent::{CookieConsent, CookieConsentPref, Domain};
+use crate::store::StoreError;
+
+/// Prefix of the statistics keys, so they don't collide with the consent IDs.
+pub const STATS_KEY_PREFIX: &str = "stats:";
+
+/// Group of the consents whose `Geolocation` has no country.
+const UNKNOWN_COUNTRY: &str = "unknown";
+
+/// Defines the storage of the daily consent counts of each `Domain`, so the statistics don't
+/// depend on a particular backend, like Workers KV. Consents are registered concurrently, so
+/// adding a count should avoid overwriting the counts other requests add at the same time.
+#[async_trait(?Send)]
+pub trait StatsCounter {
+    async fn add_count(&self, consent: &CountedConsent) -> Result<(), StoreError>;
+
+    async fn get_counts(&self, domain: &Domain, day: NaiveDate) -> Result<DailyCounts, StoreError>;
+}
+
+/// Keeps the daily consent counts in memory, so they're lost once the counter is dropped.
+#[derive(Default)]
+pub struct MemoryStatsCounter(RefCell<BTreeMap<String, DailyCounts>>);
+
+#[async_trait(?Send)]
+impl StatsCounter for MemoryStatsCounter {
+    async fn add_count(&self, consent: &CountedConsent) -> Result<(), StoreError> {
+        self
+            .0
+            .borrow_mut()
+            .entry(stats_key(&consent.domain, consent.day))
+            .or_default()
+            .add(&consent.pref, &consent.country);
+        Ok(())
+    }
+
+    async fn get_counts(&self, domain: &Domain, day: NaiveDate) -> Result<DailyCounts, StoreError> {
+        Ok(self.0.borrow().get(&stats_key(domain, day)).cloned().unwrap_or_default())
+    }
+}
+
+/// Returns the key of the counts of a `Domain` on the given day, like
+/// `stats:mathswe.com:2024-03-10`.
+pub fn stats_key(domain: &Domain, day: NaiveDate) -> String {
+    format!("{}{}:{}", STATS_KEY_PREFIX, domain, day.format("%Y-%m-%d"))
+}
+
+/// Defines what the statistics count of a registered consent, that is, its `Domain`, the day
+/// it was created in UTC, its preference, and the country it was given from. It has no
+/// consent ID, so the counts can't be linked back to a consent.
+#[derive(PartialEq, Clone, Debug)]
+pub struct CountedConsent {
+    domain: Domain,
+    day: NaiveDate,
+    pref: CookieConsentPref,
+    country: String,
+}
+
+impl CountedConsent {
+    pub fn of(consent: &CookieConsent) -> Self {
+        let (_, value) = consent.to_kv();
+
+        CountedConsent {
+            domain: value.domain().clone(),
+            day: value.created_at().date_naive(),
+            pref: *value.pref(),
+            country: value
+                .geolocation()
+                .country()
+                .cloned()
+                .unwrap_or(UNKNOWN_COUNTRY.to_string()),
+        }
+    }
+
+    pub fn domain(&self) -> &Domain {
+        &self.domain
+    }
+
+    pub fn day(&self) -> NaiveDate {
+        self.day
+    }
+
+    pub fn pref(&self) -> &CookieConsentPref {
+        &self.pref
+    }
+
+    pub fn country(&self) -> &str {
+        &self.country
+    }
+}
+
+/// Defines how many consents were registered, and how many of them accepted each cookie
+/// category.
+#[derive(PartialEq, Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct ConsentCounts {
+    consents: u64,
+    essential: u64,
+    functional: u64,
+    analytical: u64,
+    targeting: u64,
+}
+
+impl ConsentCounts {
+    pub fn new(
+        consents: u64,
+        essential: u64,
+        functional: u64,
+        analytical: u64,
+        targeting: u64,
+    ) -> Self {
+        ConsentCounts { consents, essential, functional, analytical, targeting }
+    }
+
+    pub fn add(&mut self, pref: &CookieConsentPref) {
+        self.consents += 1;
+        self.essential += u64::from(pref.essential());
+        self.functional += u64::from(pref.functional());
+        self.analytical += u64::from(pref.analytical());
+        self.targeting += u64::from(pref.targeting());
+    }
+
+    fn merge(&mut self, other: &ConsentCounts) {
+        self.consents += other.consents;
+        self.essential += other.essential;
+        self.functional += other.functional;
+        self.analytical += other.analytical;
+        self.targeting += other.targeting;
+    }
+}
+
+/// Defines the consent counts of a `Domain` on a day, in total and by the country the
+/// consents were given from.
+#[derive(PartialEq, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DailyCounts {
+    counts: ConsentCounts,
+    countries: BTreeMap<String, ConsentCounts>,
+}
+
+impl DailyCounts {
+    pub fn add(&mut self, pref: &CookieConsentPref, country: &str) {
+        self.counts.add(pref);
+        self.countries.entry(country.to_string()).or_default().add(pref);
+    }
+
+    /// Adds the counts of the consents given from a country.
+    pub fn add_country(&mut self, country: &str, counts: &ConsentCounts) {
+        self.counts.merge(counts);
+        self.countries.entry(country.to_string()).or_default().merge(counts);
+    }
+
+    pub fn merge(&mut self, other: &DailyCounts) {
+        other
+            .countries
+            .iter()
+            .for_each(|(country, counts)| self.add_country(country, counts));
+    }
+}
+
+/// Adds a registered consent to the counts of its `Domain` on the day it was created in UTC.
+pub async fn count_consent(
+    counter: &impl StatsCounter,
+    consent: &CookieConsent,
+) -> Result<(), StoreError> {
+    counter.add_count(&CountedConsent::of(consent)).await
+}
+
+/// Defines how many consents accepted a cookie category, and their rate out of all the
+/// consents.
+#[derive(PartialEq, Clone, Copy, Debug, Serialize)]
+pub struct CategoryStats {
+    accepted: u64,
+    rate: f64,
+}
+
+impl CategoryStats {
+    fn new(accepted: u64, consents: u64) -> Self {
+        let rate = if consents == 0 { 0.0 } else { accepted as f64 / consents as f64 };
+
+        CategoryStats { accepted, rate }
+    }
+}
+
+/// Defines the number of consents and the acceptance of each cookie category.
+#[derive(PartialEq, Clone, Copy, Debug, Serialize)]
+pub struct ConsentStats {
+    consents: u64,
+    essential: CategoryStats,
+    functional: CategoryStats,
+    analytical: CategoryStats,
+    targeting: CategoryStats,
+}
+
+impl ConsentStats {
+    pub fn from(counts: &ConsentCounts) -> Self {
+        ConsentStats {
+            consents: counts.consents,
+            essential: CategoryStats::new(counts.essential, counts.consents),
+            functional: CategoryStats::new(counts.functional, counts.consents),
+            analytical: CategoryStats::new(counts.analytical, counts.consents),
+            targeting: CategoryStats::new(counts.targeting, counts.consents),
+        }
+    }
+}
+
+/// Defines the statistics of a `Domain` on a day, and by country if they were requested.
+#[derive(PartialEq, Clone, Debug, Serialize)]
+pub struct DayStats {
+    day: NaiveDate,
+    #[serde(flatten)]
+    stats: ConsentStats,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    countries: Option<BTreeMap<String, ConsentStats>>,
+}
+
+/// Defines the statistics of a `Domain` in a range of days, in total and by day.
+#[derive(PartialEq, Clone, Debug, Serialize)]
+pub struct StatsReport {
+    domain: Domain,
+    total: ConsentStats,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    countries: Option<BTreeMap<String, ConsentStats>>,
+    days: Vec<DayStats>,
+}
+
+impl StatsReport {
+    /// Builds the report of the given daily counts of a `Domain`, grouping them by country
+    /// if `by_country` is set.
+    pub fn from_days(
+        domain: Domain,
+        days: Vec<(NaiveDate, DailyCounts)>,
+        by_country: bool,
+    ) -> Self {
+        let country_stats = |countries: &BTreeMap<String, ConsentCounts>| {
+            countries
+                .iter()
+                .map(|(country, counts)| (country.clone(), ConsentStats::from(counts)))
+                .collect::<BTreeMap<_, _>>()
+        };
+        let mut total = ConsentCounts::default();
+        let mut total_countries = BTreeMap::<String, ConsentCounts>::new();
+
+        for (_, daily) in days.iter() {
+            total.merge(&daily.counts);
+
+            for (country, counts) in daily.countries.iter() {
+                total_countries.entry(country.clone()).or_default().merge(counts);
+            }
+        }
+
+        let days = days
+            .iter()
+            .map(|(day, daily)| DayStats {
+                day: *day,
+                stats: ConsentStats::from(&daily.counts),
+                countries: by_country.then(|| country_stats(&daily.countries)),
+            })
+            .collect();
+
+        StatsReport {
+            domain,
+            total: ConsentStats::from(&total),
+            countries: by_country.then(|| country_stats(&total_countries)),
+            days,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+    use futures::executor::block_on;
+
+    use crate::consent::{CookieConsentPref, Domain};
+    use crate::stats::{
+        ConsentCounts,
+        count_consent,
+        DailyCounts,
+        MemoryStatsCounter,
+        StatsCounter,
+        StatsReport,
+    };
+    use crate::store::ConsentBuilder;
+
+    #[test]
+    fn counts_consents_by_day_and_country() {
+        let counter = MemoryStatsCounter::default();
+        let consents = [
+            ConsentBuilder::at("2024-03-10T08:00:00Z").country("DE").accept_all(true).build(),
+            ConsentBuilder::at("2024-03-10T23:59:59Z").country("DE").build(),
+            ConsentBuilder::at("2024-03-10T12:00:00Z").build(),
+            ConsentBuilder::at("2024-03-11T00:00:00Z").country("FR").accept_all(true).build(),
+        ];
+        let counts = |day: &str| {
+            let day = day.parse::<NaiveDate>().unwrap();
+            let counts = block_on(counter.get_counts(&Domain::new("mathswe.com"), day));
+
+            serde_json::to_value(counts.unwrap()).unwrap()
+        };
+
+        consents
+            .iter()
+            .for_each(|consent| block_on(count_consent(&counter, consent)).unwrap());
+
+        let first_day = counts("2024-03-10");
+
+        assert_eq!(3, first_day["counts"]["consents"]);
+        assert_eq!(3, first_day["counts"]["essential"]);
+        assert_eq!(1, first_day["counts"]["analytical"]);
+        assert_eq!(2, first_day["countries"]["DE"]["consents"]);
+        assert_eq!(1, first_day["countries"]["unknown"]["consents"]);
+        assert_eq!(1, counts("2024-03-11")["countries"]["FR"]["targeting"]);
+        assert_eq!(0, counts("2024-03-12")["counts"]["consents"]);
+    }
+
+    #[test]
+    fn merges_daily_counts() {
+        let mut counts = DailyCounts::default();
+        let mut other = DailyCounts::default();
+
+        counts.add(&pref(true), "DE");
+        other.add(&pref(false), "DE");
+        other.add_country("FR", &ConsentCounts::new(3, 3, 2, 1, 0));
+        counts.merge(&other);
+
+        let counts = serde_json::to_value(counts).unwrap();
+
+        assert_eq!(5, counts["counts"]["consents"]);
+        assert_eq!(3, counts["counts"]["functional"]);
+        assert_eq!(2, counts["countries"]["DE"]["consents"]);
+        assert_eq!(1, counts["countries"]["FR"]["analytical"]);
+    }
+
+    #[test]
+    fn reports_acceptance_rates() {
+        let mut first_day = DailyCounts::default();
+        let mut second_day = DailyCounts::default();
+
+        first_day.add(&pref(true), "DE");
+        first_day.add(&pref(false), "DE");
+        second_day.add(&pref(true), "FR");
+        second_day.add(&pref(true), "DE");
+
+        let days = vec![
+            ("2024-03-10".parse().unwrap(), first_day),
+            ("2024-03-11".parse().unwrap(), second_day),
+        ];
+        let report = serde_json::to_value(
+            StatsReport::from_days(Domain::new("mathswe.com"), days.clone(), true)
+        ).unwrap();
+        let report_without_countries = serde_json::to_value(
+            StatsReport::from_days(Domain::new("mathswe.com"), days, false)
+        ).unwrap();
+
+        assert_eq!(4, report["total"]["consents"]);
+        assert_eq!(3, report["total"]["analytical"]["accepted"]);
+        assert_eq!(0.75, report["total"]["analytical"]["rate"]);
+        assert_eq!(1.0, report["total"]["essential"]["rate"]);
+        assert_eq!(2.0 / 3.0, report["countries"]["DE"]["targeting"]["rate"]);
+        assert_eq!("2024-03-10", report["days"][0]["day"]);
+        assert_eq!(0.5, report["days"][0]["functional"]["rate"]);
+        assert_eq!(1, report["days"][1]["countries"]["FR"]["consents"]);
+        assert!(report_without_countries.get("countries").is_none());
+        assert!(report_without_countries["days"][0].get("countries").is_none());
+    }
+
+    #[test]
+    fn reports_empty_rates_without_consents() {
+        let report = serde_json::to_value(
+            StatsReport::from_days(Domain::new("mathswe.com"), vec![], false)
+        ).unwrap();
+
+        assert_eq!(0, report["total"]["consents"]);
+        assert_eq!(0.0, report["total"]["targeting"]["rate"]);
+    }
+
+    fn pref(accept_all: bool) -> CookieConsentPref {
+        serde_json::from_value(serde_json::json!({
+            "essential": true,
+            "functional": accept_all,
+            "analytical": accept_all,
+            "targeting": accept_all
+        })).unwrap()
+    }
+}
@@ -0,0 +1,347 @@
+// Copyright (c) 2024 Tobias Briones. All rights reserved.
+// This file is part of https://github.com/mathswe/legal
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::ops::Bound::{Excluded, Unbounded};
+
+use async_trait::async_trait;
+use chrono::NaiveDate;
+
+use crate::consent::{CookieConsent, CookieConsentValue, Domain};
+
+/// Prefix of the index keys that list the consents of a `Domain` by creation time.
+pub const INDEX_KEY_PREFIX: &str = "idx:";
+
+/// Defines the storage of registered `CookieConsent`s, so the consent operations don't
+/// depend on a particular backend, like Workers KV.
+#[async_trait(?Send)]
+pub trait ConsentStore {
+    async fn put(&self, consent: &CookieConsent) -> Result<(), StoreError>;
+
+    async fn get(&self, id: &str) -> Result<Option<CookieConsent>, StoreError>;
+
+    /// Lists at most `limit` consent IDs, starting after the given `cursor` from a previous
+    /// `ConsentPage`.
+    async fn list(&self, cursor: Option<String>, limit: u64) -> Result<ConsentPage, StoreError>;
+
+    /// Lists at most `limit` IDs of the consents of the `Domain` created on the given day, by
+    /// their creation time, starting after the given `cursor` from a previous `ConsentPage`.
+    async fn list_day(
+        &self,
+        domain: &Domain,
+        day: NaiveDate,
+        cursor: Option<String>,
+        limit: u64,
+    ) -> Result<ConsentPage, StoreError>;
+
+    async fn delete(&self, id: &str) -> Result<(), StoreError>;
+}
+
+/// Defines a page of consent IDs listed from a `ConsentStore`. Its `cursor` is `None` if
+/// there are no more IDs to list.
+#[derive(PartialEq, Clone, Debug)]
+pub struct ConsentPage {
+    pub ids: Vec<String>,
+    pub cursor: Option<String>,
+}
+
+/// Returns the index key of the consent, like
+/// `idx:mathswe.com:2024-03-10T12:30:00.000Z:<id>`, so the keys of a `Domain` sort by the
+/// consent creation time, and the keys of a day share the prefix of `index_day_prefix`.
+pub fn index_key(consent: &CookieConsent) -> String {
+    let (id, value) = consent.to_kv();
+
+    format!(
+        "{}{}:{}:{}",
+        INDEX_KEY_PREFIX,
+        value.domain(),
+        value.created_at().format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+        id,
+    )
+}
+
+pub fn index_day_prefix(domain: &Domain, day: NaiveDate) -> String {
+    format!("{}{}:{}T", INDEX_KEY_PREFIX, domain, day.format("%Y-%m-%d"))
+}
+
+/// Returns the consent ID of the given index key, which is its last part since consent IDs
+/// can't have `:`.
+pub fn index_key_id(key: &str) -> &str {
+    key.rsplit(':').next().unwrap_or(key)
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub struct StoreError(String);
+
+impl StoreError {
+    pub fn new(msg: impl Into<String>) -> Self {
+        StoreError(msg.into())
+    }
+}
+
+impl Display for StoreError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Stores the consents in memory, so they're lost once the store is dropped. It's useful for
+/// running the consent operations natively.
+#[derive(Default)]
+pub struct MemoryConsentStore(RefCell<BTreeMap<String, CookieConsentValue>>);
+
+#[async_trait(?Send)]
+impl ConsentStore for MemoryConsentStore {
+    async fn put(&self, consent: &CookieConsent) -> Result<(), StoreError> {
+        let (id, value) = consent.to_kv();
+
+        self.0.borrow_mut().insert(id, value);
+        Ok(())
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<CookieConsent>, StoreError> {
+        Ok(
+            self
+                .0
+                .borrow()
+                .get(id)
+                .map(|value| CookieConsent::from_kv(id.to_string(), value.clone()))
+        )
+    }
+
+    async fn list(&self, cursor: Option<String>, limit: u64) -> Result<ConsentPage, StoreError> {
+        let consents = self.0.borrow();
+        let start = cursor.map_or(Unbounded, Excluded);
+        let mut ids = consents
+            .range((start, Unbounded))
+            .map(|(id, _)| id.clone())
+            .take(limit as usize + 1)
+            .collect::<Vec<_>>();
+        let has_more = ids.len() > limit as usize;
+
+        ids.truncate(limit as usize);
+
+        let cursor = if has_more { ids.last().cloned() } else { None };
+
+        Ok(ConsentPage { ids, cursor })
+    }
+
+    async fn list_day(
+        &self,
+        domain: &Domain,
+        day: NaiveDate,
+        cursor: Option<String>,
+        limit: u64,
+    ) -> Result<ConsentPage, StoreError> {
+        let prefix = index_day_prefix(domain, day);
+        let mut keys = self
+            .0
+            .borrow()
+            .iter()
+            .map(|(id, value)| index_key(&CookieConsent::from_kv(id.clone(), value.clone())))
+            .filter(|key| key.starts_with(&prefix))
+            .filter(|key| cursor.as_ref().is_none_or(|cursor| key > cursor))
+            .collect::<Vec<_>>();
+
+        keys.sort();
+
+        let has_more = keys.len() > limit as usize;
+
+        keys.truncate(limit as usize);
+
+        let cursor = if has_more { keys.last().cloned() } else { None };
+        let ids = keys.iter().map(|key| index_key_id(key).to_string()).collect();
+
+        Ok(ConsentPage { ids, cursor })
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), StoreError> {
+        self.0.borrow_mut().remove(id);
+        Ok(())
+    }
+}
+
+/// Builds the consents of the tests. A consent is given to `mathswe.com` under the
+/// `2024-03-10` policy, only accepts the essential cookies, has no location, and is created
+/// now, unless set otherwise.
+#[cfg(test)]
+pub struct ConsentBuilder {
+    domain: String,
+    created_at: Option<String>,
+    country: Option<String>,
+    city: Option<String>,
+    policy_version: String,
+    accept_all: bool,
+}
+
+#[cfg(test)]
+impl Default for ConsentBuilder {
+    fn default() -> Self {
+        ConsentBuilder {
+            domain: "mathswe.com".to_string(),
+            created_at: None,
+            country: None,
+            city: None,
+            policy_version: "2024-03-10".to_string(),
+            accept_all: false,
+        }
+    }
+}
+
+#[cfg(test)]
+impl ConsentBuilder {
+    /// Builds a consent created at the given RFC 3339 date.
+    pub fn at(created_at: &str) -> Self {
+        ConsentBuilder { created_at: Some(created_at.to_string()), ..Self::default() }
+    }
+
+    pub fn domain(self, domain: &str) -> Self {
+        ConsentBuilder { domain: domain.to_string(), ..self }
+    }
+
+    pub fn country(self, country: &str) -> Self {
+        ConsentBuilder { country: Some(country.to_string()), ..self }
+    }
+
+    pub fn city(self, city: &str) -> Self {
+        ConsentBuilder { city: Some(city.to_string()), ..self }
+    }
+
+    pub fn policy_version(self, policy_version: &str) -> Self {
+        ConsentBuilder { policy_version: policy_version.to_string(), ..self }
+    }
+
+    pub fn accept_all(self, accept_all: bool) -> Self {
+        ConsentBuilder { accept_all, ..self }
+    }
+
+    pub fn build(self) -> CookieConsent {
+        use crate::consent::CookieConsentPref;
+        use crate::geolocation::Geolocation;
+        use crate::policy::{KnownPolicy, PolicyVersion};
+        use crate::privacy_signal::PrivacySignals;
+
+        let pref = serde_json::from_value::<CookieConsentPref>(serde_json::json!({
+            "essential": true,
+            "functional": self.accept_all,
+            "analytical": self.accept_all,
+            "targeting": self.accept_all
+        })).unwrap();
+        let policies = [KnownPolicy::active(&self.policy_version)];
+        let consent = CookieConsent::new(
+            Domain::new(self.domain),
+            pref,
+            PolicyVersion::validate(self.policy_version.clone(), &policies).unwrap(),
+            Geolocation::new(chrono_tz::Tz::UTC, self.country, self.city, None, None),
+            None,
+            "Mozilla/5.0".to_string(),
+            PrivacySignals::default(),
+        );
+
+        match self.created_at {
+            Some(created_at) => {
+                let (id, value) = consent.to_kv();
+                let mut json = serde_json::to_value(value).unwrap();
+
+                json["created_at"] = created_at.into();
+
+                CookieConsent::from_kv(id, serde_json::from_value(json).unwrap())
+            }
+            None => consent,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+    use futures::executor::block_on;
+
+    use crate::consent::{CookieConsent, Domain};
+    use crate::store::{ConsentBuilder, ConsentPage, ConsentStore, MemoryConsentStore};
+
+    #[test]
+    fn puts_and_gets_consent() {
+        let store = MemoryConsentStore::default();
+        let consent = ConsentBuilder::default().build();
+        let (id, _) = consent.to_kv();
+
+        assert_eq!(None, block_on(store.get(&id)).unwrap());
+
+        block_on(store.put(&consent)).unwrap();
+
+        assert_eq!(Some(consent), block_on(store.get(&id)).unwrap());
+    }
+
+    #[test]
+    fn deletes_consent() {
+        let store = MemoryConsentStore::default();
+        let consent = ConsentBuilder::default().build();
+        let (id, _) = consent.to_kv();
+
+        block_on(store.put(&consent)).unwrap();
+        block_on(store.delete(&id)).unwrap();
+
+        assert_eq!(None, block_on(store.get(&id)).unwrap());
+    }
+
+    #[test]
+    fn lists_consents_by_pages() {
+        let store = MemoryConsentStore::default();
+        let consents = (0..5)
+            .map(|_| ConsentBuilder::default().build())
+            .collect::<Vec<CookieConsent>>();
+        let mut ids = consents
+            .iter()
+            .map(|consent| consent.to_kv().0)
+            .collect::<Vec<_>>();
+
+        ids.sort();
+        consents
+            .iter()
+            .for_each(|consent| block_on(store.put(consent)).unwrap());
+
+        let first_page = block_on(store.list(None, 2)).unwrap();
+        let second_page = block_on(store.list(first_page.cursor.clone(), 2)).unwrap();
+        let last_page = block_on(store.list(second_page.cursor.clone(), 2)).unwrap();
+
+        assert_eq!(
+            ConsentPage { ids: ids[0..2].to_vec(), cursor: Some(ids[1].clone()) },
+            first_page
+        );
+        assert_eq!(
+            ConsentPage { ids: ids[2..4].to_vec(), cursor: Some(ids[3].clone()) },
+            second_page
+        );
+        assert_eq!(ConsentPage { ids: ids[4..].to_vec(), cursor: None }, last_page);
+    }
+
+    #[test]
+    fn lists_consents_of_domain_by_day() {
+        let store = MemoryConsentStore::default();
+        let consents = [
+            ConsentBuilder::at("2024-03-10T23:00:00Z").build(),
+            ConsentBuilder::at("2024-03-10T08:00:00Z").build(),
+            ConsentBuilder::at("2024-03-10T12:00:00Z").build(),
+            ConsentBuilder::at("2024-03-11T00:00:00Z").build(),
+            ConsentBuilder::at("2024-03-10T09:00:00Z").domain("math.software").build(),
+        ];
+        let id = |i: usize| consents[i].to_kv().0;
+        let domain = Domain::new("mathswe.com");
+        let day = NaiveDate::from_ymd_opt(2024, 3, 10).unwrap();
+
+        consents
+            .iter()
+            .for_each(|consent| block_on(store.put(consent)).unwrap());
+
+        let first_page = block_on(store.list_day(&domain, day, None, 2)).unwrap();
+        let last_page = block_on(store.list_day(&domain, day, first_page.cursor.clone(), 2))
+            .unwrap();
+
+        assert_eq!(vec![id(1), id(2)], first_page.ids, "consents are listed by creation time");
+        assert!(first_page.cursor.is_some());
+        assert_eq!(ConsentPage { ids: vec![id(0)], cursor: None }, last_page);
+    }
+}
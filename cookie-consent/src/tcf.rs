@@ -0,0 +1,707 @@
+// Copyright (c) 2024 Tobias Briones. All rights reserved.
+// This file is part of https://github.com/mathswe/legal
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter};
+use std::sync::OnceLock;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::consent::CookieConsentPref;
+
+const LOCAL_VENDOR_LIST: &str = include_str!("../tcf/vendor-list.json");
+
+const TC_STRING_VERSION: u64 = 2;
+
+const PURPOSE_COUNT: u8 = 24;
+
+const SPECIAL_FEATURE_COUNT: u8 = 12;
+
+/// Purposes a vendor can process under legitimate interest since TCF v2.2.
+const LEGITIMATE_INTEREST_PURPOSES: [u8; 6] = [2, 7, 8, 9, 10, 11];
+
+/// Defines the TCF purposes and special features a cookie category consents to.
+#[derive(PartialEq, Clone, Debug, Default, Deserialize)]
+pub struct TcfCategory {
+    #[serde(default)]
+    purposes: Vec<u8>,
+    #[serde(default)]
+    special_features: Vec<u8>,
+}
+
+impl TcfCategory {
+    pub fn new(purposes: Vec<u8>, special_features: Vec<u8>) -> Self {
+        TcfCategory { purposes, special_features }
+    }
+}
+
+/// Defines how the categories of a `CookieConsentPref` map to TCF purposes and special
+/// features. By default, functional cookies map to purposes 1 and 11, analytical cookies to
+/// purposes 1, 7, 8, 9, and 10, targeting cookies to purposes 1 to 6, and essential cookies to
+/// none, since they don't depend on the user's consent.
+#[derive(PartialEq, Clone, Debug, Deserialize)]
+pub struct TcfMapping {
+    #[serde(default)]
+    essential: TcfCategory,
+    #[serde(default)]
+    functional: TcfCategory,
+    #[serde(default)]
+    analytical: TcfCategory,
+    #[serde(default)]
+    targeting: TcfCategory,
+}
+
+impl Default for TcfMapping {
+    fn default() -> Self {
+        TcfMapping {
+            essential: TcfCategory::default(),
+            functional: TcfCategory::new(vec![1, 11], vec![]),
+            analytical: TcfCategory::new(vec![1, 7, 8, 9, 10], vec![]),
+            targeting: TcfCategory::new(vec![1, 2, 3, 4, 5, 6], vec![]),
+        }
+    }
+}
+
+impl TcfMapping {
+    pub fn new(
+        essential: TcfCategory,
+        functional: TcfCategory,
+        analytical: TcfCategory,
+        targeting: TcfCategory,
+    ) -> Self {
+        TcfMapping { essential, functional, analytical, targeting }
+    }
+
+    fn categories(&self) -> [&TcfCategory; 4] {
+        [&self.essential, &self.functional, &self.analytical, &self.targeting]
+    }
+
+    fn accepted_categories(&self, pref: &CookieConsentPref) -> Vec<&TcfCategory> {
+        [
+            (pref.essential(), &self.essential),
+            (pref.functional(), &self.functional),
+            (pref.analytical(), &self.analytical),
+            (pref.targeting(), &self.targeting),
+        ]
+            .into_iter()
+            .filter(|(accepted, _)| *accepted)
+            .map(|(_, category)| category)
+            .collect()
+    }
+}
+
+/// Defines the TCF settings of a `Domain`, where `cmp_id` and `cmp_version` identify the
+/// CMP registered with IAB Europe, and `publisher_cc` is the publisher's country code.
+#[derive(PartialEq, Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TcfConfig {
+    cmp_id: u16,
+    cmp_version: u16,
+    #[serde(default = "default_consent_language")]
+    consent_language: String,
+    publisher_cc: String,
+    #[serde(default)]
+    mapping: TcfMapping,
+}
+
+impl TcfConfig {
+    pub fn new(
+        cmp_id: u16,
+        cmp_version: u16,
+        consent_language: &str,
+        publisher_cc: &str,
+        mapping: TcfMapping,
+    ) -> Self {
+        TcfConfig {
+            cmp_id,
+            cmp_version,
+            consent_language: consent_language.to_string(),
+            publisher_cc: publisher_cc.to_string(),
+            mapping,
+        }
+    }
+
+    /// Validates that the settings fit in their TC string fields.
+    pub fn validate(&self) -> Result<(), TcfError> {
+        fn is_letter_code(code: &str) -> bool {
+            code.len() == 2 && code.chars().all(|c| c.is_ascii_uppercase())
+        }
+
+        let has_valid_ids = self
+            .mapping
+            .categories()
+            .iter()
+            .all(|category| {
+                category.purposes.iter().all(|id| (1..=PURPOSE_COUNT).contains(id))
+                    && category
+                    .special_features
+                    .iter()
+                    .all(|id| (1..=SPECIAL_FEATURE_COUNT).contains(id))
+            });
+
+        if !(1..4096).contains(&self.cmp_id) || self.cmp_version >= 4096 {
+            Err(TcfError::new("TCF CMP ID and version must be in 1..4096"))
+        } else if !is_letter_code(&self.consent_language) || !is_letter_code(&self.publisher_cc) {
+            Err(TcfError::new("TCF consent language and publisher CC must be two capital letters"))
+        } else if !has_valid_ids {
+            Err(TcfError::new("TCF mapping has unknown purposes or special features"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+fn default_consent_language() -> String {
+    "EN".to_string()
+}
+
+/// Defines the Global Vendor List (GVL) subset of the advertising partners on the MathSwe
+/// sites. It's read from the local `tcf/vendor-list.json` file, which has to be updated from
+/// the GVL when a partner changes its declarations.
+#[derive(PartialEq, Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VendorList {
+    vendor_list_version: u16,
+    tcf_policy_version: u8,
+    vendors: BTreeMap<String, Vendor>,
+}
+
+impl VendorList {
+    /// Returns the local vendor list, which is parsed once per Worker instance.
+    pub fn local() -> &'static Self {
+        static LOCAL: OnceLock<VendorList> = OnceLock::new();
+
+        LOCAL.get_or_init(|| {
+            serde_json::from_str(LOCAL_VENDOR_LIST)
+                .expect("Fail to read the local TCF vendor list")
+        })
+    }
+}
+
+#[derive(PartialEq, Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Vendor {
+    id: u16,
+    #[serde(default)]
+    purposes: Vec<u8>,
+    #[serde(default)]
+    leg_int_purposes: Vec<u8>,
+    #[serde(default)]
+    special_features: Vec<u8>,
+}
+
+/// Defines the core segment of an IAB TCF v2.2 TC string.
+#[derive(PartialEq, Clone, Debug)]
+pub struct TcString {
+    created: DateTime<Utc>,
+    last_updated: DateTime<Utc>,
+    cmp_id: u16,
+    cmp_version: u16,
+    consent_screen: u8,
+    consent_language: String,
+    vendor_list_version: u16,
+    tcf_policy_version: u8,
+    is_service_specific: bool,
+    use_non_standard_texts: bool,
+    special_feature_opt_ins: BTreeSet<u8>,
+    purpose_consents: BTreeSet<u8>,
+    purpose_legitimate_interests: BTreeSet<u8>,
+    purpose_one_treatment: bool,
+    publisher_cc: String,
+    vendor_consents: BTreeSet<u16>,
+    vendor_legitimate_interests: BTreeSet<u16>,
+}
+
+impl TcString {
+    /// Creates the TC string of a consent. The purposes and special features are the ones
+    /// the `TcfMapping` gives for the accepted categories. A vendor has consent if the user
+    /// consented to all its purposes and special features, and it has legitimate interest
+    /// for the accepted purposes it declares under legitimate interest, so rejecting a
+    /// category also objects to the legitimate interest of its purposes.
+    pub fn from_consent(
+        pref: &CookieConsentPref,
+        created_at: DateTime<Utc>,
+        config: &TcfConfig,
+        vendor_list: &VendorList,
+    ) -> Self {
+        let categories = config.mapping.accepted_categories(pref);
+        let purpose_consents = categories
+            .iter()
+            .flat_map(|category| category.purposes.iter().copied())
+            .collect::<BTreeSet<_>>();
+        let special_feature_opt_ins = categories
+            .iter()
+            .flat_map(|category| category.special_features.iter().copied())
+            .collect::<BTreeSet<_>>();
+        let is_legitimate_interest = |purpose: &u8| {
+            LEGITIMATE_INTEREST_PURPOSES.contains(purpose) && purpose_consents.contains(purpose)
+        };
+        let vendors = vendor_list.vendors.values().collect::<Vec<_>>();
+        let vendor_consents = vendors
+            .iter()
+            .filter(|vendor| !vendor.purposes.is_empty()
+                && vendor.purposes.iter().all(|id| purpose_consents.contains(id))
+                && vendor.special_features.iter().all(|id| special_feature_opt_ins.contains(id))
+            )
+            .map(|vendor| vendor.id)
+            .collect();
+        let vendor_legitimate_interests = vendors
+            .iter()
+            .filter(|vendor| vendor.leg_int_purposes.iter().any(is_legitimate_interest))
+            .map(|vendor| vendor.id)
+            .collect();
+        let purpose_legitimate_interests = vendors
+            .iter()
+            .flat_map(|vendor| vendor.leg_int_purposes.iter().copied())
+            .filter(is_legitimate_interest)
+            .collect();
+        let created = from_deciseconds(to_deciseconds(created_at));
+
+        TcString {
+            created,
+            last_updated: created,
+            cmp_id: config.cmp_id,
+            cmp_version: config.cmp_version,
+            consent_screen: 1,
+            consent_language: config.consent_language.clone(),
+            vendor_list_version: vendor_list.vendor_list_version,
+            tcf_policy_version: vendor_list.tcf_policy_version,
+            is_service_specific: true,
+            use_non_standard_texts: false,
+            special_feature_opt_ins,
+            purpose_consents,
+            purpose_legitimate_interests,
+            purpose_one_treatment: false,
+            publisher_cc: config.publisher_cc.clone(),
+            vendor_consents,
+            vendor_legitimate_interests,
+        }
+    }
+
+    /// Returns the core segment encoded in base64url without padding, as the TCF requires.
+    pub fn encode(&self) -> String {
+        let mut bits = BitWriter::default();
+
+        bits.write_int(TC_STRING_VERSION, 6);
+        bits.write_int(to_deciseconds(self.created), 36);
+        bits.write_int(to_deciseconds(self.last_updated), 36);
+        bits.write_int(self.cmp_id as u64, 12);
+        bits.write_int(self.cmp_version as u64, 12);
+        bits.write_int(self.consent_screen as u64, 6);
+        bits.write_letters(&self.consent_language);
+        bits.write_int(self.vendor_list_version as u64, 12);
+        bits.write_int(self.tcf_policy_version as u64, 6);
+        bits.write_bool(self.is_service_specific);
+        bits.write_bool(self.use_non_standard_texts);
+        bits.write_ids(&self.special_feature_opt_ins, SPECIAL_FEATURE_COUNT);
+        bits.write_ids(&self.purpose_consents, PURPOSE_COUNT);
+        bits.write_ids(&self.purpose_legitimate_interests, PURPOSE_COUNT);
+        bits.write_bool(self.purpose_one_treatment);
+        bits.write_letters(&self.publisher_cc);
+        bits.write_vendors(&self.vendor_consents);
+        bits.write_vendors(&self.vendor_legitimate_interests);
+        bits.write_int(0, 12); // There are no publisher restrictions
+
+        URL_SAFE_NO_PAD.encode(bits.into_bytes())
+    }
+
+    /// Decodes the core segment of the given TC string, ignoring its other segments.
+    pub fn decode(tc_string: &str) -> Result<Self, TcfError> {
+        let core_segment = tc_string.split('.').next().unwrap_or_default();
+        let bytes = URL_SAFE_NO_PAD
+            .decode(core_segment)
+            .map_err(|e| TcfError::new(format!("Invalid TC string encoding: {}", e)))?;
+        let mut bits = BitReader::new(&bytes);
+        let version = bits.read_int(6)?;
+
+        if version != TC_STRING_VERSION {
+            return Err(TcfError::new(format!("Unsupported TC string version: {}", version)));
+        }
+
+        let tc_string = TcString {
+            created: from_deciseconds(bits.read_int(36)?),
+            last_updated: from_deciseconds(bits.read_int(36)?),
+            cmp_id: bits.read_int(12)? as u16,
+            cmp_version: bits.read_int(12)? as u16,
+            consent_screen: bits.read_int(6)? as u8,
+            consent_language: bits.read_letters()?,
+            vendor_list_version: bits.read_int(12)? as u16,
+            tcf_policy_version: bits.read_int(6)? as u8,
+            is_service_specific: bits.read_bool()?,
+            use_non_standard_texts: bits.read_bool()?,
+            special_feature_opt_ins: bits.read_ids(SPECIAL_FEATURE_COUNT)?,
+            purpose_consents: bits.read_ids(PURPOSE_COUNT)?,
+            purpose_legitimate_interests: bits.read_ids(PURPOSE_COUNT)?,
+            purpose_one_treatment: bits.read_bool()?,
+            publisher_cc: bits.read_letters()?,
+            vendor_consents: bits.read_vendors()?,
+            vendor_legitimate_interests: bits.read_vendors()?,
+        };
+        let restriction_count = bits.read_int(12)?;
+
+        for _ in 0..restriction_count {
+            bits.read_int(6 + 2)?; // Purpose ID and restriction type
+            bits.read_ranges()?;
+        }
+
+        Ok(tc_string)
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct TcfError(String);
+
+impl TcfError {
+    pub fn new(msg: impl Into<String>) -> Self {
+        TcfError(msg.into())
+    }
+}
+
+impl Display for TcfError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+fn to_deciseconds(date_time: DateTime<Utc>) -> u64 {
+    (date_time.timestamp_millis() / 100) as u64
+}
+
+fn from_deciseconds(deciseconds: u64) -> DateTime<Utc> {
+    DateTime::from_timestamp_millis(deciseconds as i64 * 100).unwrap_or_default()
+}
+
+/// Returns the ranges of consecutive IDs.
+fn to_ranges(ids: &BTreeSet<u16>) -> Vec<(u16, u16)> {
+    let mut ranges: Vec<(u16, u16)> = Vec::new();
+
+    for &id in ids {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == id => *end = id,
+            _ => ranges.push((id, id)),
+        }
+    }
+    ranges
+}
+
+#[derive(Default)]
+struct BitWriter(Vec<bool>);
+
+impl BitWriter {
+    fn write_bool(&mut self, value: bool) {
+        self.0.push(value);
+    }
+
+    fn write_int(&mut self, value: u64, length: usize) {
+        (0..length)
+            .rev()
+            .for_each(|i| self.0.push((value >> i) & 1 == 1));
+    }
+
+    fn write_letters(&mut self, letters: &str) {
+        letters
+            .bytes()
+            .for_each(|letter| self.write_int((letter - b'A') as u64, 6));
+    }
+
+    fn write_ids(&mut self, ids: &BTreeSet<u8>, length: u8) {
+        (1..=length).for_each(|id| self.write_bool(ids.contains(&id)));
+    }
+
+    /// Writes the vendor IDs with the shortest encoding, either a bit field or ranges.
+    fn write_vendors(&mut self, ids: &BTreeSet<u16>) {
+        let max_id = ids.last().copied().unwrap_or(0);
+        let ranges = to_ranges(ids);
+        let range_length = 12 + ranges
+            .iter()
+            .map(|(start, end)| if start == end { 17 } else { 33 })
+            .sum::<usize>();
+
+        self.write_int(max_id as u64, 16);
+
+        if range_length < max_id as usize {
+            self.write_bool(true);
+            self.write_int(ranges.len() as u64, 12);
+
+            for (start, end) in ranges {
+                self.write_bool(start != end);
+                self.write_int(start as u64, 16);
+
+                if start != end {
+                    self.write_int(end as u64, 16);
+                }
+            }
+        } else {
+            self.write_bool(false);
+            (1..=max_id).for_each(|id| self.write_bool(ids.contains(&id)));
+        }
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self
+            .0
+            .chunks(8)
+            .map(|byte| {
+                (0..8).fold(0, |acc, i| acc << 1 | *byte.get(i).unwrap_or(&false) as u8)
+            })
+            .collect()
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        BitReader { bytes, position: 0 }
+    }
+
+    fn read_bool(&mut self) -> Result<bool, TcfError> {
+        let byte = self
+            .bytes
+            .get(self.position / 8)
+            .ok_or(TcfError::new("TC string is too short"))?;
+        let bit = byte >> (7 - self.position % 8) & 1 == 1;
+
+        self.position += 1;
+        Ok(bit)
+    }
+
+    fn read_int(&mut self, length: usize) -> Result<u64, TcfError> {
+        (0..length).try_fold(0, |acc, _| Ok(acc << 1 | self.read_bool()? as u64))
+    }
+
+    fn read_letters(&mut self) -> Result<String, TcfError> {
+        (0..2)
+            .map(|_| self.read_int(6).map(|letter| (b'A' + letter as u8) as char))
+            .collect()
+    }
+
+    fn read_ids(&mut self, length: u8) -> Result<BTreeSet<u8>, TcfError> {
+        let mut ids = BTreeSet::new();
+
+        for id in 1..=length {
+            if self.read_bool()? {
+                ids.insert(id);
+            }
+        }
+        Ok(ids)
+    }
+
+    fn read_vendors(&mut self) -> Result<BTreeSet<u16>, TcfError> {
+        let max_id = self.read_int(16)? as u16;
+
+        if self.read_bool()? {
+            return self.read_ranges();
+        }
+
+        let mut ids = BTreeSet::new();
+
+        for id in 1..=max_id {
+            if self.read_bool()? {
+                ids.insert(id);
+            }
+        }
+        Ok(ids)
+    }
+
+    fn read_ranges(&mut self) -> Result<BTreeSet<u16>, TcfError> {
+        let range_count = self.read_int(12)?;
+        let mut ids = BTreeSet::new();
+
+        for _ in 0..range_count {
+            let is_range = self.read_bool()?;
+            let start = self.read_int(16)? as u16;
+            let end = if is_range { self.read_int(16)? as u16 } else { start };
+
+            ids.extend(start..=end);
+        }
+        Ok(ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use chrono::{DateTime, Utc};
+
+    use crate::consent::CookieConsentPref;
+    use crate::tcf::{TcfCategory, TcfConfig, TcfError, TcfMapping, TcString, VendorList};
+
+    #[test]
+    fn reads_local_vendor_list() {
+        let vendor_list = VendorList::local();
+
+        assert!(vendor_list.vendor_list_version > 0);
+        assert_eq!(4, vendor_list.tcf_policy_version, "TCF v2.2 has policy version 4");
+        assert!(!vendor_list.vendors.is_empty());
+        assert!(std::ptr::eq(vendor_list, VendorList::local()), "vendor list is parsed once");
+    }
+
+    #[test]
+    fn encodes_consent_to_tc_string() {
+        let tc_string = TcString::from_consent(
+            &all_accepted_pref(),
+            dummy_created_at(),
+            &dummy_config(),
+            VendorList::local(),
+        );
+        let encoded = tc_string.encode();
+
+        assert!(encoded.starts_with('C'), "TC strings of version 2 start with C");
+        assert!(!encoded.contains(['=', '+', '/']), "TC string is base64url without padding");
+        assert_eq!(Ok(tc_string), TcString::decode(&encoded), "TC string round trips");
+    }
+
+    #[test]
+    fn maps_accepted_categories_to_purposes() {
+        let vendor_list = VendorList::local();
+        let tc_string = |pref| TcString::from_consent(
+            &pref,
+            dummy_created_at(),
+            &dummy_config(),
+            vendor_list,
+        );
+        let all_accepted = tc_string(all_accepted_pref());
+        let essential_only = tc_string(CookieConsentPref::essential_only());
+
+        assert_eq!(BTreeSet::from_iter(1..=11), all_accepted.purpose_consents);
+        assert_eq!(BTreeSet::from([2, 7, 9, 10]), all_accepted.purpose_legitimate_interests);
+        assert_eq!(BTreeSet::from([755]), all_accepted.vendor_consents);
+        assert_eq!(BTreeSet::from([755]), all_accepted.vendor_legitimate_interests);
+        assert_eq!(BTreeSet::new(), essential_only.purpose_consents);
+        assert_eq!(BTreeSet::new(), essential_only.purpose_legitimate_interests);
+        assert_eq!(BTreeSet::new(), essential_only.vendor_consents);
+        assert_eq!(BTreeSet::new(), essential_only.vendor_legitimate_interests);
+    }
+
+    #[test]
+    fn maps_categories_with_configured_mapping() {
+        let mapping = TcfMapping::new(
+            TcfCategory::new(vec![1], vec![]),
+            TcfCategory::default(),
+            TcfCategory::default(),
+            TcfCategory::new(vec![2, 3, 4], vec![1]),
+        );
+        let config = TcfConfig::new(10, 1, "ES", "HN", mapping);
+        let tc_string = TcString::from_consent(
+            &all_accepted_pref(),
+            dummy_created_at(),
+            &config,
+            VendorList::local(),
+        );
+
+        assert_eq!(BTreeSet::from([1, 2, 3, 4]), tc_string.purpose_consents);
+        assert_eq!(BTreeSet::from([1]), tc_string.special_feature_opt_ins);
+        assert_eq!("ES", tc_string.consent_language);
+        assert_eq!(Ok(tc_string.clone()), TcString::decode(&tc_string.encode()));
+    }
+
+    #[test]
+    fn encodes_vendors_as_bit_field_or_ranges() {
+        let vendor_cases = [
+            BTreeSet::new(),
+            BTreeSet::from([1, 3, 5]),
+            BTreeSet::from([755]),
+            BTreeSet::from_iter(1..=400),
+            BTreeSet::from([2, 3, 4, 900, 1000, 1001, 1002]),
+        ];
+        let tc_string = TcString::from_consent(
+            &all_accepted_pref(),
+            dummy_created_at(),
+            &dummy_config(),
+            VendorList::local(),
+        );
+
+        vendor_cases
+            .iter()
+            .for_each(|vendors| {
+                let tc_string = TcString {
+                    vendor_consents: vendors.clone(),
+                    vendor_legitimate_interests: vendors.clone(),
+                    ..tc_string.clone()
+                };
+
+                assert_eq!(
+                    Ok(tc_string.clone()),
+                    TcString::decode(&tc_string.encode()),
+                    "vendors {:?} round trip",
+                    vendors
+                )
+            })
+    }
+
+    #[test]
+    fn rejects_invalid_tc_strings() {
+        let tc_string = TcString::from_consent(
+            &all_accepted_pref(),
+            dummy_created_at(),
+            &dummy_config(),
+            VendorList::local(),
+        ).encode();
+        let invalid_tc_strings = [
+            "".to_string(),
+            "C+/=".to_string(),
+            tc_string[..20].to_string(),
+            format!("B{}", &tc_string[1..]),
+        ];
+
+        invalid_tc_strings
+            .iter()
+            .for_each(|invalid| assert!(
+                matches!(TcString::decode(invalid), Err(TcfError(_))),
+                "{} is not a valid TC string",
+                invalid
+            ))
+    }
+
+    #[test]
+    fn validates_config() {
+        let invalid_configs = [
+            TcfConfig::new(0, 1, "EN", "HN", TcfMapping::default()),
+            TcfConfig::new(4096, 1, "EN", "HN", TcfMapping::default()),
+            TcfConfig::new(10, 1, "en", "HN", TcfMapping::default()),
+            TcfConfig::new(10, 1, "EN", "HND", TcfMapping::default()),
+            TcfConfig::new(10, 1, "EN", "HN", TcfMapping::new(
+                TcfCategory::default(),
+                TcfCategory::new(vec![25], vec![]),
+                TcfCategory::default(),
+                TcfCategory::default(),
+            )),
+            TcfConfig::new(10, 1, "EN", "HN", TcfMapping::new(
+                TcfCategory::default(),
+                TcfCategory::default(),
+                TcfCategory::default(),
+                TcfCategory::new(vec![], vec![0]),
+            )),
+        ];
+
+        assert_eq!(Ok(()), dummy_config().validate());
+        invalid_configs
+            .iter()
+            .for_each(|config| assert!(config.validate().is_err(), "{:?} is invalid", config))
+    }
+
+    fn dummy_config() -> TcfConfig {
+        TcfConfig::new(10, 1, "EN", "HN", TcfMapping::default())
+    }
+
+    fn dummy_created_at() -> DateTime<Utc> {
+        "2024-03-10 17:49:01.613437 UTC".parse().unwrap()
+    }
+
+    fn all_accepted_pref() -> CookieConsentPref {
+        serde_json::from_str(r#"{
+            "essential": true,
+            "functional": true,
+            "analytical": true,
+            "targeting": true
+        }"#).unwrap()
+    }
+}